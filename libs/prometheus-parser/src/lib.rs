@@ -1,14 +1,21 @@
 #![deny(warnings)]
 
-use std::{collections::BTreeMap, convert::TryFrom};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
 
 use indexmap::IndexMap;
 use snafu::ResultExt;
 
 mod line;
+mod openmetrics;
+mod protobuf;
 
 pub use line::ErrorKind;
-use line::{Line, Metric, MetricKind};
+use line::{Help, Line, Metric, MetricKind};
+pub use openmetrics::parse_openmetrics;
+pub use protobuf::parse_protobuf;
 
 pub const METRIC_NAME_LABEL: &str = "__name__";
 
@@ -16,6 +23,11 @@ pub const METRIC_NAME_LABEL: &str = "__name__";
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
 
+    /// The protobuf exposition format.
+    pub mod client {
+        include!(concat!(env!("OUT_DIR"), "/io.prometheus.client.rs"));
+    }
+
     pub use metric_metadata::MetricType;
 
     impl MetricType {
@@ -60,6 +72,27 @@ pub enum ParserError {
     MultipleMetricKinds { name: String },
     #[snafu(display("request is missing metric name label"))]
     RequestNoNameLabel,
+
+    #[snafu(display("OpenMetrics input must end with `# EOF`"))]
+    MissingEof,
+    #[snafu(display("unexpected content after `# EOF`, line: `{}`", line))]
+    ContentAfterEof { line: String },
+    #[snafu(display("metric family `{}` is not contiguous", name))]
+    DuplicateMetricFamily { name: String },
+    #[snafu(display("sample `{}` has an invalid suffix for {} `{}`", sample, kind, family))]
+    InvalidSampleSuffix {
+        sample: String,
+        family: String,
+        kind: &'static str,
+    },
+    #[snafu(display("metric family `{}` must end with its unit `{}`", name, unit))]
+    InvalidUnit { name: String, unit: String },
+
+    #[snafu(display("error decoding protobuf metric family: {}", error))]
+    DecodeProtobuf {
+        #[snafu(source)]
+        error: prost::DecodeError,
+    },
 }
 
 /// Defines how the parser should behave when encountering metadata conflicts.
@@ -72,7 +105,7 @@ pub enum MetadataConflictStrategy {
     Reject,
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct GroupKey {
     pub timestamp: Option<i64>,
     pub labels: BTreeMap<String, String>,
@@ -91,7 +124,7 @@ pub struct SummaryMetric {
     pub count: u64,
 }
 
-#[derive(Debug, Default, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Default, PartialEq, PartialOrd)]
 pub struct HistogramBucket {
     pub bucket: f64,
     pub count: u64,
@@ -109,6 +142,112 @@ pub struct SimpleMetric {
     pub value: f64,
 }
 
+/// Consecutive buckets of a native histogram, starting `offset` buckets
+/// after the end of the previous span, or at bucket index `offset` for the
+/// first one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BucketSpan {
+    pub offset: i32,
+    pub length: u32,
+}
+
+/// A native (sparse, exponential) histogram. Bucket `i` of the positive
+/// buckets covers `(base^(i-1), base^i]` with `base = 2^(2^-schema)`; the
+/// negative buckets mirror it. Counts are given as deltas to the previous
+/// bucket of the same sign.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NativeHistogramMetric {
+    pub schema: i32,
+    pub zero_threshold: f64,
+    pub zero_count: u64,
+    pub count: u64,
+    pub sum: f64,
+    pub positive_spans: Vec<BucketSpan>,
+    pub positive_deltas: Vec<i64>,
+    pub negative_spans: Vec<BucketSpan>,
+    pub negative_deltas: Vec<i64>,
+    /// Classic buckets the target exposed next to the native ones, the
+    /// `+Inf` bucket included; empty if it exposed none.
+    pub classic_buckets: Vec<HistogramBucket>,
+}
+
+impl NativeHistogramMetric {
+    /// Upper bound of the positive bucket at `index`.
+    fn upper_bound(&self, index: i32) -> f64 {
+        2f64.powf(f64::from(index) * 2f64.powi(-self.schema))
+    }
+
+    /// Index and absolute count of every bucket described by the spans.
+    fn buckets(spans: &[BucketSpan], deltas: &[i64]) -> Vec<(i32, u64)> {
+        let mut deltas = deltas.iter();
+        let mut buckets = Vec::with_capacity(deltas.len());
+        let mut index = 0;
+        let mut count = 0i64;
+        for span in spans {
+            // After the first span, `index` is one past its last bucket.
+            index += span.offset;
+            for _ in 0..span.length {
+                let Some(delta) = deltas.next() else {
+                    return buckets;
+                };
+                count += delta;
+                buckets.push((index, count.max(0) as u64));
+                index += 1;
+            }
+        }
+        buckets
+    }
+
+    /// The same observations as cumulative `le` buckets, for sinks that only
+    /// know classic histograms. These are the classic buckets the target
+    /// exposed, if any. Otherwise bucket boundaries are those of the native
+    /// buckets and the zero bucket, so no precision is lost.
+    pub fn to_classic(&self) -> HistogramMetric {
+        if !self.classic_buckets.is_empty() {
+            return HistogramMetric {
+                buckets: self.classic_buckets.clone(),
+                sum: self.sum,
+                count: self.count,
+            };
+        }
+        let negative = Self::buckets(&self.negative_spans, &self.negative_deltas);
+        let positive = Self::buckets(&self.positive_spans, &self.positive_deltas);
+
+        let mut buckets = Vec::with_capacity(negative.len() + positive.len() + 2);
+        let mut cumulative = 0;
+        // Negative bucket `i` covers `[-base^i, -base^(i-1))`, so the
+        // largest index comes first.
+        for &(index, count) in negative.iter().rev() {
+            cumulative += count;
+            buckets.push(HistogramBucket {
+                bucket: -self.upper_bound(index - 1),
+                count: cumulative,
+            });
+        }
+        cumulative += self.zero_count;
+        buckets.push(HistogramBucket {
+            bucket: self.zero_threshold,
+            count: cumulative,
+        });
+        for &(index, count) in &positive {
+            cumulative += count;
+            buckets.push(HistogramBucket {
+                bucket: self.upper_bound(index),
+                count: cumulative,
+            });
+        }
+        buckets.push(HistogramBucket {
+            bucket: f64::INFINITY,
+            count: self.count,
+        });
+        HistogramMetric {
+            buckets,
+            sum: self.sum,
+            count: self.count,
+        }
+    }
+}
+
 type MetricMap<T> = IndexMap<GroupKey, T>;
 
 #[derive(Debug)]
@@ -118,6 +257,15 @@ pub enum GroupKind {
     Gauge(MetricMap<SimpleMetric>),
     Counter(MetricMap<SimpleMetric>),
     Untyped(MetricMap<SimpleMetric>),
+    /// OpenMetrics gauge histogram, whose totals are `_gsum` and `_gcount`.
+    GaugeHistogram(MetricMap<HistogramMetric>),
+    /// OpenMetrics info metric. The group name ends with `_info`.
+    Info(MetricMap<SimpleMetric>),
+    /// OpenMetrics state set: one series per state, with the state in the
+    /// label named after the group.
+    StateSet(MetricMap<SimpleMetric>),
+    /// Native histograms, only found in the protobuf exposition format.
+    NativeHistogram(MetricMap<NativeHistogramMetric>),
 }
 
 impl GroupKind {
@@ -128,6 +276,9 @@ impl GroupKind {
             MetricKind::Counter => Self::Counter(IndexMap::default()),
             MetricKind::Gauge => Self::Gauge(IndexMap::default()),
             MetricKind::Untyped => Self::Untyped(IndexMap::default()),
+            MetricKind::GaugeHistogram => Self::GaugeHistogram(IndexMap::default()),
+            MetricKind::Info => Self::Info(IndexMap::default()),
+            MetricKind::StateSet => Self::StateSet(IndexMap::default()),
         }
     }
 
@@ -144,6 +295,18 @@ impl GroupKind {
             Self::Histogram { .. } => kind == MetricKind::Histogram,
             Self::Summary { .. } => kind == MetricKind::Summary,
             Self::Untyped { .. } => true,
+            Self::GaugeHistogram { .. } => kind == MetricKind::GaugeHistogram,
+            Self::Info { .. } => kind == MetricKind::Info,
+            Self::StateSet { .. } => kind == MetricKind::StateSet,
+            Self::NativeHistogram { .. } => kind == MetricKind::Histogram,
+        }
+    }
+
+    /// Suffixes of the sum and count series of histograms and summaries.
+    pub fn sum_and_count_suffixes(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::GaugeHistogram(_) => ("_gsum", "_gcount"),
+            _ => ("_sum", "_count"),
         }
     }
 
@@ -156,6 +319,7 @@ impl GroupKind {
         metric: Metric,
     ) -> Result<Option<Metric>, ParserError> {
         let suffix = &metric.name[prefix_len..];
+        let (sum_suffix, count_suffix) = self.sum_and_count_suffixes();
         let mut key = GroupKey {
             timestamp: metric.timestamp,
             labels: metric.labels,
@@ -163,7 +327,11 @@ impl GroupKind {
         let value = metric.value;
 
         match self {
-            Self::Counter(metrics) | Self::Gauge(metrics) | Self::Untyped(metrics) => {
+            Self::Counter(metrics)
+            | Self::Gauge(metrics)
+            | Self::Untyped(metrics)
+            | Self::Info(metrics)
+            | Self::StateSet(metrics) => {
                 if !suffix.is_empty() {
                     return Ok(Some(Metric {
                         name: metric.name,
@@ -174,7 +342,7 @@ impl GroupKind {
                 }
                 metrics.insert(key, SimpleMetric { value });
             }
-            Self::Histogram(metrics) => match suffix {
+            Self::Histogram(metrics) | Self::GaugeHistogram(metrics) => match suffix {
                 "_bucket" => {
                     let bucket = key.labels.remove("le").ok_or(ParserError::ExpectedLeTag)?;
                     let (_, bucket) = line::Metric::parse_value(&bucket)
@@ -185,11 +353,11 @@ impl GroupKind {
                         .buckets
                         .push(HistogramBucket { bucket, count });
                 }
-                "_sum" => {
+                "_sum" | "_gsum" if suffix == sum_suffix => {
                     let sum = metric.value;
                     matching_group(metrics, key).sum = sum;
                 }
-                "_count" => {
+                "_count" | "_gcount" if suffix == count_suffix => {
                     let count = try_f64_to_u64(metric.value)?;
                     matching_group(metrics, key).count = count;
                 }
@@ -233,6 +401,15 @@ impl GroupKind {
                     }));
                 }
             },
+            // Text formats have no native histogram samples.
+            Self::NativeHistogram(_) => {
+                return Ok(Some(Metric {
+                    name: metric.name,
+                    timestamp: key.timestamp,
+                    labels: key.labels,
+                    value,
+                }));
+            }
         }
         Ok(None)
     }
@@ -242,6 +419,10 @@ impl GroupKind {
 pub struct MetricGroup {
     pub name: String,
     pub metrics: GroupKind,
+    /// Text of the family's `# HELP` line, or of its metadata.
+    pub help: Option<String>,
+    /// Unit of the family, from OpenMetrics `# UNIT` lines or metadata.
+    pub unit: Option<String>,
 }
 
 fn try_f64_to_u64(f: f64) -> Result<u64, ParserError> {
@@ -258,7 +439,12 @@ fn try_f64_to_u64(f: f64) -> Result<u64, ParserError> {
 impl MetricGroup {
     fn new(name: String, kind: MetricKind) -> Self {
         let metrics = GroupKind::new(kind);
-        MetricGroup { name, metrics }
+        MetricGroup {
+            name,
+            metrics,
+            help: None,
+            unit: None,
+        }
     }
 
     // For cases where a metric group was not defined with `# TYPE ...`.
@@ -273,6 +459,8 @@ impl MetricGroup {
         MetricGroup {
             name,
             metrics: GroupKind::new_untyped(key, value),
+            help: None,
+            unit: None,
         }
     }
 
@@ -294,17 +482,27 @@ fn matching_group<T: Default>(values: &mut MetricMap<T>, group: GroupKey) -> &mu
 /// Parse the given text input, and group the result into higher-level
 /// metric types based on the declared types in the text.
 pub fn parse_text(input: &str) -> Result<Vec<MetricGroup>, ParserError> {
-    let mut groups = Vec::new();
+    let mut groups: Vec<MetricGroup> = Vec::new();
+    // `# HELP` usually comes before the group it describes is started by
+    // `# TYPE` or its first sample.
+    let mut help: Option<Help> = None;
 
     for line in input.lines() {
         let line = Line::parse(line).with_context(|_| WithLineSnafu {
             line: line.to_owned(),
         })?;
         if let Some(line) = line {
+            let len = groups.len();
             match line {
                 Line::Header(header) => {
                     groups.push(MetricGroup::new(header.metric_name, header.kind));
                 }
+                Line::Help(line) => match groups.last_mut() {
+                    Some(group) if group.name == line.metric_name => {
+                        group.help = Some(line.text);
+                    }
+                    _ => help = Some(line),
+                },
                 Line::Metric(metric) => {
                     let metric = match groups.last_mut() {
                         Some(group) => group.try_push(metric)?,
@@ -315,14 +513,27 @@ pub fn parse_text(input: &str) -> Result<Vec<MetricGroup>, ParserError> {
                     }
                 }
             }
+            if groups.len() > len {
+                let group = groups.last_mut().unwrap();
+                if let Some(line) = help.take_if(|help| help.metric_name == group.name) {
+                    group.help = Some(line.text);
+                }
+            }
         }
     }
 
     Ok(groups)
 }
 
+/// Help and unit of a family, from remote_write metadata.
 #[derive(Default)]
-struct MetricGroupSet(IndexMap<String, GroupKind>);
+struct GroupMetadata {
+    help: Option<String>,
+    unit: Option<String>,
+}
+
+#[derive(Default)]
+struct MetricGroupSet(IndexMap<String, GroupKind>, HashMap<String, GroupMetadata>);
 
 impl MetricGroupSet {
     fn get_group<'a>(&'a mut self, name: &str) -> (usize, &'a String, &'a mut GroupKind) {
@@ -335,6 +546,10 @@ impl MetricGroupSet {
             &name[..len - 4]
         } else if name.ends_with("_count") && self.0.contains_key(&name[..len - 6]) {
             &name[..len - 6]
+        } else if name.ends_with("_gsum") && self.0.contains_key(&name[..len - 5]) {
+            &name[..len - 5]
+        } else if name.ends_with("_gcount") && self.0.contains_key(&name[..len - 7]) {
+            &name[..len - 7]
         } else {
             self.0
                 .insert(name.into(), GroupKind::new(MetricKind::Untyped));
@@ -347,8 +562,17 @@ impl MetricGroupSet {
         &mut self,
         name: String,
         kind: MetricKind,
+        help: String,
+        unit: String,
         metadata_conflict_strategy: MetadataConflictStrategy,
     ) -> Result<(), ParserError> {
+        let metadata = self.1.entry(name.clone()).or_default();
+        if metadata.help.is_none() && !help.is_empty() {
+            metadata.help = Some(help);
+        }
+        if metadata.unit.is_none() && !unit.is_empty() {
+            metadata.unit = Some(unit);
+        }
         match self.0.get(&name) {
             Some(group) if !group.matches_kind(kind) => {
                 if matches!(metadata_conflict_strategy, MetadataConflictStrategy::Reject) {
@@ -391,10 +615,18 @@ impl MetricGroupSet {
         Ok(())
     }
 
-    fn finish(self) -> Vec<MetricGroup> {
+    fn finish(mut self) -> Vec<MetricGroup> {
         self.0
             .into_iter()
-            .map(|(name, metrics)| MetricGroup { name, metrics })
+            .map(|(name, metrics)| {
+                let metadata = self.1.remove(&name).unwrap_or_default();
+                MetricGroup {
+                    name,
+                    metrics,
+                    help: metadata.help,
+                    unit: metadata.unit,
+                }
+            })
             .collect()
     }
 }
@@ -412,7 +644,13 @@ pub fn parse_request(
         let kind = proto::MetricType::try_from(metadata.r#type)
             .unwrap_or(proto::MetricType::Unknown)
             .into();
-        groups.insert_metadata(name, kind, metadata_conflict_strategy)?;
+        groups.insert_metadata(
+            name,
+            kind,
+            metadata.help,
+            metadata.unit,
+            metadata_conflict_strategy,
+        )?;
     }
 
     for timeseries in request.timeseries {
@@ -441,9 +679,11 @@ impl From<proto::MetricType> for MetricKind {
             Counter => MetricKind::Counter,
             Gauge => MetricKind::Gauge,
             Histogram => MetricKind::Histogram,
-            Gaugehistogram => MetricKind::Histogram,
+            Gaugehistogram => MetricKind::GaugeHistogram,
             Summary => MetricKind::Summary,
-            _ => MetricKind::Untyped,
+            Info => MetricKind::Info,
+            Stateset => MetricKind::StateSet,
+            Unknown => MetricKind::Untyped,
         }
     }
 }
@@ -533,6 +773,11 @@ mod test {
             "#;
         let output = parse_text(input).unwrap();
         assert_eq!(output.len(), 7);
+        assert_eq!(
+            output[0].help.as_deref(),
+            Some("The total number of HTTP requests.")
+        );
+        assert_eq!(output[1].help, None);
         match_group!(output[0], "http_requests_total", Counter => |metrics: &MetricMap<SimpleMetric>| {
             assert_eq!(metrics.len(), 2);
             assert_eq!(
@@ -622,6 +867,30 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_parse_text_help() {
+        let input = r#"
+            # HELP untyped Help before the first sample.
+            untyped 1
+            # TYPE typed gauge
+            # HELP typed Help after the type,\nwith a newline.
+            typed 2
+            # HELP unused Help of a metric without samples.
+            other 3
+            "#;
+        let output = parse_text(input).unwrap();
+        assert_eq!(output.len(), 3);
+        assert_eq!(
+            output[0].help.as_deref(),
+            Some("Help before the first sample.")
+        );
+        assert_eq!(
+            output[1].help.as_deref(),
+            Some("Help after the type,\nwith a newline.")
+        );
+        assert_eq!(output[2].help, None);
+    }
+
     #[test]
     fn test_f64_to_u64() {
         let value = -1.0;
@@ -730,6 +999,339 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_parse_openmetrics() {
+        let input = r#"# TYPE http_requests counter
+# HELP http_requests The total number of HTTP requests.
+http_requests_total{method="post",code="200"} 1027 1395066363.5
+http_requests_created{method="post",code="200"} 1395066000
+http_requests_total{method="post",code="400"} 3 # {trace_id="KOO5S4vxi0o"} 1 1395066363.1
+# TYPE request_duration_seconds histogram
+# UNIT request_duration_seconds seconds
+request_duration_seconds_bucket{le="0.1"} 5
+request_duration_seconds_bucket{le="+Inf"} 7 # {trace_id="a"} 0.5
+request_duration_seconds_sum 1.2
+request_duration_seconds_count 7
+# TYPE queue_size gaugehistogram
+queue_size_bucket{le="10"} 3
+queue_size_bucket{le="+Inf"} 4
+queue_size_gsum 25
+queue_size_gcount 4
+# TYPE build info
+build_info{version="1.2.3"} 1
+# TYPE state stateset
+state{state="ready"} 1
+state{state="failed"} 0
+# TYPE temperature unknown
+temperature 21.5
+untyped_without_descriptors 1
+# EOF
+"#;
+        let output = parse_openmetrics(input).unwrap();
+        assert_eq!(output.len(), 7);
+        assert_eq!(
+            output[0].help.as_deref(),
+            Some("The total number of HTTP requests.")
+        );
+        assert_eq!(output[1].help, None);
+        assert_eq!(output[1].unit.as_deref(), Some("seconds"));
+        match_group!(output[0], "http_requests_total", Counter => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(metrics.len(), 2);
+            assert_eq!(
+                metrics.get_index(0).unwrap(),
+                simple_metric!(Some(1395066363500), labels!(method => "post", code => 200), 1027.0)
+            );
+            assert_eq!(
+                metrics.get_index(1).unwrap(),
+                simple_metric!(None, labels!(method => "post", code => 400), 3.0)
+            );
+        });
+        match_group!(output[1], "request_duration_seconds", Histogram => |metrics: &MetricMap<HistogramMetric>| {
+            let metric = metrics.get(&GroupKey { timestamp: None, labels: labels!() }).unwrap();
+            assert_eq!(metric.buckets.len(), 2);
+            assert_eq!(metric.buckets[1].bucket, f64::INFINITY);
+            assert_eq!(metric.buckets[1].count, 7);
+            assert_eq!(metric.sum, 1.2);
+            assert_eq!(metric.count, 7);
+        });
+        match_group!(output[2], "queue_size", GaugeHistogram => |metrics: &MetricMap<HistogramMetric>| {
+            let metric = metrics.get(&GroupKey { timestamp: None, labels: labels!() }).unwrap();
+            assert_eq!(metric.buckets.len(), 2);
+            assert_eq!(metric.sum, 25.0);
+            assert_eq!(metric.count, 4);
+        });
+        assert_eq!(
+            output[2].metrics.sum_and_count_suffixes(),
+            ("_gsum", "_gcount")
+        );
+        match_group!(output[3], "build_info", Info => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(
+                metrics.get_index(0).unwrap(),
+                simple_metric!(None, labels!(version => "1.2.3"), 1.0)
+            );
+        });
+        match_group!(output[4], "state", StateSet => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(metrics.len(), 2);
+        });
+        match_group!(output[5], "temperature", Untyped => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(metrics.get_index(0).unwrap(), simple_metric!(None, labels!(), 21.5));
+        });
+        match_group!(output[6], "untyped_without_descriptors", Untyped => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(metrics.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_openmetrics_errors() {
+        assert_eq!(
+            parse_openmetrics("a 1\n").unwrap_err(),
+            ParserError::MissingEof
+        );
+        assert!(matches!(
+            parse_openmetrics("# EOF\na 1\n").unwrap_err(),
+            ParserError::ContentAfterEof { .. }
+        ));
+        assert!(matches!(
+            parse_openmetrics("a 1\nb 1\na 2\n# EOF\n").unwrap_err(),
+            ParserError::DuplicateMetricFamily { name } if name == "a"
+        ));
+        assert!(matches!(
+            parse_openmetrics("# TYPE a counter\na 1\n# EOF\n").unwrap_err(),
+            ParserError::InvalidSampleSuffix {
+                kind: "counter",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_openmetrics("# TYPE a gaugehistogram\na_sum 1\n# EOF\n").unwrap_err(),
+            ParserError::InvalidSampleSuffix {
+                kind: "gaugehistogram",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_openmetrics("# TYPE a_bytes gauge\n# UNIT a_bytes seconds\n# EOF\n").unwrap_err(),
+            ParserError::InvalidUnit { .. }
+        ));
+        assert!(matches!(
+            parse_openmetrics("# TYPE a untyped\n# EOF\n").unwrap_err(),
+            ParserError::WithLine {
+                kind: ErrorKind::InvalidMetricKind { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_openmetrics("a 1 # trace_id=\"a\" 1\n# EOF\n").unwrap_err(),
+            ParserError::WithLine {
+                kind: ErrorKind::ExpectedChar { expected: '{', .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_protobuf() {
+        use prost::Message;
+        use proto::client;
+
+        fn label(name: &str, value: &str) -> client::LabelPair {
+            client::LabelPair {
+                name: Some(name.into()),
+                value: Some(value.into()),
+            }
+        }
+
+        let families = [
+            client::MetricFamily {
+                name: Some("http_requests_total".into()),
+                help: Some("The total number of HTTP requests.".into()),
+                r#type: Some(client::MetricType::Counter as i32),
+                metric: vec![client::Metric {
+                    label: vec![label("method", "post"), label("code", "200")],
+                    counter: Some(client::Counter {
+                        value: Some(1027.0),
+                        ..Default::default()
+                    }),
+                    timestamp_ms: Some(1395066363000),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            client::MetricFamily {
+                name: Some("request_duration_seconds".into()),
+                r#type: Some(client::MetricType::Histogram as i32),
+                metric: vec![client::Metric {
+                    histogram: Some(client::Histogram {
+                        sample_count: Some(7),
+                        sample_sum: Some(1.2),
+                        bucket: vec![client::Bucket {
+                            cumulative_count: Some(5),
+                            upper_bound: Some(0.1),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            client::MetricFamily {
+                name: Some("rpc_duration_seconds".into()),
+                r#type: Some(client::MetricType::Summary as i32),
+                metric: vec![client::Metric {
+                    summary: Some(client::Summary {
+                        sample_count: Some(3),
+                        sample_sum: Some(0.3),
+                        quantile: vec![client::Quantile {
+                            quantile: Some(0.5),
+                            value: Some(0.1),
+                        }],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ];
+        let mut input = Vec::new();
+        for family in &families {
+            family.encode_length_delimited(&mut input).unwrap();
+        }
+
+        let output = parse_protobuf(&input).unwrap();
+        assert_eq!(output.len(), 3);
+        assert_eq!(
+            output[0].help.as_deref(),
+            Some("The total number of HTTP requests.")
+        );
+        assert_eq!(output[1].help, None);
+        match_group!(output[0], "http_requests_total", Counter => |metrics: &MetricMap<SimpleMetric>| {
+            assert_eq!(
+                metrics.get_index(0).unwrap(),
+                simple_metric!(Some(1395066363000), labels!(method => "post", code => 200), 1027.0)
+            );
+        });
+        match_group!(output[1], "request_duration_seconds", Histogram => |metrics: &MetricMap<HistogramMetric>| {
+            let metric = metrics.get(&GroupKey { timestamp: None, labels: labels!() }).unwrap();
+            assert_eq!(
+                metric.buckets,
+                vec![
+                    HistogramBucket { bucket: 0.1, count: 5 },
+                    HistogramBucket { bucket: f64::INFINITY, count: 7 },
+                ]
+            );
+            assert_eq!(metric.sum, 1.2);
+            assert_eq!(metric.count, 7);
+        });
+        match_group!(output[2], "rpc_duration_seconds", Summary => |metrics: &MetricMap<SummaryMetric>| {
+            let metric = metrics.get(&GroupKey { timestamp: None, labels: labels!() }).unwrap();
+            assert_eq!(
+                metric.quantiles,
+                vec![SummaryQuantile { quantile: 0.5, value: 0.1 }]
+            );
+            assert_eq!(metric.count, 3);
+        });
+
+        // A truncated message is an error, not an empty scrape.
+        assert!(matches!(
+            parse_protobuf(&input[..input.len() - 1]).unwrap_err(),
+            ParserError::DecodeProtobuf { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_protobuf_native_histogram() {
+        use prost::Message;
+        use proto::client;
+
+        let span = |offset, length| client::BucketSpan {
+            offset: Some(offset),
+            length: Some(length),
+        };
+        let family = client::MetricFamily {
+            name: Some("request_duration_seconds".into()),
+            r#type: Some(client::MetricType::Histogram as i32),
+            metric: vec![client::Metric {
+                histogram: Some(client::Histogram {
+                    sample_count: Some(9),
+                    sample_sum: Some(10.0),
+                    // Classic buckets exposed alongside are kept for sinks
+                    // without native histograms.
+                    bucket: vec![client::Bucket {
+                        cumulative_count: Some(4),
+                        upper_bound: Some(1.0),
+                        ..Default::default()
+                    }],
+                    schema: Some(0),
+                    zero_threshold: Some(0.001),
+                    zero_count: Some(1),
+                    negative_span: vec![span(0, 1)],
+                    negative_delta: vec![1],
+                    positive_span: vec![span(0, 2), span(1, 1)],
+                    positive_delta: vec![2, -1, 3],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let output = parse_protobuf(&family.encode_length_delimited_to_vec()).unwrap();
+        assert_eq!(output.len(), 1);
+        match_group!(output[0], "request_duration_seconds", NativeHistogram => |metrics: &MetricMap<NativeHistogramMetric>| {
+            let metric = metrics.get(&GroupKey { timestamp: None, labels: labels!() }).unwrap();
+            assert_eq!(
+                metric,
+                &NativeHistogramMetric {
+                    schema: 0,
+                    zero_threshold: 0.001,
+                    zero_count: 1,
+                    count: 9,
+                    sum: 10.0,
+                    positive_spans: vec![
+                        BucketSpan { offset: 0, length: 2 },
+                        BucketSpan { offset: 1, length: 1 },
+                    ],
+                    positive_deltas: vec![2, -1, 3],
+                    negative_spans: vec![BucketSpan { offset: 0, length: 1 }],
+                    negative_deltas: vec![1],
+                    classic_buckets: vec![
+                        HistogramBucket { bucket: 1.0, count: 4 },
+                        HistogramBucket { bucket: f64::INFINITY, count: 9 },
+                    ],
+                }
+            );
+            assert_eq!(
+                metric.to_classic(),
+                HistogramMetric {
+                    buckets: metric.classic_buckets.clone(),
+                    sum: 10.0,
+                    count: 9,
+                }
+            );
+            // Without exposed classic buckets, buckets 0, 1 and 3 with base
+            // 2 end at 1, 2 and 8; negative bucket 0 covers [-1, -0.5).
+            let native_only = NativeHistogramMetric {
+                classic_buckets: Vec::new(),
+                ..metric.clone()
+            };
+            assert_eq!(
+                native_only.to_classic(),
+                HistogramMetric {
+                    buckets: vec![
+                        HistogramBucket { bucket: -0.5, count: 1 },
+                        HistogramBucket { bucket: 0.001, count: 2 },
+                        HistogramBucket { bucket: 1.0, count: 4 },
+                        HistogramBucket { bucket: 2.0, count: 5 },
+                        HistogramBucket { bucket: 8.0, count: 9 },
+                        HistogramBucket { bucket: f64::INFINITY, count: 9 },
+                    ],
+                    sum: 10.0,
+                    count: 9,
+                }
+            );
+        });
+    }
+
     macro_rules! write_request {
         (
             [ $( $name:literal = $type:ident ),* ],
@@ -754,6 +1356,7 @@ mod test {
                     samples: vec![
                         $( proto::Sample { value: $sample as f64, timestamp: $timestamp as i64 }, )*
                     ],
+                    histograms: vec![],
                 }, )* ],
             }
         };
@@ -945,12 +1548,17 @@ mod test {
                     value: 12345.0,
                     timestamp: 1395066367500,
                 }],
+                histograms: vec![],
             }],
         };
 
         // Should succeed and use the first metadata entry (Gauge)
         let parsed = parse_request(request.clone(), MetadataConflictStrategy::Ignore).unwrap();
         assert_eq!(parsed.len(), 1);
+        assert_eq!(
+            parsed[0].help.as_deref(),
+            Some("Number of bytes allocated and still in use.")
+        );
         match_group!(parsed[0], "go_memstats_alloc_bytes", Gauge => |metrics: &MetricMap<SimpleMetric>| {
             assert_eq!(metrics.len(), 1);
             assert_eq!(
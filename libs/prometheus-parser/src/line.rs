@@ -87,6 +87,10 @@ pub enum MetricKind {
     Histogram,
     Summary,
     Untyped,
+    // Only in OpenMetrics.
+    GaugeHistogram,
+    Info,
+    StateSet,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -225,7 +229,7 @@ impl Metric {
     }
 
     /// Parse `{label_name="value",...}`
-    fn parse_labels(input: &str) -> IResult<'_, BTreeMap<String, String>> {
+    pub(crate) fn parse_labels(input: &str) -> IResult<'_, BTreeMap<String, String>> {
         let input = trim_space(input);
 
         match opt(char('{')).parse(input) {
@@ -238,7 +242,7 @@ impl Metric {
     /// Parse `'"' string_content '"'`. `string_content` can contain any unicode characters,
     /// backslash (`\`), double-quote (`"`), and line feed (`\n`) characters have to be
     /// escaped as `\\`, `\"`, and `\n`, respectively.
-    fn parse_escaped_string(input: &str) -> IResult<'_, String> {
+    pub(crate) fn parse_escaped_string(input: &str) -> IResult<'_, String> {
         #[derive(Debug)]
         enum StringFragment<'a> {
             Literal(&'a str),
@@ -330,11 +334,62 @@ impl Header {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Help {
+    pub metric_name: String,
+    pub text: String,
+}
+
+impl Help {
+    /// `# HELP <metric_name> <docstring>`
+    fn parse(input: &str) -> IResult<'_, Self> {
+        let input = trim_space(input);
+        let (input, _) = char('#')(input).map_err(|_: NomError| ErrorKind::ExpectedChar {
+            expected: '#',
+            input: input.to_owned(),
+        })?;
+        let input = trim_space(input);
+        let (input, _) = tag("HELP")(input).map_err(|_: NomError| ErrorKind::ExpectedToken {
+            expected: "HELP",
+            input: input.to_owned(),
+        })?;
+        let (input, _) = Header::space1(input)?;
+        let (input, metric_name) = parse_name(input)?;
+        let text = unescape_help(trim_space(input));
+        Ok(("", Help { metric_name, text }))
+    }
+}
+
+/// Undo the escaping of `\\`, `\n` and (in OpenMetrics) `\"` in help texts.
+/// Other backslashes are kept as they are.
+pub(crate) fn unescape_help(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => result.push('\\'),
+            Some('n') => result.push('\n'),
+            Some('"') => result.push('"'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
 /// Each line of Prometheus text format.
 /// We discard empty lines, comments, and timestamps.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Line {
     Header(Header),
+    Help(Help),
     Metric(Metric),
 }
 
@@ -360,6 +415,10 @@ impl Line {
             Err(e) => e.into(),
         };
 
+        if let Ok((_, help)) = Help::parse(input) {
+            return Ok(Some(Line::Help(help)));
+        }
+
         if let Ok((input, _)) = char::<_, NomErrorType>('#')(input) {
             if (sp, tag::<_, _, NomErrorType>("TYPE")).parse(input).is_ok() {
                 return Err(header_error);
@@ -372,7 +431,7 @@ impl Line {
 }
 
 /// Name matches the regex `[a-zA-Z_][a-zA-Z0-9_]*`.
-fn parse_name(input: &str) -> IResult<'_, String> {
+pub(crate) fn parse_name(input: &str) -> IResult<'_, String> {
     let input = trim_space(input);
     let (input, (a, b)) = pair(
         take_while1(|c: char| c.is_alphabetic() || c == '_'),
@@ -385,7 +444,7 @@ fn parse_name(input: &str) -> IResult<'_, String> {
     Ok((input, a.to_owned() + b))
 }
 
-fn trim_space(input: &str) -> &str {
+pub(crate) fn trim_space(input: &str) -> &str {
     input.trim_start_matches([' ', '\t'])
 }
 
@@ -745,4 +804,24 @@ mod test {
             "#;
         assert!(input.lines().map(Line::parse).all(|r| r.is_ok()));
     }
+
+    #[test]
+    fn test_parse_help() {
+        assert_eq!(
+            Line::parse(r"# HELP go_gc_duration_seconds A summary of\nGC \\ pauses."),
+            Ok(Some(Line::Help(Help {
+                metric_name: "go_gc_duration_seconds".into(),
+                text: "A summary of\nGC \\ pauses.".into(),
+            })))
+        );
+        assert_eq!(
+            Line::parse("# HELP empty"),
+            Ok(Some(Line::Help(Help {
+                metric_name: "empty".into(),
+                text: String::new(),
+            })))
+        );
+        // Not a help line, so just a comment.
+        assert_eq!(Line::parse("# HELPER text"), Ok(None));
+    }
 }
@@ -0,0 +1,383 @@
+//! Parse the OpenMetrics text format (`application/openmetrics-text`).
+//!
+//! The format is a stricter superset of the Prometheus text format:
+//! metric families are contiguous and declared with `# TYPE`, `# HELP` and
+//! `# UNIT` descriptors, counters and info metrics have mandatory `_total`
+//! and `_info` suffixes, samples may carry an exemplar after ` # `,
+//! timestamps are in (fractional) seconds and the input ends with `# EOF`.
+//!
+//! Families map to the same [`MetricGroup`] model as [`crate::parse_text`].
+//! Groups are named after the series they hold, so a counter family `foo`
+//! becomes the group `foo_total` and an info family `foo` the group
+//! `foo_info`. `_created` series are skipped.
+
+use std::collections::HashSet;
+
+use nom::{
+    Parser,
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    combinator::value,
+    number::complete::double,
+};
+use snafu::ResultExt;
+
+use crate::line::{ErrorKind, Metric, MetricKind, parse_name, trim_space, unescape_help};
+use crate::{MetricGroup, ParserError, WithLineSnafu};
+
+type IResult<'a, O> = Result<(&'a str, O), nom::Err<ErrorKind>>;
+
+type NomError<'a> = nom::Err<(&'a str, nom::error::ErrorKind)>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Descriptor {
+    Type { name: String, kind: MetricKind },
+    Help { name: String, text: String },
+    Unit { name: String, unit: String },
+    Eof,
+}
+
+fn space1(input: &str) -> IResult<'_, ()> {
+    take_while1(|c| c == ' ' || c == '\t')(input)
+        .map(|(input, _)| (input, ()))
+        .map_err(|_: NomError| {
+            ErrorKind::ExpectedSpace {
+                input: input.to_owned(),
+            }
+            .into()
+        })
+}
+
+fn keyword<'a>(expected: &'static str) -> impl Fn(&'a str) -> IResult<'a, &'a str> {
+    move |input| {
+        tag(expected)(input).map_err(|_: NomError| {
+            ErrorKind::ExpectedToken {
+                expected,
+                input: input.to_owned(),
+            }
+            .into()
+        })
+    }
+}
+
+fn parse_kind(input: &str) -> IResult<'_, MetricKind> {
+    alt((
+        value(MetricKind::Counter, tag("counter")),
+        // Before `gauge`, which is a prefix of it.
+        value(MetricKind::GaugeHistogram, tag("gaugehistogram")),
+        value(MetricKind::Gauge, tag("gauge")),
+        value(MetricKind::Histogram, tag("histogram")),
+        value(MetricKind::Summary, tag("summary")),
+        value(MetricKind::StateSet, tag("stateset")),
+        value(MetricKind::Info, tag("info")),
+        value(MetricKind::Untyped, tag("unknown")),
+    ))
+    .parse(input)
+    .map_err(|_: NomError| {
+        ErrorKind::InvalidMetricKind {
+            input: input.to_owned(),
+        }
+        .into()
+    })
+}
+
+impl Descriptor {
+    /// `# TYPE <name> <type>`, `# HELP <name> <text>`, `# UNIT <name> <unit>`
+    /// or `# EOF`.
+    fn parse(input: &str) -> IResult<'_, Self> {
+        let (input, _) = keyword("#")(input)?;
+        let (input, _) = space1(input)?;
+        if let Ok((input, _)) = keyword("EOF")(input) {
+            return Ok((input, Descriptor::Eof));
+        }
+        let (input, keyword) = alt((keyword("TYPE"), keyword("HELP"), keyword("UNIT")))
+            .parse(input)
+            .map_err(|_| ErrorKind::ExpectedToken {
+                expected: "TYPE, HELP, UNIT or EOF",
+                input: input.to_owned(),
+            })?;
+        let (input, _) = space1(input)?;
+        let (input, name) = parse_name(input)?;
+        match keyword {
+            "TYPE" => {
+                let (input, _) = space1(input)?;
+                let (input, kind) = parse_kind(input)?;
+                Ok((input, Descriptor::Type { name, kind }))
+            }
+            "HELP" => {
+                let text = unescape_help(trim_space(input));
+                Ok(("", Descriptor::Help { name, text }))
+            }
+            _ => {
+                let unit = trim_space(input).trim_end().to_owned();
+                Ok(("", Descriptor::Unit { name, unit }))
+            }
+        }
+    }
+}
+
+/// Timestamps are seconds, possibly fractional. They are kept in
+/// milliseconds, like everywhere else.
+fn parse_timestamp(input: &str) -> IResult<'_, Option<i64>> {
+    let input = trim_space(input);
+    if input.is_empty() || input.starts_with('#') {
+        return Ok((input, None));
+    }
+    double(input)
+        .map(|(input, seconds)| (input, Some((seconds * 1000.0).round() as i64)))
+        .map_err(|_: NomError| {
+            ErrorKind::ParseTimestampError {
+                input: input.to_owned(),
+            }
+            .into()
+        })
+}
+
+/// ` # {labels} value [timestamp]`. Exemplars are validated and dropped.
+fn parse_exemplar(input: &str) -> IResult<'_, ()> {
+    let input = trim_space(input);
+    let Some(input) = input.strip_prefix('#') else {
+        return Ok((input, ()));
+    };
+    let (input, _) = space1(input)?;
+    if !input.starts_with('{') {
+        return Err(ErrorKind::ExpectedChar {
+            expected: '{',
+            input: input.to_owned(),
+        }
+        .into());
+    }
+    let (input, _) = Metric::parse_labels(input)?;
+    let (input, _) = Metric::parse_value(input)?;
+    let (input, _) = parse_timestamp(input)?;
+    Ok((input, ()))
+}
+
+fn parse_sample(input: &str) -> IResult<'_, Metric> {
+    let (input, name) = parse_name(input)?;
+    let (input, labels) = Metric::parse_labels(input)?;
+    let (input, value) = Metric::parse_value(input)?;
+    let (input, timestamp) = parse_timestamp(input)?;
+    let (input, _) = parse_exemplar(input)?;
+    if !trim_space(input).is_empty() {
+        return Err(ErrorKind::ExpectedChar {
+            expected: '#',
+            input: input.to_owned(),
+        }
+        .into());
+    }
+    Ok((
+        input,
+        Metric {
+            name,
+            labels,
+            value,
+            timestamp,
+        },
+    ))
+}
+
+/// The family samples are currently added to.
+struct Family {
+    name: String,
+    kind: MetricKind,
+    /// Declared with `# TYPE`, so its samples must have the right suffixes.
+    typed: bool,
+    has_samples: bool,
+}
+
+fn kind_name(kind: MetricKind) -> &'static str {
+    match kind {
+        MetricKind::Counter => "counter",
+        MetricKind::Gauge => "gauge",
+        MetricKind::Histogram => "histogram",
+        MetricKind::Summary => "summary",
+        MetricKind::Untyped => "unknown",
+        MetricKind::GaugeHistogram => "gaugehistogram",
+        MetricKind::Info => "info",
+        MetricKind::StateSet => "stateset",
+    }
+}
+
+fn group_name(family: &str, kind: MetricKind) -> String {
+    match kind {
+        MetricKind::Counter => format!("{family}_total"),
+        MetricKind::Info => format!("{family}_info"),
+        _ => family.to_owned(),
+    }
+}
+
+/// Suffixes a sample of a family of the kind may have.
+fn sample_suffixes(kind: MetricKind) -> &'static [&'static str] {
+    match kind {
+        MetricKind::Counter => &["_total", "_created"],
+        MetricKind::Gauge | MetricKind::Untyped | MetricKind::StateSet => &[""],
+        MetricKind::Info => &["_info"],
+        MetricKind::Histogram => &["_bucket", "_count", "_sum", "_created"],
+        MetricKind::GaugeHistogram => &["_bucket", "_gcount", "_gsum"],
+        MetricKind::Summary => &["", "_count", "_sum", "_created"],
+    }
+}
+
+const KNOWN_SUFFIXES: &[&str] = &[
+    "", "_total", "_created", "_info", "_bucket", "_count", "_sum", "_gcount", "_gsum",
+];
+
+struct Families {
+    groups: Vec<MetricGroup>,
+    family: Option<Family>,
+    seen: HashSet<String>,
+}
+
+impl Families {
+    fn start_family(
+        &mut self,
+        name: String,
+        kind: MetricKind,
+        typed: bool,
+    ) -> Result<(), ParserError> {
+        if !self.seen.insert(name.clone()) {
+            return Err(ParserError::DuplicateMetricFamily { name });
+        }
+        self.groups
+            .push(MetricGroup::new(group_name(&name, kind), kind));
+        self.family = Some(Family {
+            name,
+            kind,
+            typed,
+            has_samples: false,
+        });
+        Ok(())
+    }
+
+    /// Descriptors of a family come before its samples, in any order.
+    fn is_pending(&self, name: &str) -> bool {
+        self.family
+            .as_ref()
+            .is_some_and(|family| family.name == name && !family.has_samples)
+    }
+
+    fn descriptor(&mut self, descriptor: Descriptor) -> Result<(), ParserError> {
+        match descriptor {
+            Descriptor::Eof => unreachable!("handled by the caller"),
+            Descriptor::Type { name, kind } => {
+                if self.is_pending(&name) && !self.family.as_ref().unwrap().typed {
+                    let described = self.groups.pop().expect("family has a group");
+                    let mut group = MetricGroup::new(group_name(&name, kind), kind);
+                    group.help = described.help;
+                    group.unit = described.unit;
+                    self.groups.push(group);
+                    self.family = Some(Family {
+                        name,
+                        kind,
+                        typed: true,
+                        has_samples: false,
+                    });
+                    return Ok(());
+                }
+                self.start_family(name, kind, true)
+            }
+            Descriptor::Help { name, text } => {
+                if !self.is_pending(&name) {
+                    self.start_family(name, MetricKind::Untyped, false)?;
+                }
+                self.groups.last_mut().expect("family has a group").help = Some(text);
+                Ok(())
+            }
+            Descriptor::Unit { name, unit } => {
+                if !unit.is_empty() && !name.ends_with(&format!("_{unit}")) {
+                    return Err(ParserError::InvalidUnit { name, unit });
+                }
+                if !self.is_pending(&name) {
+                    self.start_family(name, MetricKind::Untyped, false)?;
+                }
+                if !unit.is_empty() {
+                    self.groups.last_mut().expect("family has a group").unit = Some(unit);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn sample(&mut self, metric: Metric) -> Result<(), ParserError> {
+        let suffix = self.family.as_ref().and_then(|family| {
+            let suffix = metric.name.strip_prefix(&family.name)?;
+            KNOWN_SUFFIXES.contains(&suffix).then_some(suffix)
+        });
+        let Some(suffix) = suffix else {
+            // A sample without descriptors is a family of unknown type.
+            self.start_family(metric.name.clone(), MetricKind::Untyped, false)?;
+            return self.sample(metric);
+        };
+        let family = self.family.as_mut().unwrap();
+        if !sample_suffixes(family.kind).contains(&suffix) {
+            if !family.typed {
+                self.start_family(metric.name.clone(), MetricKind::Untyped, false)?;
+                return self.sample(metric);
+            }
+            return Err(ParserError::InvalidSampleSuffix {
+                sample: metric.name,
+                family: family.name.clone(),
+                kind: kind_name(family.kind),
+            });
+        }
+        family.has_samples = true;
+        if suffix == "_created" {
+            return Ok(());
+        }
+
+        let group = self.groups.last_mut().expect("family has a group");
+        if let Some(metric) = group.try_push(metric)? {
+            self.groups.push(MetricGroup::new_untyped(metric));
+        }
+        Ok(())
+    }
+}
+
+/// Parse the given OpenMetrics input, grouping the samples by the metric
+/// families declared in it.
+pub fn parse_openmetrics(input: &str) -> Result<Vec<MetricGroup>, ParserError> {
+    let mut parser = Families {
+        groups: Vec::new(),
+        family: None,
+        seen: HashSet::new(),
+    };
+    let mut eof = false;
+
+    for line in input.lines() {
+        if eof {
+            return Err(ParserError::ContentAfterEof {
+                line: line.to_owned(),
+            });
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('#') {
+            let (_, descriptor) =
+                Descriptor::parse(line)
+                    .map_err(Into::into)
+                    .with_context(|_| WithLineSnafu {
+                        line: line.to_owned(),
+                    })?;
+            if descriptor == Descriptor::Eof {
+                eof = true;
+            } else {
+                parser.descriptor(descriptor)?;
+            }
+        } else {
+            let (_, metric) =
+                parse_sample(line)
+                    .map_err(Into::into)
+                    .with_context(|_| WithLineSnafu {
+                        line: line.to_owned(),
+                    })?;
+            parser.sample(metric)?;
+        }
+    }
+
+    if !eof {
+        return Err(ParserError::MissingEof);
+    }
+    Ok(parser.groups)
+}
@@ -0,0 +1,268 @@
+//! Parse the protobuf exposition format
+//! (`application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily;
+//! encoding=delimited`): a stream of length-delimited `MetricFamily` messages.
+//!
+//! Families map to [`MetricGroup`]s named after the family. Classic
+//! histogram buckets are kept as they are, with the `+Inf` bucket the format
+//! leaves out added back from the sample count. Native histograms go to a
+//! group of their own; their classic buckets, if any, are kept on them for
+//! sinks that only take classic histograms. Gauge histograms are always read
+//! as classic ones.
+
+use std::collections::BTreeMap;
+
+use indexmap::IndexMap;
+use prost::Message;
+
+use crate::proto::client::{self, MetricFamily, MetricType};
+use crate::{
+    BucketSpan, GroupKey, GroupKind, HistogramBucket, HistogramMetric, MetricGroup,
+    NativeHistogramMetric, ParserError, SimpleMetric, SummaryMetric, SummaryQuantile,
+    try_f64_to_u64,
+};
+
+/// Parse the given length-delimited `MetricFamily` messages.
+pub fn parse_protobuf(mut input: &[u8]) -> Result<Vec<MetricGroup>, ParserError> {
+    let mut groups = Vec::new();
+    while !input.is_empty() {
+        let family = MetricFamily::decode_length_delimited(&mut input)
+            .map_err(|error| ParserError::DecodeProtobuf { error })?;
+        groups.extend(convert_family(family)?);
+    }
+    Ok(groups)
+}
+
+fn convert_family(family: MetricFamily) -> Result<Vec<MetricGroup>, ParserError> {
+    let name = family.name().to_owned();
+    let kind = family.r#type();
+    let help = Some(family.help().to_owned()).filter(|help| !help.is_empty());
+    let unit = Some(family.unit().to_owned()).filter(|unit| !unit.is_empty());
+    let metrics = &family.metric;
+    if kind == MetricType::Histogram && metrics.iter().any(is_native) {
+        let mut groups = vec![MetricGroup {
+            name: name.clone(),
+            metrics: GroupKind::NativeHistogram(native_histogram_metrics(
+                metrics.iter().filter(|metric| is_native(metric)),
+            )?),
+            help: help.clone(),
+            unit: unit.clone(),
+        }];
+        if !metrics.iter().all(is_native) {
+            groups.push(MetricGroup {
+                name,
+                metrics: GroupKind::Histogram(histogram_metrics(
+                    metrics.iter().filter(|metric| !is_native(metric)),
+                )?),
+                help,
+                unit,
+            });
+        }
+        return Ok(groups);
+    }
+    let metrics = match kind {
+        MetricType::Counter => GroupKind::Counter(simple_metrics(metrics, |metric| {
+            metric.counter.as_ref().map(client::Counter::value)
+        })),
+        MetricType::Gauge => GroupKind::Gauge(simple_metrics(metrics, |metric| {
+            metric.gauge.as_ref().map(client::Gauge::value)
+        })),
+        MetricType::Untyped => GroupKind::Untyped(simple_metrics(metrics, |metric| {
+            metric.untyped.as_ref().map(client::Untyped::value)
+        })),
+        MetricType::Summary => GroupKind::Summary(summary_metrics(metrics)),
+        MetricType::Histogram => GroupKind::Histogram(histogram_metrics(metrics.iter())?),
+        MetricType::GaugeHistogram => GroupKind::GaugeHistogram(histogram_metrics(metrics.iter())?),
+    };
+    Ok(vec![MetricGroup {
+        name,
+        metrics,
+        help,
+        unit,
+    }])
+}
+
+/// Whether the metric holds a native histogram. Classic histograms set
+/// none of the native fields; an empty native histogram still has a
+/// schema or a zero threshold.
+fn is_native(metric: &client::Metric) -> bool {
+    metric.histogram.as_ref().is_some_and(|histogram| {
+        histogram.schema.is_some()
+            || histogram.zero_threshold.is_some()
+            || !histogram.positive_span.is_empty()
+            || !histogram.negative_span.is_empty()
+    })
+}
+
+/// Counts of the format may be floats, which override the integers when
+/// positive.
+fn effective_count(count: u64, count_float: f64) -> Result<u64, ParserError> {
+    if count_float > 0.0 {
+        try_f64_to_u64(count_float)
+    } else {
+        Ok(count)
+    }
+}
+
+fn group_key(metric: &client::Metric) -> GroupKey {
+    GroupKey {
+        timestamp: metric.timestamp_ms,
+        labels: metric
+            .label
+            .iter()
+            .map(|label| (label.name().to_owned(), label.value().to_owned()))
+            .collect::<BTreeMap<_, _>>(),
+    }
+}
+
+/// Metrics missing the field of their family's type are skipped.
+fn simple_metrics(
+    metrics: &[client::Metric],
+    value: impl Fn(&client::Metric) -> Option<f64>,
+) -> IndexMap<GroupKey, SimpleMetric> {
+    metrics
+        .iter()
+        .filter_map(|metric| {
+            Some((
+                group_key(metric),
+                SimpleMetric {
+                    value: value(metric)?,
+                },
+            ))
+        })
+        .collect()
+}
+
+fn summary_metrics(metrics: &[client::Metric]) -> IndexMap<GroupKey, SummaryMetric> {
+    metrics
+        .iter()
+        .filter_map(|metric| {
+            let summary = metric.summary.as_ref()?;
+            let quantiles = summary
+                .quantile
+                .iter()
+                .map(|quantile| SummaryQuantile {
+                    quantile: quantile.quantile(),
+                    value: quantile.value(),
+                })
+                .collect();
+            Some((
+                group_key(metric),
+                SummaryMetric {
+                    quantiles,
+                    sum: summary.sample_sum(),
+                    count: summary.sample_count(),
+                },
+            ))
+        })
+        .collect()
+}
+
+fn histogram_metrics<'a>(
+    metrics: impl Iterator<Item = &'a client::Metric>,
+) -> Result<IndexMap<GroupKey, HistogramMetric>, ParserError> {
+    let mut result = IndexMap::new();
+    for metric in metrics {
+        let Some(histogram) = &metric.histogram else {
+            continue;
+        };
+        let count = effective_count(histogram.sample_count(), histogram.sample_count_float())?;
+        let mut buckets = classic_buckets(histogram)?;
+        if buckets
+            .last()
+            .is_none_or(|bucket| bucket.bucket != f64::INFINITY)
+        {
+            buckets.push(HistogramBucket {
+                bucket: f64::INFINITY,
+                count,
+            });
+        }
+        result.insert(
+            group_key(metric),
+            HistogramMetric {
+                buckets,
+                sum: histogram.sample_sum(),
+                count,
+            },
+        );
+    }
+    Ok(result)
+}
+
+fn classic_buckets(histogram: &client::Histogram) -> Result<Vec<HistogramBucket>, ParserError> {
+    histogram
+        .bucket
+        .iter()
+        .map(|bucket| {
+            Ok(HistogramBucket {
+                bucket: bucket.upper_bound(),
+                count: effective_count(bucket.cumulative_count(), bucket.cumulative_count_float())?,
+            })
+        })
+        .collect()
+}
+
+fn native_histogram_metrics<'a>(
+    metrics: impl Iterator<Item = &'a client::Metric>,
+) -> Result<IndexMap<GroupKey, NativeHistogramMetric>, ParserError> {
+    let mut result = IndexMap::new();
+    for metric in metrics {
+        let Some(histogram) = &metric.histogram else {
+            continue;
+        };
+        let count = effective_count(histogram.sample_count(), histogram.sample_count_float())?;
+        let mut classic_buckets = classic_buckets(histogram)?;
+        if classic_buckets
+            .last()
+            .is_some_and(|bucket| bucket.bucket != f64::INFINITY)
+        {
+            classic_buckets.push(HistogramBucket {
+                bucket: f64::INFINITY,
+                count,
+            });
+        }
+        result.insert(
+            group_key(metric),
+            NativeHistogramMetric {
+                schema: histogram.schema(),
+                zero_threshold: histogram.zero_threshold(),
+                zero_count: effective_count(histogram.zero_count(), histogram.zero_count_float())?,
+                count,
+                sum: histogram.sample_sum(),
+                positive_spans: spans(&histogram.positive_span),
+                positive_deltas: deltas(&histogram.positive_delta, &histogram.positive_count)?,
+                negative_spans: spans(&histogram.negative_span),
+                negative_deltas: deltas(&histogram.negative_delta, &histogram.negative_count)?,
+                classic_buckets,
+            },
+        );
+    }
+    Ok(result)
+}
+
+fn spans(spans: &[client::BucketSpan]) -> Vec<BucketSpan> {
+    spans
+        .iter()
+        .map(|span| BucketSpan {
+            offset: span.offset(),
+            length: span.length(),
+        })
+        .collect()
+}
+
+/// Float histograms give absolute bucket counts instead of deltas; they
+/// are turned into deltas of whole counts.
+fn deltas(deltas: &[i64], counts: &[f64]) -> Result<Vec<i64>, ParserError> {
+    if !deltas.is_empty() {
+        return Ok(deltas.to_vec());
+    }
+    let mut previous = 0;
+    counts
+        .iter()
+        .map(|&count| {
+            let count = try_f64_to_u64(count)? as i64;
+            let delta = count - previous;
+            previous = count;
+            Ok(delta)
+        })
+        .collect()
+}
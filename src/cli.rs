@@ -0,0 +1,312 @@
+//! Command-line interface: running the agent, and the subcommands that help
+//! set it up without running it.
+
+use crate::config::Config;
+use crate::discovery::FileDiscovery;
+use crate::metrics_agent::MetricsMessage;
+use crate::metrics_formatter::{MetricsFormatter, TextDocument, format_labels};
+use crate::relabel::Labels;
+use crate::scraper::TargetScraper;
+use crate::target::Target;
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_CONFIG_PATH: &str = "agent.yml";
+
+/// Scrapes Prometheus targets and writes their metrics to remote storage.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Log level, or a filter such as `info,agent_rs=debug`. Defaults to
+    /// `RUST_LOG`, then `info`.
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Defaults to `run`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Scrape the configured targets and write to the destinations.
+    Run(ConfigArgs),
+    /// Validate the config and print the jobs and targets it resolves to.
+    CheckConfig(ConfigArgs),
+    /// Scrape a URL once and print its metrics the way the agent writes them.
+    ScrapeOnce {
+        url: String,
+        /// Give up on the scrape after this long.
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+}
+
+#[derive(Debug, PartialEq, Args)]
+pub struct ConfigArgs {
+    /// Path of the config file.
+    #[arg(long, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+}
+
+impl Default for ConfigArgs {
+    fn default() -> Self {
+        ConfigArgs {
+            config: PathBuf::from(DEFAULT_CONFIG_PATH),
+        }
+    }
+}
+
+impl Cli {
+    /// Log to stderr, so the output of the subcommands stays on stdout.
+    pub fn init_logging(&self) -> Result<()> {
+        let filter = match &self.log_level {
+            Some(filter) => EnvFilter::try_new(filter)
+                .with_context(|| format!("invalid log level `{filter}`"))?,
+            None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr);
+        match self.log_format {
+            LogFormat::Text => subscriber.init(),
+            LogFormat::Json => subscriber.json().init(),
+        }
+        Ok(())
+    }
+}
+
+/// Load the config and print every job with the targets it resolves to,
+/// discovered ones as their files list them now, and the destinations.
+/// Fails if the config or any of its targets is invalid.
+pub fn check_config(path: &Path, out: &mut impl Write) -> Result<()> {
+    let config =
+        Config::load(path).with_context(|| format!("loading config from `{}`", path.display()))?;
+    let mut invalid = 0;
+    for job in &config.scrape_configs {
+        writeln!(
+            out,
+            "job {}: every {}, timeout {}",
+            job.job_name,
+            humantime::format_duration(job.scrape_interval(&config.global)),
+            humantime::format_duration(job.scrape_timeout(&config.global)),
+        )?;
+        let discovered = job
+            .file_sd_configs
+            .iter()
+            .flat_map(|file_sd_config| FileDiscovery::new(file_sd_config.clone()).refresh())
+            .collect::<Vec<_>>();
+        let targets = Target::from_static_configs(job).chain(Target::from_groups(job, &discovered));
+        for (address, target) in targets {
+            match target {
+                Ok(Some(target)) => writeln!(
+                    out,
+                    "  {} {{{}}}",
+                    target.url,
+                    format_labels(&target.labels)
+                )?,
+                Ok(None) => writeln!(out, "  {address}: dropped by relabeling")?,
+                Err(err) => {
+                    invalid += 1;
+                    writeln!(out, "  {address}: invalid: {err}")?;
+                }
+            }
+        }
+    }
+    for remote_write in &config.remote_write {
+        let name = remote_write.name.as_deref().unwrap_or("(unnamed)");
+        writeln!(
+            out,
+            "remote_write {name}: {} ({})",
+            remote_write.url,
+            remote_write.protocol.as_str()
+        )?;
+    }
+    if invalid > 0 {
+        bail!("{invalid} invalid targets in `{}`", path.display());
+    }
+    writeln!(out, "`{}` is valid", path.display())?;
+    Ok(())
+}
+
+/// Scrape `url` without target labels or relabeling, and print the result
+/// as it would be written to a text destination. The payload is parsed by
+/// its content type, as when scraping a target.
+pub async fn scrape_once(url: &str, timeout: Duration, out: &mut impl Write) -> Result<()> {
+    let scraper = TargetScraper {
+        job_name: String::new(),
+        url: url.to_string(),
+        labels: Labels::new(),
+        discovered_labels: Labels::new(),
+        client: reqwest::Client::new(),
+        timeout,
+        max_retries: 0,
+        honor_labels: false,
+        honor_timestamps: true,
+        metric_relabel_configs: Vec::new(),
+    };
+    let scraped_at = SystemTime::now();
+    let scrape = scraper
+        .scrape()
+        .await
+        .with_context(|| format!("scraping `{url}`"))?;
+    let message = MetricsMessage {
+        target_url: scraper.url,
+        metrics: scrape.metrics,
+        stale: Vec::new(),
+        scraped_at,
+    };
+    let mut document = TextDocument::default();
+    MetricsFormatter.format_into(&mut document, &message);
+    out.write_all(document.finish().as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_subcommands_and_global_flags() {
+        let cli = Cli::try_parse_from(["agent-rs"]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.log_format, LogFormat::Text);
+
+        let cli = Cli::try_parse_from([
+            "agent-rs",
+            "check-config",
+            "--config",
+            "other.yml",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::CheckConfig(ConfigArgs {
+                config: PathBuf::from("other.yml")
+            }))
+        );
+        assert_eq!(cli.log_level.as_deref(), Some("debug"));
+
+        let cli = Cli::try_parse_from([
+            "agent-rs",
+            "--log-format",
+            "json",
+            "scrape-once",
+            "http://localhost:9100/metrics",
+            "--timeout",
+            "2s",
+        ])
+        .unwrap();
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert_eq!(
+            cli.command,
+            Some(Command::ScrapeOnce {
+                url: "http://localhost:9100/metrics".to_string(),
+                timeout: Duration::from_secs(2),
+            })
+        );
+    }
+
+    #[test]
+    fn check_config_lists_jobs_and_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.yml");
+        std::fs::write(
+            &path,
+            r#"
+scrape_configs:
+  - job_name: node
+    scrape_interval: 15s
+    static_configs:
+      - targets: ["a:9100", "b:9100"]
+    relabel_configs:
+      - source_labels: [__address__]
+        regex: "b:.*"
+        action: drop
+remote_write:
+  - name: vm
+    url: http://127.0.0.1:8428/api/v1/import/prometheus
+"#,
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        check_config(&path, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "job node: every 15s, timeout 5s\n  \
+                 http://a:9100/metrics {{instance=\"a:9100\",job=\"node\"}}\n  \
+                 b:9100: dropped by relabeling\n\
+                 remote_write vm: http://127.0.0.1:8428/api/v1/import/prometheus (text)\n\
+                 `{}` is valid\n",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn check_config_rejects_invalid_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.yml");
+        std::fs::write(&path, "remote_write: []\n").unwrap();
+        assert!(check_config(&path, &mut Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn scrape_once_prints_formatted_metrics() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("# TYPE up gauge\nup{job=\"node\"} 1 1000\n"),
+            )
+            .mount(&server)
+            .await;
+
+        let mut out = Vec::new();
+        scrape_once(&server.uri(), Duration::from_secs(1), &mut out)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# TYPE up gauge\nup{job=\"node\"} 1 1000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_once_reports_parse_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("not a metric line\n"))
+            .mount(&server)
+            .await;
+
+        let err = scrape_once(&server.uri(), Duration::from_secs(1), &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").starts_with(&format!("scraping `{}`", server.uri())));
+    }
+}
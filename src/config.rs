@@ -0,0 +1,890 @@
+//! Declarative agent configuration, loaded from a YAML file.
+//!
+//! The layout follows the Prometheus configuration file: a `global` section
+//! with defaults, a list of `scrape_configs` (jobs) with their targets, and a
+//! list of `remote_write` destinations. The `web` section configures the
+//! agent's own HTTP server.
+
+use crate::relabel::{RelabelConfig, is_valid_label_name};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file `{}`: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to parse config: {0}")]
+    Parse(#[from] serde_yaml::Error),
+    #[error("invalid config field `{field}`: {message}")]
+    Invalid { field: String, message: String },
+}
+
+impl ConfigError {
+    fn invalid(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub global: GlobalConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub scrape_configs: Vec<ScrapeConfig>,
+    #[serde(default)]
+    pub remote_write: Vec<RemoteWriteConfig>,
+}
+
+/// Defaults shared by every scrape job.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfig {
+    #[serde(with = "humantime_serde", default = "default_scrape_interval")]
+    pub scrape_interval: Duration,
+    #[serde(with = "humantime_serde", default = "default_scrape_timeout")]
+    pub scrape_timeout: Duration,
+    #[serde(default = "default_scrape_retries")]
+    pub scrape_retries: usize,
+    /// Labels added to every series sent to the `remote_write`
+    /// destinations, unless the series already has a label of that name.
+    #[serde(default)]
+    pub external_labels: BTreeMap<String, String>,
+    /// How long shutdown waits for scraped data to be written before
+    /// giving up on it.
+    #[serde(with = "humantime_serde", default = "default_shutdown_timeout")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            scrape_interval: default_scrape_interval(),
+            scrape_timeout: default_scrape_timeout(),
+            scrape_retries: default_scrape_retries(),
+            external_labels: BTreeMap::new(),
+            shutdown_timeout: default_shutdown_timeout(),
+        }
+    }
+}
+
+/// Where the agent keeps its on-disk state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    #[serde(default = "default_storage_path")]
+    pub path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            path: default_storage_path(),
+        }
+    }
+}
+
+/// The HTTP server exposing the agent's own metrics.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    #[serde(default = "default_listen_address")]
+    pub listen_address: SocketAddr,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            listen_address: default_listen_address(),
+        }
+    }
+}
+
+/// A scrape job: a set of targets sharing the same scrape settings.
+/// Settings left empty fall back to the `global` section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrapeConfig {
+    pub job_name: String,
+    #[serde(with = "humantime_serde", default)]
+    pub scrape_interval: Option<Duration>,
+    #[serde(with = "humantime_serde", default)]
+    pub scrape_timeout: Option<Duration>,
+    #[serde(default)]
+    pub scrape_retries: Option<usize>,
+    #[serde(default = "default_metrics_path")]
+    pub metrics_path: String,
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// Keep the labels exposed by targets when they clash with target
+    /// labels, instead of renaming them to `exported_<name>`.
+    #[serde(default)]
+    pub honor_labels: bool,
+    /// Keep the timestamps targets expose on their samples. Without it,
+    /// every sample is stamped with the time of the scrape.
+    #[serde(default = "default_honor_timestamps")]
+    pub honor_timestamps: bool,
+    /// URL parameters added to every scrape request.
+    #[serde(default)]
+    pub params: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub static_configs: Vec<StaticConfig>,
+    #[serde(default)]
+    pub file_sd_configs: Vec<FileSdConfig>,
+    /// Rewrite target labels before scraping, see [`crate::target`].
+    #[serde(default)]
+    pub relabel_configs: Vec<RelabelConfig>,
+    /// Rewrite or drop scraped series before they are written, see
+    /// [`crate::relabel::relabel_groups`].
+    #[serde(default)]
+    pub metric_relabel_configs: Vec<RelabelConfig>,
+}
+
+impl ScrapeConfig {
+    pub fn scrape_interval(&self, global: &GlobalConfig) -> Duration {
+        self.scrape_interval.unwrap_or(global.scrape_interval)
+    }
+
+    pub fn scrape_timeout(&self, global: &GlobalConfig) -> Duration {
+        self.scrape_timeout.unwrap_or(global.scrape_timeout)
+    }
+
+    pub fn scrape_retries(&self, global: &GlobalConfig) -> usize {
+        self.scrape_retries.unwrap_or(global.scrape_retries)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticConfig {
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Targets listed in files, in Prometheus' `file_sd` format, see
+/// [`crate::discovery`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSdConfig {
+    /// `.json`, `.yml` or `.yaml` files. The file name may be a glob
+    /// pattern, with `*`, `?` and `[...]`.
+    pub files: Vec<PathBuf>,
+    /// How often the files are read again, changed or not. Changes are
+    /// noticed right away where the files can be watched.
+    #[serde(with = "humantime_serde", default = "default_refresh_interval")]
+    pub refresh_interval: Duration,
+}
+
+/// A destination that formatted metrics are written to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub queue_config: QueueConfig,
+}
+
+impl RemoteWriteConfig {
+    /// Directory of this destination's on-disk queue.
+    pub fn queue_dir(&self, storage: &StorageConfig) -> PathBuf {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .url
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect(),
+        };
+        storage.path.join("queue").join(name)
+    }
+}
+
+/// Wire format used to push metrics to a destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// Prometheus text exposition format, as accepted by VictoriaMetrics'
+    /// `/api/v1/import/prometheus`.
+    #[default]
+    Text,
+    /// Prometheus remote_write: snappy-compressed protobuf `WriteRequest`s.
+    RemoteWrite,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Text => "text",
+            Protocol::RemoteWrite => "remote_write",
+        }
+    }
+}
+
+/// Batching limits of a destination. Batches are flushed as soon as any
+/// one of the limits is reached.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    #[serde(with = "humantime_serde", default = "default_batch_send_deadline")]
+    pub batch_send_deadline: Duration,
+    #[serde(default = "default_max_samples_per_send")]
+    pub max_samples_per_send: usize,
+    #[serde(default = "default_max_bytes_per_send")]
+    pub max_bytes_per_send: usize,
+    /// Size limit of the on-disk queue. Once reached, the oldest batches
+    /// are dropped.
+    #[serde(default = "default_max_queue_bytes")]
+    pub max_queue_bytes: u64,
+    #[serde(with = "humantime_serde", default = "default_min_backoff")]
+    pub min_backoff: Duration,
+    #[serde(with = "humantime_serde", default = "default_max_backoff")]
+    pub max_backoff: Duration,
+    /// Drop a batch after this many failed retries. Unset, batches are
+    /// retried until they are delivered.
+    #[serde(default)]
+    pub max_retries: Option<usize>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            batch_send_deadline: default_batch_send_deadline(),
+            max_samples_per_send: default_max_samples_per_send(),
+            max_bytes_per_send: default_max_bytes_per_send(),
+            max_queue_bytes: default_max_queue_bytes(),
+            min_backoff: default_min_backoff(),
+            max_backoff: default_max_backoff(),
+            max_retries: None,
+        }
+    }
+}
+
+fn default_scrape_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_scrape_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_scrape_retries() -> usize {
+    10
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_honor_timestamps() -> bool {
+    true
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+fn default_refresh_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_batch_send_deadline() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_max_samples_per_send() -> usize {
+    10_000
+}
+
+fn default_max_bytes_per_send() -> usize {
+    4 << 20
+}
+
+fn default_max_queue_bytes() -> u64 {
+    1 << 30
+}
+
+fn default_min_backoff() -> Duration {
+    Duration::from_millis(30)
+}
+
+fn default_max_backoff() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_storage_path() -> PathBuf {
+    PathBuf::from("data")
+}
+
+fn default_listen_address() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 9099))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        Self::from_yaml(&text)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = serde_yaml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.global.scrape_interval.is_zero() {
+            return Err(ConfigError::invalid(
+                "global.scrape_interval",
+                "must be greater than zero",
+            ));
+        }
+        if self.global.scrape_timeout > self.global.scrape_interval {
+            return Err(ConfigError::invalid(
+                "global.scrape_timeout",
+                "must not be greater than `global.scrape_interval`",
+            ));
+        }
+        for name in self.global.external_labels.keys() {
+            if !is_valid_label_name(name) {
+                return Err(ConfigError::invalid(
+                    format!("global.external_labels.{name}"),
+                    "not a valid label name",
+                ));
+            }
+        }
+
+        let mut job_names = HashSet::new();
+        for (i, job) in self.scrape_configs.iter().enumerate() {
+            let field = |name: &str| format!("scrape_configs[{i}].{name}");
+
+            if job.job_name.is_empty() {
+                return Err(ConfigError::invalid(field("job_name"), "must not be empty"));
+            }
+            if !job_names.insert(job.job_name.as_str()) {
+                return Err(ConfigError::invalid(
+                    field("job_name"),
+                    format!("duplicate job name `{}`", job.job_name),
+                ));
+            }
+            let interval = job.scrape_interval(&self.global);
+            if interval.is_zero() {
+                return Err(ConfigError::invalid(
+                    field("scrape_interval"),
+                    "must be greater than zero",
+                ));
+            }
+            if job.scrape_timeout(&self.global) > interval {
+                return Err(ConfigError::invalid(
+                    field("scrape_timeout"),
+                    "must not be greater than the job's scrape_interval",
+                ));
+            }
+            if !job.metrics_path.starts_with('/') {
+                return Err(ConfigError::invalid(
+                    field("metrics_path"),
+                    "must start with `/`",
+                ));
+            }
+            for (j, static_config) in job.static_configs.iter().enumerate() {
+                for (k, target) in static_config.targets.iter().enumerate() {
+                    validate_target(target).map_err(|message| {
+                        ConfigError::invalid(
+                            field(&format!("static_configs[{j}].targets[{k}]")),
+                            message,
+                        )
+                    })?;
+                }
+                for name in static_config.labels.keys() {
+                    if !is_valid_label_name(name) {
+                        return Err(ConfigError::invalid(
+                            field(&format!("static_configs[{j}].labels.{name}")),
+                            "not a valid label name",
+                        ));
+                    }
+                }
+            }
+            for (j, file_sd_config) in job.file_sd_configs.iter().enumerate() {
+                let field = |name: &str| field(&format!("file_sd_configs[{j}].{name}"));
+                if file_sd_config.files.is_empty() {
+                    return Err(ConfigError::invalid(field("files"), "must not be empty"));
+                }
+                for (k, file) in file_sd_config.files.iter().enumerate() {
+                    validate_file_pattern(file).map_err(|message| {
+                        ConfigError::invalid(field(&format!("files[{k}]")), message)
+                    })?;
+                }
+                if file_sd_config.refresh_interval.is_zero() {
+                    return Err(ConfigError::invalid(
+                        field("refresh_interval"),
+                        "must be greater than zero",
+                    ));
+                }
+            }
+            for (j, relabel_config) in job.relabel_configs.iter().enumerate() {
+                relabel_config.validate().map_err(|message| {
+                    ConfigError::invalid(field(&format!("relabel_configs[{j}]")), message)
+                })?;
+            }
+            for (j, relabel_config) in job.metric_relabel_configs.iter().enumerate() {
+                relabel_config.validate().map_err(|message| {
+                    ConfigError::invalid(field(&format!("metric_relabel_configs[{j}]")), message)
+                })?;
+            }
+        }
+
+        if self.remote_write.is_empty() {
+            return Err(ConfigError::invalid(
+                "remote_write",
+                "at least one destination is required",
+            ));
+        }
+        let mut queue_dirs = HashSet::new();
+        for (i, remote_write) in self.remote_write.iter().enumerate() {
+            if !queue_dirs.insert(remote_write.queue_dir(&self.storage)) {
+                return Err(ConfigError::invalid(
+                    format!("remote_write[{i}].name"),
+                    "destinations must have unique names and URLs",
+                ));
+            }
+            if remote_write.name.as_ref().is_some_and(|name| {
+                name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.')
+            }) {
+                return Err(ConfigError::invalid(
+                    format!("remote_write[{i}].name"),
+                    "must be a non-empty file name",
+                ));
+            }
+            match reqwest::Url::parse(&remote_write.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(url) => {
+                    return Err(ConfigError::invalid(
+                        format!("remote_write[{i}].url"),
+                        format!("unsupported scheme `{}`", url.scheme()),
+                    ));
+                }
+                Err(err) => {
+                    return Err(ConfigError::invalid(
+                        format!("remote_write[{i}].url"),
+                        err.to_string(),
+                    ));
+                }
+            }
+            let queue_config = &remote_write.queue_config;
+            let field = |name: &str| format!("remote_write[{i}].queue_config.{name}");
+            if queue_config.batch_send_deadline.is_zero() {
+                return Err(ConfigError::invalid(
+                    field("batch_send_deadline"),
+                    "must be greater than zero",
+                ));
+            }
+            if queue_config.max_samples_per_send == 0 {
+                return Err(ConfigError::invalid(
+                    field("max_samples_per_send"),
+                    "must be greater than zero",
+                ));
+            }
+            if queue_config.max_bytes_per_send == 0 {
+                return Err(ConfigError::invalid(
+                    field("max_bytes_per_send"),
+                    "must be greater than zero",
+                ));
+            }
+            if queue_config.max_queue_bytes < queue_config.max_bytes_per_send as u64 {
+                return Err(ConfigError::invalid(
+                    field("max_queue_bytes"),
+                    "must not be smaller than `max_bytes_per_send`",
+                ));
+            }
+            if queue_config.min_backoff.is_zero() {
+                return Err(ConfigError::invalid(
+                    field("min_backoff"),
+                    "must be greater than zero",
+                ));
+            }
+            if queue_config.max_backoff < queue_config.min_backoff {
+                return Err(ConfigError::invalid(
+                    field("max_backoff"),
+                    "must not be smaller than `min_backoff`",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Targets are `host:port` pairs; the scheme and path come from the job.
+pub fn validate_target(target: &str) -> Result<(), String> {
+    if target.contains("://") {
+        return Err(format!(
+            "`{target}` must be `host:port`, set the scheme with `scheme`"
+        ));
+    }
+    if target.contains('/') {
+        return Err(format!(
+            "`{target}` must be `host:port`, set the path with `metrics_path`"
+        ));
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(format!("`{target}` is not a valid `host:port` pair")),
+    }
+}
+
+/// Target files are read by their extension, and only their name may be a
+/// glob pattern, as with Prometheus.
+fn validate_file_pattern(path: &Path) -> Result<(), String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension);
+    if !extension.is_some_and(|extension| {
+        ["json", "yml", "yaml"].contains(&extension.to_ascii_lowercase().as_str())
+    }) {
+        return Err(format!(
+            "`{}` must be a `.json`, `.yml` or `.yaml` file",
+            path.display()
+        ));
+    }
+    let directory = path.parent().map(Path::as_os_str).unwrap_or_default();
+    if directory.to_string_lossy().contains(['*', '?', '[']) {
+        return Err(format!(
+            "`{}` may only be a pattern in its file name",
+            path.display()
+        ));
+    }
+    glob::Pattern::new(name)
+        .map(drop)
+        .map_err(|err| format!("`{}` is not a valid pattern: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+remote_write:
+  - url: http://127.0.0.1:8428/api/v1/import/prometheus
+"#;
+
+    fn invalid_field(text: &str) -> String {
+        match Config::from_yaml(text).unwrap_err() {
+            ConfigError::Invalid { field, .. } => field,
+            err => panic!("expected invalid field error, got: {err}"),
+        }
+    }
+
+    #[test]
+    fn parse_minimal() {
+        let config = Config::from_yaml(MINIMAL).unwrap();
+        assert_eq!(config.global.scrape_interval, Duration::from_secs(30));
+        assert_eq!(config.global.scrape_timeout, Duration::from_secs(5));
+        assert_eq!(config.global.scrape_retries, 10);
+        assert!(config.scrape_configs.is_empty());
+        assert_eq!(config.remote_write.len(), 1);
+        assert_eq!(config.remote_write[0].protocol, Protocol::Text);
+        assert_eq!(
+            config.remote_write[0].queue_dir(&config.storage),
+            Path::new("data/queue/http___127_0_0_1_8428_api_v1_import_prometheus")
+        );
+    }
+
+    #[test]
+    fn parse_web_listen_address() {
+        let config = Config::from_yaml(MINIMAL).unwrap();
+        assert_eq!(
+            config.web.listen_address,
+            "0.0.0.0:9099".parse::<SocketAddr>().unwrap()
+        );
+
+        let config = Config::from_yaml(&format!(
+            "web:\n  listen_address: 127.0.0.1:8080\n{MINIMAL}"
+        ))
+        .unwrap();
+        assert_eq!(
+            config.web.listen_address,
+            "127.0.0.1:8080".parse::<SocketAddr>().unwrap()
+        );
+        assert!(
+            Config::from_yaml(&format!("web:\n  listen_address: localhost\n{MINIMAL}")).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_file_sd_configs() {
+        let config = Config::from_yaml(&format!(
+            "scrape_configs:
+  - job_name: node
+    file_sd_configs:
+      - files: [targets/*-node-?.json, /etc/agent/node.yml]
+      - files: [more.yaml]
+        refresh_interval: 30s
+{MINIMAL}"
+        ))
+        .unwrap();
+        let file_sd_configs = &config.scrape_configs[0].file_sd_configs;
+        assert_eq!(
+            file_sd_configs[0].files,
+            [
+                PathBuf::from("targets/*-node-?.json"),
+                PathBuf::from("/etc/agent/node.yml")
+            ]
+        );
+        assert_eq!(
+            file_sd_configs[0].refresh_interval,
+            Duration::from_secs(300)
+        );
+        assert_eq!(file_sd_configs[1].refresh_interval, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn parse_jobs_with_defaults() {
+        let config = Config::from_yaml(
+            r#"
+global:
+  scrape_interval: 1m
+  scrape_timeout: 10s
+  external_labels:
+    cluster: eu-1
+scrape_configs:
+  - job_name: node
+    static_configs:
+      - targets: ["127.0.0.1:9100", "10.0.0.2:9100"]
+        labels:
+          env: prod
+  - job_name: app
+    scheme: https
+    honor_labels: true
+    honor_timestamps: false
+    metrics_path: /custom/metrics
+    scrape_interval: 15s
+    scrape_timeout: 2s
+    scrape_retries: 3
+    static_configs:
+      - targets: ["app.local:8080"]
+remote_write:
+  - url: http://127.0.0.1:8428/api/v1/import/prometheus
+    name: vm
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.global.external_labels["cluster"], "eu-1");
+        let node = &config.scrape_configs[0];
+        assert!(!node.honor_labels);
+        assert!(node.honor_timestamps);
+        assert_eq!(
+            node.scrape_interval(&config.global),
+            Duration::from_secs(60)
+        );
+        assert_eq!(node.scrape_timeout(&config.global), Duration::from_secs(10));
+        assert_eq!(node.scrape_retries(&config.global), 10);
+        assert_eq!(node.metrics_path, "/metrics");
+        assert_eq!(node.scheme, Scheme::Http);
+        assert_eq!(node.static_configs[0].labels["env"], "prod");
+
+        let app = &config.scrape_configs[1];
+        assert_eq!(app.scrape_interval(&config.global), Duration::from_secs(15));
+        assert_eq!(app.scrape_timeout(&config.global), Duration::from_secs(2));
+        assert_eq!(app.scrape_retries(&config.global), 3);
+        assert_eq!(app.metrics_path, "/custom/metrics");
+        assert_eq!(app.scheme, Scheme::Https);
+        assert!(app.honor_labels);
+        assert!(!app.honor_timestamps);
+        assert_eq!(config.remote_write[0].name.as_deref(), Some("vm"));
+        assert_eq!(
+            config.remote_write[0].queue_dir(&config.storage),
+            Path::new("data/queue/vm")
+        );
+        assert_eq!(
+            config.remote_write[0].queue_config.batch_send_deadline,
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn parse_queue_config() {
+        let config = Config::from_yaml(
+            r#"
+remote_write:
+  - url: http://127.0.0.1:8428/api/v1/import/prometheus
+    protocol: remote_write
+    queue_config:
+      batch_send_deadline: 1s
+      max_samples_per_send: 500
+      max_bytes_per_send: 65536
+      min_backoff: 100ms
+      max_backoff: 10s
+      max_retries: 3
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.remote_write[0].protocol, Protocol::RemoteWrite);
+        let queue_config = &config.remote_write[0].queue_config;
+        assert_eq!(queue_config.batch_send_deadline, Duration::from_secs(1));
+        assert_eq!(queue_config.max_samples_per_send, 500);
+        assert_eq!(queue_config.max_bytes_per_send, 65536);
+        assert_eq!(queue_config.min_backoff, Duration::from_millis(100));
+        assert_eq!(queue_config.max_backoff, Duration::from_secs(10));
+        assert_eq!(queue_config.max_retries, Some(3));
+
+        assert_eq!(
+            invalid_field(
+                "remote_write:\n  - url: http://vm:8428/\n    queue_config:\n      max_samples_per_send: 0\n"
+            ),
+            "remote_write[0].queue_config.max_samples_per_send"
+        );
+        assert_eq!(
+            invalid_field(
+                "remote_write:\n  - url: http://vm:8428/\n    queue_config:\n      max_queue_bytes: 1024\n"
+            ),
+            "remote_write[0].queue_config.max_queue_bytes"
+        );
+        assert_eq!(
+            invalid_field(
+                "remote_write:\n  - url: http://vm:8428/\n    queue_config:\n      min_backoff: 10s\n"
+            ),
+            "remote_write[0].queue_config.max_backoff"
+        );
+        assert_eq!(
+            invalid_field(
+                "remote_write:\n  - url: http://a:8428/\n    name: vm\n  - url: http://b:8428/\n    name: vm\n"
+            ),
+            "remote_write[1].name"
+        );
+    }
+
+    #[test]
+    fn parse_errors_name_the_field() {
+        let err = Config::from_yaml("scrape_configs:\n  - static_configs: []\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("job_name"), "{message}");
+
+        let err = Config::from_yaml("global:\n  scrape_interval: often\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("scrape_interval"), "{message}");
+
+        let err = Config::from_yaml("global:\n  bogus: 1\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("bogus"), "{message}");
+    }
+
+    #[test]
+    fn validation_errors() {
+        assert_eq!(invalid_field(""), "remote_write");
+        assert_eq!(
+            invalid_field("remote_write:\n  - url: ftp://example.com\n"),
+            "remote_write[0].url"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "global:\n  scrape_interval: 5s\n  scrape_timeout: 10s\n{MINIMAL}"
+            )),
+            "global.scrape_timeout"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n  - job_name: a\n{MINIMAL}"
+            )),
+            "scrape_configs[1].job_name"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    static_configs:\n      - targets: [\"http://host:9100\"]\n{MINIMAL}"
+            )),
+            "scrape_configs[0].static_configs[0].targets[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    static_configs:\n      - targets: [\"host\"]\n{MINIMAL}"
+            )),
+            "scrape_configs[0].static_configs[0].targets[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    metrics_path: metrics\n{MINIMAL}"
+            )),
+            "scrape_configs[0].metrics_path"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "global:\n  external_labels:\n    cluster-name: eu\n{MINIMAL}"
+            )),
+            "global.external_labels.cluster-name"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    relabel_configs:\n      - action: hashmod\n        target_label: shard\n{MINIMAL}"
+            )),
+            "scrape_configs[0].relabel_configs[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    metric_relabel_configs:\n      - source_labels: [a]\n        action: labelkeep\n{MINIMAL}"
+            )),
+            "scrape_configs[0].metric_relabel_configs[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    file_sd_configs:\n      - files: [targets/*.txt]\n{MINIMAL}"
+            )),
+            "scrape_configs[0].file_sd_configs[0].files[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    file_sd_configs:\n      - files: [\"*/targets.json\"]\n{MINIMAL}"
+            )),
+            "scrape_configs[0].file_sd_configs[0].files[0]"
+        );
+        assert_eq!(
+            invalid_field(&format!(
+                "scrape_configs:\n  - job_name: a\n    file_sd_configs:\n      - files: [\"targets/[a.json\"]\n{MINIMAL}"
+            )),
+            "scrape_configs[0].file_sd_configs[0].files[0]"
+        );
+        let err = Config::from_yaml(&format!(
+            "scrape_configs:\n  - job_name: a\n    relabel_configs:\n      - regex: '('\n{MINIMAL}"
+        ))
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("relabel_configs"), "{message}");
+    }
+}
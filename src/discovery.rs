@@ -0,0 +1,334 @@
+//! File-based service discovery, as Prometheus' `file_sd_configs`.
+//!
+//! Target files hold a list of target groups, in JSON or YAML by their
+//! extension:
+//!
+//! ```yaml
+//! - targets: ["10.0.0.1:9100", "10.0.0.2:9100"]
+//!   labels:
+//!     env: prod
+//! ```
+//!
+//! Each group's targets are discovered with its labels and the path of the
+//! file as `__meta_filepath`, and go through the job's relabeling like
+//! static targets. The directories of the files are watched, and the files
+//! read again whenever something changes in them and every
+//! `refresh_interval`; where they can't be watched, that polling is all
+//! there is. A file that can't be read or parsed keeps its last targets.
+
+use crate::config::{FileSdConfig, validate_target};
+use crate::relabel::{Labels, is_valid_label_name};
+use anyhow::{Result, bail};
+use notify::{RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, warn};
+
+pub const FILEPATH_LABEL: &str = "__meta_filepath";
+
+/// Targets sharing a set of labels.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TargetGroup {
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+/// The target groups one of a job's `file_sd_configs` now lists.
+pub struct Update {
+    pub job_name: String,
+    /// Position of the config among the job's `file_sd_configs`.
+    pub index: usize,
+    pub groups: Vec<TargetGroup>,
+}
+
+/// Reads the target files of one `file_sd_configs` entry.
+pub struct FileDiscovery {
+    config: FileSdConfig,
+    /// Groups last read from each file.
+    files: BTreeMap<PathBuf, Vec<TargetGroup>>,
+}
+
+impl FileDiscovery {
+    pub fn new(config: FileSdConfig) -> Self {
+        FileDiscovery {
+            config,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Read every file matching the config and return all their groups.
+    /// A file that can't be read keeps the groups last read from it.
+    pub fn refresh(&mut self) -> Vec<TargetGroup> {
+        let mut files = BTreeMap::new();
+        for path in self.paths() {
+            match read_file(&path) {
+                Ok(groups) => {
+                    files.insert(path, groups);
+                }
+                Err(err) => {
+                    warn!(
+                        path = %path.display(),
+                        "keeping the targets last read from the file: {err:#}"
+                    );
+                    if let Some(groups) = self.files.remove(&path) {
+                        files.insert(path, groups);
+                    }
+                }
+            }
+        }
+        self.files = files;
+        self.groups()
+    }
+
+    fn groups(&self) -> Vec<TargetGroup> {
+        self.files.values().flatten().cloned().collect()
+    }
+
+    /// Files currently matching the patterns of the config.
+    fn paths(&self) -> BTreeSet<PathBuf> {
+        let mut paths = BTreeSet::new();
+        for pattern in &self.config.files {
+            // Patterns are checked when the config is loaded.
+            let Ok(matches) = glob::glob(&pattern.to_string_lossy()) else {
+                continue;
+            };
+            paths.extend(matches.flatten().filter(|path| path.is_file()));
+        }
+        paths
+    }
+
+    /// Send the groups of the files to `tx` whenever they change, from
+    /// those last returned by [`FileDiscovery::refresh`] on.
+    pub async fn run(mut self, job_name: String, index: usize, tx: mpsc::Sender<Update>) {
+        let refresh_interval = self.config.refresh_interval;
+        let (event_tx, mut events) = mpsc::unbounded_channel();
+        // Events stop once the watcher is dropped.
+        let _watcher = match self.watch(event_tx) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                warn!(
+                    job = %job_name,
+                    "can't watch target files, reading them every {}: {err}",
+                    humantime::format_duration(refresh_interval)
+                );
+                None
+            }
+        };
+        let mut ticker =
+            time::interval_at(time::Instant::now() + refresh_interval, refresh_interval);
+        let mut current = self.groups();
+        loop {
+            tokio::select! {
+                Some(()) = events.recv() => {
+                    // A single write can come as several events.
+                    while events.try_recv().is_ok() {}
+                }
+                _ = ticker.tick() => {}
+            }
+            let groups = self.refresh();
+            if groups == current {
+                continue;
+            }
+            debug!(job = %job_name, index, "target files changed");
+            current = groups.clone();
+            let update = Update {
+                job_name: job_name.clone(),
+                index,
+                groups,
+            };
+            if tx.send(update).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Watch the directories of the files rather than the files, so that
+    /// files created or replaced are noticed too.
+    fn watch(
+        &self,
+        events: mpsc::UnboundedSender<()>,
+    ) -> notify::Result<notify::RecommendedWatcher> {
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                match event {
+                    // Reading the files is reported too, and mustn't make
+                    // them be read again.
+                    Ok(event) if event.kind.is_access() => return,
+                    Ok(_) => {}
+                    Err(err) => debug!("watching target files: {err}"),
+                }
+                let _ = events.send(());
+            })?;
+        let directories = self
+            .config
+            .files
+            .iter()
+            .map(|pattern| directory(pattern))
+            .collect::<BTreeSet<_>>();
+        for directory in directories {
+            if let Err(err) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+                warn!(
+                    directory = %directory.display(),
+                    "can't watch target files, reading them every {}: {err}",
+                    humantime::format_duration(self.config.refresh_interval)
+                );
+            }
+        }
+        Ok(watcher)
+    }
+}
+
+/// Directory of a file pattern, `.` for a bare file name.
+fn directory(pattern: &Path) -> &Path {
+    match pattern.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// The groups of a target file, each labeled with the file's path. A file
+/// with any invalid target or label is rejected as a whole.
+fn read_file(path: &Path) -> Result<Vec<TargetGroup>> {
+    let text = fs::read(path)?;
+    if text.trim_ascii().is_empty() {
+        return Ok(Vec::new());
+    }
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let mut groups: Vec<TargetGroup> = if extension.eq_ignore_ascii_case("json") {
+        serde_json::from_slice(&text)?
+    } else {
+        serde_yaml::from_slice(&text)?
+    };
+    for group in &mut groups {
+        for target in &group.targets {
+            validate_target(target).map_err(anyhow::Error::msg)?;
+        }
+        if let Some(name) = group.labels.keys().find(|name| !is_valid_label_name(name)) {
+            bail!("`{name}` is not a valid label name");
+        }
+        group
+            .labels
+            .insert(FILEPATH_LABEL.to_string(), path.display().to_string());
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn discovery(files: Vec<PathBuf>, refresh_interval: Duration) -> FileDiscovery {
+        FileDiscovery::new(FileSdConfig {
+            files,
+            refresh_interval,
+        })
+    }
+
+    fn targets(groups: &[TargetGroup]) -> Vec<&str> {
+        groups
+            .iter()
+            .flat_map(|group| group.targets.iter().map(String::as_str))
+            .collect()
+    }
+
+    #[test]
+    fn reads_json_and_yaml_files_matching_the_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("a-targets-1.json");
+        fs::write(
+            &json,
+            r#"[{"targets": ["a:9100"], "labels": {"env": "prod"}}]"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.yml"), "- targets: [b:9100, c:9100]\n").unwrap();
+        fs::write(dir.path().join("c.yml"), "- targets: [c:9100]\n").unwrap();
+        fs::write(
+            dir.path().join("targets.json"),
+            r#"[{"targets": ["d:9100"]}]"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("ignored.txt"), "- targets: [d:9100]\n").unwrap();
+
+        let mut discovery = discovery(
+            vec![
+                dir.path().join("*-targets-*.json"),
+                dir.path().join("[ab].yml"),
+            ],
+            Duration::from_secs(300),
+        );
+        let groups = discovery.refresh();
+        assert_eq!(targets(&groups), ["a:9100", "b:9100", "c:9100"]);
+        assert_eq!(
+            groups[0].labels,
+            Labels::from([
+                (FILEPATH_LABEL.to_string(), json.display().to_string()),
+                ("env".to_string(), "prod".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_targets_of_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("targets.yml");
+        fs::write(&path, "- targets: [a:9100]\n").unwrap();
+        let mut discovery = discovery(vec![path.clone()], Duration::from_secs(300));
+        assert_eq!(targets(&discovery.refresh()), ["a:9100"]);
+
+        fs::write(&path, "- targets: [http://b:9100]\n").unwrap();
+        assert_eq!(targets(&discovery.refresh()), ["a:9100"]);
+        fs::write(&path, "- targets: [a:9100\n").unwrap();
+        assert_eq!(targets(&discovery.refresh()), ["a:9100"]);
+
+        // Removed files take their targets with them.
+        fs::remove_file(&path).unwrap();
+        assert!(discovery.refresh().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_changes_as_files_are_written() {
+        let dir = tempfile::tempdir().unwrap();
+        // Polling alone would only notice the change after an hour.
+        let mut discovery = discovery(vec![dir.path().join("*.json")], Duration::from_secs(3600));
+        assert!(discovery.refresh().is_empty());
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(discovery.run("node".to_string(), 0, tx));
+        // Let the watcher start.
+        time::sleep(Duration::from_millis(100)).await;
+
+        fs::write(dir.path().join("new.json"), r#"[{"targets": ["a:9100"]}]"#).unwrap();
+        let update = time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.job_name, "node");
+        assert_eq!(targets(&update.groups), ["a:9100"]);
+    }
+
+    #[tokio::test]
+    async fn polls_files_that_cant_be_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        // The directory doesn't exist yet, so there is nothing to watch.
+        let targets_dir = dir.path().join("targets");
+        let discovery = discovery(vec![targets_dir.join("*.yml")], Duration::from_millis(50));
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(discovery.run("node".to_string(), 0, tx));
+
+        fs::create_dir(&targets_dir).unwrap();
+        fs::write(targets_dir.join("a.yml"), "- targets: [a:9100]\n").unwrap();
+        let update = time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(targets(&update.groups), ["a:9100"]);
+    }
+}
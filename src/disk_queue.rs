@@ -0,0 +1,599 @@
+//! Durable, segment-based queue of formatted batches.
+//!
+//! Batches are appended to numbered segment files in the queue directory.
+//! The writer reads them back in order and acknowledges what it delivered;
+//! the acknowledged position is persisted in a checkpoint file, so after a
+//! crash or restart everything past the checkpoint is replayed. Delivery is
+//! therefore at-least-once: a batch sent but not yet acknowledged when the
+//! agent dies is sent again.
+//!
+//! Each record is `len: u32 | crc32: u32 | samples: u64 | body`, all
+//! little-endian, where `len` covers `samples` and `body`.
+
+use crate::metrics_agent::FormattedBatch;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tracing::warn;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const RECORD_HEADER_LEN: u64 = 8;
+const MAX_SEGMENT_BYTES: u64 = 8 << 20;
+
+/// A position in the queue: a byte offset within a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+struct Segment {
+    seq: u64,
+    size: u64,
+}
+
+struct Inner {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    segments: VecDeque<Segment>,
+    total_bytes: u64,
+    head: File,
+    /// Next record handed out by `read_next`.
+    read: Position,
+    reader: Option<(u64, BufReader<File>)>,
+    /// Everything before this position has been delivered.
+    committed: Position,
+    closed: bool,
+}
+
+pub struct DiskQueue {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    dropped_samples: AtomicU64,
+}
+
+impl DiskQueue {
+    /// Open the queue in `dir`, creating it if needed, and resume from the
+    /// last checkpoint. The oldest segments are dropped once the queue holds
+    /// more than `max_bytes`.
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                seqs.push(seq);
+            }
+        }
+        seqs.sort_unstable();
+        if seqs.is_empty() {
+            seqs.push(1);
+        }
+
+        let mut segments = VecDeque::with_capacity(seqs.len());
+        let last = *seqs.last().unwrap();
+        for seq in seqs {
+            let path = segment_path(dir, seq);
+            let size = if seq == last {
+                // A crash mid-append can leave a partial record behind.
+                let valid = valid_len(&path)?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&path)?;
+                file.set_len(valid)?;
+                valid
+            } else {
+                fs::metadata(&path)?.len()
+            };
+            segments.push_back(Segment { seq, size });
+        }
+        let head = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir, last))?;
+
+        let first = segments.front().unwrap();
+        let start = Position {
+            segment: first.seq,
+            offset: 0,
+        };
+        let committed = read_checkpoint(dir)?.map_or(start, |checkpoint| checkpoint.max(start));
+        let total_bytes = segments.iter().map(|segment| segment.size).sum();
+
+        Ok(DiskQueue {
+            inner: Mutex::new(Inner {
+                dir: dir.to_owned(),
+                max_bytes,
+                segment_bytes: (max_bytes / 4).clamp(1, MAX_SEGMENT_BYTES),
+                segments,
+                total_bytes,
+                head,
+                read: committed,
+                reader: None,
+                committed,
+                closed: false,
+            }),
+            notify: Notify::new(),
+            dropped_samples: AtomicU64::new(0),
+        })
+    }
+
+    /// Durably append a batch.
+    pub fn push(&self, batch: &FormattedBatch) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.append(batch)?;
+        let dropped = inner.enforce_max_bytes()?;
+        if dropped > 0 {
+            self.dropped_samples.fetch_add(dropped, Ordering::Relaxed);
+            warn!(
+                dir = %inner.dir.display(),
+                dropped,
+                total_dropped = self.dropped_samples(),
+                "disk queue is full, dropped the oldest samples"
+            );
+        }
+        drop(inner);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// The next batch that hasn't been handed out yet, with the position to
+    /// acknowledge once it is delivered. `None` if the queue is drained.
+    pub fn read_next(&self) -> io::Result<Option<(FormattedBatch, Position)>> {
+        self.inner.lock().unwrap().read_next()
+    }
+
+    /// Mark everything up to `position` as delivered.
+    pub fn ack(&self, position: Position) -> io::Result<()> {
+        self.inner.lock().unwrap().ack(position)
+    }
+
+    /// Wait until a batch is pushed or the queue is closed.
+    pub async fn wait(&self) {
+        if self.is_closed() {
+            return;
+        }
+        self.notify.notified().await;
+    }
+
+    /// Change the size limit. A smaller limit drops the oldest segments on
+    /// the next push.
+    pub fn set_max_bytes(&self, max_bytes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.max_bytes = max_bytes;
+        inner.segment_bytes = (max_bytes / 4).clamp(1, MAX_SEGMENT_BYTES);
+    }
+
+    /// No more batches will be pushed; the reader drains what is left.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().closed
+    }
+
+    /// Size of the batches that haven't been delivered yet.
+    pub fn pending_bytes(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        let committed = inner.committed;
+        let remaining: u64 = inner
+            .segments
+            .iter()
+            .filter(|segment| segment.seq >= committed.segment)
+            .map(|segment| segment.size)
+            .sum();
+        remaining.saturating_sub(committed.offset)
+    }
+
+    /// Samples lost because the queue hit its size limit.
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples.load(Ordering::Relaxed)
+    }
+}
+
+impl Inner {
+    fn append(&mut self, batch: &FormattedBatch) -> io::Result<()> {
+        let head_size = self.segments.back().unwrap().size;
+        if head_size > 0 && head_size >= self.segment_bytes {
+            self.roll()?;
+        }
+
+        let mut payload = Vec::with_capacity(8 + batch.body.len());
+        payload.extend_from_slice(&(batch.samples as u64).to_le_bytes());
+        payload.extend_from_slice(&batch.body);
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "batch too large"))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.head.write_all(&record)?;
+        self.head.sync_data()?;
+
+        self.segments.back_mut().unwrap().size += record.len() as u64;
+        self.total_bytes += record.len() as u64;
+        Ok(())
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        let seq = self.segments.back().unwrap().seq + 1;
+        self.head = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(segment_path(&self.dir, seq))?;
+        self.segments.push_back(Segment { seq, size: 0 });
+        Ok(())
+    }
+
+    /// Drop the oldest segments until the queue fits in `max_bytes`.
+    /// Returns the number of undelivered samples dropped.
+    fn enforce_max_bytes(&mut self) -> io::Result<u64> {
+        let mut dropped = 0;
+        while self.total_bytes > self.max_bytes && self.segments.len() > 1 {
+            let segment = self.segments.pop_front().unwrap();
+            let path = segment_path(&self.dir, segment.seq);
+            if self.committed.segment <= segment.seq {
+                let from = if self.committed.segment == segment.seq {
+                    self.committed.offset
+                } else {
+                    0
+                };
+                dropped += count_samples(&path, from)?;
+            }
+            fs::remove_file(&path)?;
+            self.total_bytes -= segment.size;
+            if self
+                .reader
+                .as_ref()
+                .is_some_and(|(seq, _)| *seq == segment.seq)
+            {
+                self.reader = None;
+            }
+        }
+        let start = Position {
+            segment: self.segments.front().unwrap().seq,
+            offset: 0,
+        };
+        if self.committed < start {
+            self.committed = start;
+            write_checkpoint(&self.dir, start)?;
+        }
+        self.read = self.read.max(start);
+        Ok(dropped)
+    }
+
+    fn read_next(&mut self) -> io::Result<Option<(FormattedBatch, Position)>> {
+        loop {
+            let Some(index) = self
+                .segments
+                .iter()
+                .position(|segment| segment.seq == self.read.segment)
+            else {
+                return Ok(None);
+            };
+            let size = self.segments[index].size;
+            if self.read.offset >= size {
+                match self.segments.get(index + 1) {
+                    Some(next) => {
+                        self.read = Position {
+                            segment: next.seq,
+                            offset: 0,
+                        };
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            }
+
+            let reader = match &mut self.reader {
+                Some((seq, reader)) if *seq == self.read.segment => reader,
+                reader => {
+                    let mut file = File::open(segment_path(&self.dir, self.read.segment))?;
+                    file.seek(SeekFrom::Start(self.read.offset))?;
+                    &mut reader.insert((self.read.segment, BufReader::new(file))).1
+                }
+            };
+            match read_record(reader)? {
+                Some((batch, len)) => {
+                    self.read.offset += len;
+                    return Ok(Some((batch, self.read)));
+                }
+                None => {
+                    warn!(
+                        dir = %self.dir.display(),
+                        segment = self.read.segment,
+                        offset = self.read.offset,
+                        "corrupt record in disk queue, skipping the rest of the segment"
+                    );
+                    self.reader = None;
+                    self.read.offset = size;
+                }
+            }
+        }
+    }
+
+    fn ack(&mut self, position: Position) -> io::Result<()> {
+        if position <= self.committed {
+            return Ok(());
+        }
+        self.committed = position;
+        write_checkpoint(&self.dir, position)?;
+        while self.segments.len() > 1 && self.segments[0].seq < position.segment {
+            let segment = self.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, segment.seq))?;
+            self.total_bytes -= segment.size;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{seq:020}.{SEGMENT_EXTENSION}"))
+}
+
+/// Read one record. `None` at the end of the data or on a torn or corrupt
+/// record.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<(FormattedBatch, u64)>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    if !read_full(reader, &mut header)? {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if len < 8 {
+        return Ok(None);
+    }
+    let mut payload = vec![0; len];
+    if !read_full(reader, &mut payload)? || crc32fast::hash(&payload) != crc {
+        return Ok(None);
+    }
+    let samples = u64::from_le_bytes(payload[..8].try_into().unwrap()) as usize;
+    payload.drain(..8);
+    let batch = FormattedBatch {
+        body: payload,
+        samples,
+    };
+    Ok(Some((batch, RECORD_HEADER_LEN + len as u64)))
+}
+
+/// Like `read_exact`, but reports a short read as `false` instead of an error.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Length of the intact records at the start of a segment.
+fn valid_len(path: &Path) -> io::Result<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut reader = BufReader::new(file);
+    let mut valid = 0;
+    while let Some((_, len)) = read_record(&mut reader)? {
+        valid += len;
+    }
+    Ok(valid)
+}
+
+fn count_samples(path: &Path, from: u64) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(from))?;
+    let mut reader = BufReader::new(file);
+    let mut samples = 0;
+    while let Some((batch, _)) = read_record(&mut reader)? {
+        samples += batch.samples as u64;
+    }
+    Ok(samples)
+}
+
+fn read_checkpoint(dir: &Path) -> io::Result<Option<Position>> {
+    let bytes = match fs::read(dir.join(CHECKPOINT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if bytes.len() != 16 {
+        warn!(dir = %dir.display(), "ignoring malformed disk queue checkpoint");
+        return Ok(None);
+    }
+    Ok(Some(Position {
+        segment: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+        offset: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+    }))
+}
+
+/// Atomically replace the checkpoint file.
+fn write_checkpoint(dir: &Path, position: Position) -> io::Result<()> {
+    let tmp = dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&position.segment.to_le_bytes())?;
+    file.write_all(&position.offset.to_le_bytes())?;
+    file.sync_data()?;
+    fs::rename(tmp, dir.join(CHECKPOINT_FILE))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batch(text: &str, samples: usize) -> FormattedBatch {
+        FormattedBatch {
+            body: text.as_bytes().to_vec(),
+            samples,
+        }
+    }
+
+    fn drain(queue: &DiskQueue) -> Vec<(FormattedBatch, Position)> {
+        std::iter::from_fn(|| queue.read_next().unwrap()).collect()
+    }
+
+    #[test]
+    fn push_and_read_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+        queue.push(&batch("a 1\n", 1)).unwrap();
+        queue.push(&batch("b 2\nc 3\n", 2)).unwrap();
+
+        let read = drain(&queue);
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].0, batch("a 1\n", 1));
+        assert_eq!(read[1].0, batch("b 2\nc 3\n", 2));
+        assert!(read[0].1 < read[1].1);
+        assert!(queue.read_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn pending_bytes_shrink_as_batches_are_acknowledged() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(queue.pending_bytes(), 0);
+        queue.push(&batch("a 1\n", 1)).unwrap();
+        queue.push(&batch("b 2\n", 1)).unwrap();
+        // Header and sample count, then the body.
+        assert_eq!(queue.pending_bytes(), 2 * (8 + 8 + 4));
+
+        let read = drain(&queue);
+        assert_eq!(queue.pending_bytes(), 2 * (8 + 8 + 4));
+        queue.ack(read[0].1).unwrap();
+        assert_eq!(queue.pending_bytes(), 8 + 8 + 4);
+        queue.ack(read[1].1).unwrap();
+        assert_eq!(queue.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn replays_unacknowledged_batches_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+            for i in 0..5 {
+                queue.push(&batch(&format!("m {i}\n"), 1)).unwrap();
+            }
+            let read = drain(&queue);
+            // Delivered the first two, the third was in flight when the
+            // agent died.
+            queue.ack(read[1].1).unwrap();
+        }
+
+        let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+        let replayed = drain(&queue)
+            .into_iter()
+            .map(|(batch, _)| batch)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replayed,
+            [batch("m 2\n", 1), batch("m 3\n", 1), batch("m 4\n", 1)]
+        );
+    }
+
+    #[test]
+    fn truncates_torn_record_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+            queue.push(&batch("a 1\n", 1)).unwrap();
+            queue.push(&batch("b 2\n", 1)).unwrap();
+        }
+        // Simulate a crash halfway through appending the second record.
+        let path = segment_path(dir.path(), 1);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+        queue.push(&batch("c 3\n", 1)).unwrap();
+        let read = drain(&queue)
+            .into_iter()
+            .map(|(batch, _)| batch)
+            .collect::<Vec<_>>();
+        assert_eq!(read, [batch("a 1\n", 1), batch("c 3\n", 1)]);
+    }
+
+    #[test]
+    fn rolls_segments_and_removes_acknowledged_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        // 4 KiB segments.
+        let queue = DiskQueue::open(dir.path(), 16 << 10).unwrap();
+        let body = "x".repeat(1000);
+        for _ in 0..10 {
+            queue.push(&batch(&body, 1)).unwrap();
+        }
+        let segments = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|entry| {
+                    let path = entry.as_ref().unwrap().path();
+                    path.extension().and_then(|ext| ext.to_str()) == Some(SEGMENT_EXTENSION)
+                })
+                .count()
+        };
+        assert_eq!(segments(), 2);
+
+        let read = drain(&queue);
+        assert_eq!(read.len(), 10);
+        queue.ack(read.last().unwrap().1).unwrap();
+        assert_eq!(segments(), 1);
+        assert_eq!(queue.dropped_samples(), 0);
+    }
+
+    #[test]
+    fn drops_oldest_segments_over_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = DiskQueue::open(dir.path(), 8 << 10).unwrap();
+        let body = "x".repeat(1000);
+        for i in 0..20 {
+            queue.push(&batch(&format!("{i:02}{body}"), 2)).unwrap();
+        }
+        let read = drain(&queue);
+        assert!(read.len() < 20);
+        // The newest batches survive.
+        assert!(read.last().unwrap().0.body.starts_with(b"19"));
+        assert_eq!(queue.dropped_samples(), 2 * (20 - read.len() as u64));
+    }
+
+    #[tokio::test]
+    async fn wait_wakes_on_push_and_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = std::sync::Arc::new(DiskQueue::open(dir.path(), 1 << 20).unwrap());
+
+        let waiter = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait().await }
+        });
+        queue.push(&batch("a 1\n", 1)).unwrap();
+        waiter.await.unwrap();
+
+        let waiter = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait().await }
+        });
+        queue.close();
+        waiter.await.unwrap();
+        assert!(queue.is_closed());
+    }
+}
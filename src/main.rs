@@ -1,52 +1,115 @@
-use crate::metrics_agent::MetricsMessage;
-use anyhow::Result;
+use crate::cli::{Cli, Command, ConfigArgs};
+use crate::config::Config;
+use crate::supervisor::Supervisor;
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
-use std::time;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{error, info};
 
+mod cli;
+mod config;
+mod discovery;
+mod disk_queue;
 mod metrics_agent;
 mod metrics_formatter;
+mod relabel;
 mod remote_write;
+mod remote_write_encoder;
+mod scrape_health;
+mod scrape_manager;
 mod scraper;
+mod series;
+mod supervisor;
+mod target;
+mod telemetry;
+mod web;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let reqwest_client = reqwest::Client::new();
-    let scraper = scraper::TargetScraper::new(
-        "http://127.0.0.1:9100/metrics".to_string(),
-        reqwest_client.clone(),
-        time::Duration::from_secs(5),
-        10,
-    );
-    let (scrape_tx, scrape_rx) = mpsc::channel::<MetricsMessage>(32);
-    let formatter = metrics_formatter::MetricsFormatter {};
-    let (format_tx, format_rx) = mpsc::channel::<String>(32);
+    let cli = Cli::parse();
+    cli.init_logging()?;
+    match cli
+        .command
+        .unwrap_or_else(|| Command::Run(ConfigArgs::default()))
+    {
+        Command::Run(args) => run(&args.config).await,
+        Command::CheckConfig(args) => cli::check_config(&args.config, &mut io::stdout()),
+        Command::ScrapeOnce { url, timeout } => {
+            cli::scrape_once(&url, timeout, &mut io::stdout()).await
+        }
+    }
+}
+
+/// Scrape and write until SIGINT or SIGTERM, then drain the pipelines.
+/// The config is reloaded on SIGHUP and `POST /-/reload`.
+async fn run(config_path: &Path) -> Result<()> {
+    let config = Config::load(config_path)
+        .with_context(|| format!("loading config from `{}`", config_path.display()))?;
+
+    let listen_address = config.web.listen_address;
+    let listener = TcpListener::bind(listen_address)
+        .await
+        .with_context(|| format!("listening on {listen_address}"))?;
 
-    let writer = remote_write::RemoteWriter::new(
-        "http://127.0.0.1:8428/api/v1/import/prometheus".to_string(),
-        reqwest_client,
-    );
-    let metrics_agent = Arc::new(metrics_agent::MetricsAgent::new(
-        writer,
-        formatter,
-        scraper,
-        time::Duration::from_secs(30),
-    ));
-    let metric_scraper_clone = Arc::clone(&metrics_agent);
-    let scraper_handle = tokio::spawn(async move {
-        loop {
-            metric_scraper_clone.scrape(scrape_tx.clone()).await;
-            tokio::time::sleep(metric_scraper_clone.interval).await;
+    let mut supervisor = Supervisor::start(config)?;
+    let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    info!(%listen_address, "serving the agent's metrics and status");
+    let web_state = Arc::new(web::WebState {
+        telemetry: supervisor.telemetry(),
+        targets: supervisor.statuses(),
+        reload: reload_tx,
+    });
+    tokio::spawn(async move {
+        if let Err(err) = web::serve(listener, web_state).await {
+            error!("HTTP server failed: {err}");
         }
     });
 
-    let metric_formatter_clone = Arc::clone(&metrics_agent);
-    let formater_handle =
-        tokio::spawn(async move { metric_formatter_clone.format(scrape_rx, format_tx).await });
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            err = supervisor.supervise() => return Err(err),
+            result = tokio::signal::ctrl_c() => break result?,
+            _ = terminate.recv() => break,
+            // A failed reload is logged and leaves the running config.
+            _ = hangup.recv() => {
+                let _ = supervisor.reload(config_path);
+            }
+            Some(reply) = reload_rx.recv() => {
+                let _ = reply.send(supervisor.reload(config_path));
+            }
+        }
+    }
 
-    let metric_writer_clone = Arc::clone(&metrics_agent);
-    let writer_handle = tokio::spawn(async move { metric_writer_clone.write(format_rx).await });
-    tokio::try_join!(scraper_handle, formater_handle, writer_handle)?;
+    let deadline = supervisor.config().global.shutdown_timeout;
+    let agents = supervisor.agents();
+    info!(?deadline, "shutting down, writing out scraped data");
+    let drained = time::timeout(deadline, supervisor.shutdown()).await;
+    let dropped_samples = agents
+        .iter()
+        .map(|agent| agent.stats().dropped_samples())
+        .sum::<u64>();
+    match drained {
+        Err(_) => {
+            error!(
+                ?deadline,
+                "shutdown deadline exceeded; batches already queued will be sent on the next start, \
+                 scrapes not yet queued are lost"
+            );
+            bail!("shutdown did not finish within {deadline:?}");
+        }
+        Ok(result) => result?,
+    }
+    if dropped_samples > 0 {
+        bail!("{dropped_samples} samples were dropped");
+    }
+    info!("shutdown complete");
     Ok(())
 }
@@ -1,89 +1,873 @@
-use crate::metrics_formatter::MetricsFormatter;
-use crate::remote_write::RemoteWriter;
-use crate::scraper::TargetScraper;
-use anyhow::{Context, Result};
-use prometheus_parser::{
-    GroupKey, GroupKind, HistogramMetric, MetricGroup, SimpleMetric, SummaryMetric,
-};
-use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
-use tracing::{debug, error};
+use crate::config::{Protocol, QueueConfig, RemoteWriteConfig};
+use crate::disk_queue::{DiskQueue, Position};
+use crate::metrics_formatter::{MetricsFormatter, TextDocument};
+use crate::relabel::{Labels, rewrite_labels};
+use crate::remote_write::{RemoteWriter, RetryPolicy, WriteStats};
+use crate::remote_write_encoder::RemoteWriteEncoder;
+use crate::series::SeriesKey;
+use anyhow::Result;
+use prometheus_parser::{GroupKind, MetricGroup};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tracing::{debug, error, warn};
 
 pub struct MetricsMessage {
     pub target_url: String,
     pub metrics: Vec<MetricGroup>,
-    pub scraped_at: Instant,
+    /// Series the target no longer exposes, written as staleness markers.
+    pub stale: Vec<SeriesKey>,
+    /// Wall-clock time the scrape started at.
+    pub scraped_at: SystemTime,
 }
 
+impl MetricsMessage {
+    pub fn sample_count(&self) -> usize {
+        sample_count(&self.metrics) + self.stale.len()
+    }
+
+    /// Time of the scrape in milliseconds since the epoch. Samples without
+    /// a timestamp of their own, staleness markers included, are written
+    /// with it rather than left to be stamped on arrival.
+    pub fn timestamp(&self) -> i64 {
+        self.scraped_at
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as i64)
+            .unwrap_or_default()
+    }
+}
+
+/// Number of samples the groups expand to once formatted.
+pub fn sample_count(metrics: &[MetricGroup]) -> usize {
+    metrics
+        .iter()
+        .map(|group| match &group.metrics {
+            GroupKind::Gauge(metrics)
+            | GroupKind::Counter(metrics)
+            | GroupKind::Untyped(metrics)
+            | GroupKind::Info(metrics)
+            | GroupKind::StateSet(metrics) => metrics.len(),
+            GroupKind::NativeHistogram(metrics) => metrics.len(),
+            GroupKind::Summary(metrics) => metrics
+                .values()
+                .map(|metric| metric.quantiles.len() + 2)
+                .sum(),
+            GroupKind::Histogram(metrics) | GroupKind::GaugeHistogram(metrics) => metrics
+                .values()
+                .map(|metric| metric.buckets.len() + 2)
+                .sum(),
+        })
+        .sum()
+}
+
+/// Limits that trigger a flush in the format and write stages. A batch is
+/// flushed as soon as any one of them is reached.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushLimits {
+    pub max_age: Duration,
+    pub max_bytes: usize,
+    pub max_samples: usize,
+}
+
+impl From<&QueueConfig> for FlushLimits {
+    fn from(queue_config: &QueueConfig) -> Self {
+        FlushLimits {
+            max_age: queue_config.batch_send_deadline,
+            max_bytes: queue_config.max_bytes_per_send,
+            max_samples: queue_config.max_samples_per_send,
+        }
+    }
+}
+
+/// Turns scrapes into request bodies in a destination's wire format.
+pub enum Encoder {
+    Text(MetricsFormatter),
+    RemoteWrite(RemoteWriteEncoder),
+}
+
+impl Encoder {
+    pub fn new(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Text => Encoder::Text(MetricsFormatter),
+            Protocol::RemoteWrite => Encoder::RemoteWrite(RemoteWriteEncoder),
+        }
+    }
+
+    /// Add the scrape to the batch. Text documents can't be concatenated,
+    /// as a family may appear only once in each, so the scrapes of a text
+    /// batch are merged into one document instead.
+    fn encode_into(&self, batch: &mut Batch, metrics_message: &MetricsMessage) {
+        match self {
+            Encoder::Text(formatter) => {
+                formatter.format_into(batch.document.get_or_insert_default(), metrics_message)
+            }
+            Encoder::RemoteWrite(encoder) => batch
+                .formatted
+                .body
+                .extend(encoder.encode_message(metrics_message)),
+        }
+        batch.formatted.samples += metrics_message.sample_count();
+    }
+
+    /// Whether separately encoded bodies can be sent as one. Concatenated
+    /// remote_write requests decode as one request; text documents would
+    /// repeat families.
+    pub fn joins_bodies(&self) -> bool {
+        matches!(self, Encoder::RemoteWrite(_))
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FormattedBatch {
+    pub body: Vec<u8>,
+    pub samples: usize,
+}
+
+/// Formatted output accumulated until one of the flush limits is hit.
+#[derive(Default)]
+struct Batch {
+    formatted: FormattedBatch,
+    /// Scrapes for a text destination, written out when the batch is taken.
+    document: Option<TextDocument>,
+    deadline: Option<time::Instant>,
+}
+
+impl Batch {
+    fn push(&mut self, body: &[u8], samples: usize, limits: &FlushLimits) {
+        self.start(limits);
+        self.formatted.body.extend_from_slice(body);
+        self.formatted.samples += samples;
+    }
+
+    fn push_message(
+        &mut self,
+        encoder: &Encoder,
+        metrics_message: &MetricsMessage,
+        limits: &FlushLimits,
+    ) {
+        self.start(limits);
+        encoder.encode_into(self, metrics_message);
+    }
+
+    fn start(&mut self, limits: &FlushLimits) {
+        self.deadline
+            .get_or_insert_with(|| time::Instant::now() + limits.max_age);
+    }
+
+    fn len(&self) -> usize {
+        self.formatted.body.len() + self.document.as_ref().map_or(0, TextDocument::len)
+    }
+
+    fn is_full(&self, limits: &FlushLimits) -> bool {
+        self.len() >= limits.max_bytes || self.formatted.samples >= limits.max_samples
+    }
+
+    fn is_empty(&self) -> bool {
+        self.deadline.is_none()
+    }
+
+    fn take(&mut self) -> FormattedBatch {
+        self.deadline = None;
+        let mut formatted = std::mem::take(&mut self.formatted);
+        if let Some(document) = self.document.take() {
+            formatted.body.extend(document.finish().into_bytes());
+        }
+        formatted
+    }
+
+    /// Resolves once the oldest entry in the batch reaches `max_age`,
+    /// never if the batch is empty.
+    async fn expired(&self) {
+        match self.deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Counters of a destination's pipeline, for the agent's own metrics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PipelineStats {
+    /// Batches handed to the queue by the format stage.
+    pub formatted_bytes: u64,
+    pub formatted_samples: u64,
+    /// Batches queued and not delivered yet.
+    pub queue_bytes: u64,
+    /// Samples the destination wouldn't accept.
+    pub rejected_samples: u64,
+    /// Samples dropped because the queue was full.
+    pub overflowed_samples: u64,
+    pub writes: WriteStats,
+}
+
+impl PipelineStats {
+    pub fn dropped_samples(&self) -> u64 {
+        self.rejected_samples + self.overflowed_samples
+    }
+}
+
+/// Formats and writes scraped metrics to a single destination. Formatted
+/// batches go through an on-disk queue, so nothing is lost while the
+/// destination is down or the agent restarts.
 pub struct MetricsAgent {
-    writer: RemoteWriter,
-    scraper: TargetScraper,
-    formatter: MetricsFormatter,
-    pub interval: Duration,
+    /// The writer and limits change on reload; the encoder can't, as the
+    /// queue holds batches in its format.
+    writer: Mutex<RemoteWriter>,
+    encoder: Encoder,
+    limits: Mutex<FlushLimits>,
+    queue: DiskQueue,
+    dropped_samples: AtomicU64,
+    formatted_bytes: AtomicU64,
+    formatted_samples: AtomicU64,
+    delivered: AtomicBool,
 }
 
 impl MetricsAgent {
     pub fn new(
         writer: RemoteWriter,
-        formatter: MetricsFormatter,
-        scraper: TargetScraper,
-        interval: Duration,
+        encoder: Encoder,
+        limits: FlushLimits,
+        queue: DiskQueue,
     ) -> Self {
         MetricsAgent {
-            writer,
-            scraper,
-            formatter,
-            interval,
+            writer: Mutex::new(writer),
+            encoder,
+            limits: Mutex::new(limits),
+            queue,
+            dropped_samples: AtomicU64::new(0),
+            formatted_bytes: AtomicU64::new(0),
+            formatted_samples: AtomicU64::new(0),
+            delivered: AtomicBool::new(false),
         }
     }
 
-    pub async fn scrape(&self, tx: mpsc::Sender<MetricsMessage>) -> Result<()> {
-        let metrics = self.scraper.scrape().await?;
-        let scraped_at = Instant::now();
+    pub async fn format(&self, mut rx: mpsc::Receiver<Arc<MetricsMessage>>) -> Result<()> {
+        let result = self.format_into_queue(&mut rx).await;
+        self.queue.close();
+        result
+    }
+
+    async fn format_into_queue(&self, rx: &mut mpsc::Receiver<Arc<MetricsMessage>>) -> Result<()> {
+        let mut batch = Batch::default();
+        loop {
+            let metric_message = tokio::select! {
+                metric_message = rx.recv() => metric_message,
+                _ = batch.expired() => {
+                    self.enqueue(batch.take())?;
+                    continue;
+                }
+            };
+            let Some(metric_message) = metric_message else {
+                break;
+            };
+            debug!(
+                target_url = %metric_message.target_url,
+                age = ?metric_message.scraped_at.elapsed().unwrap_or_default(),
+                "formatting scrape"
+            );
+            let limits = self.limits();
+            batch.push_message(&self.encoder, &metric_message, &limits);
 
-        let metric_message = MetricsMessage {
-            metrics,
-            target_url: self.scraper.url.clone(),
-            scraped_at,
-        };
-        tx.send(metric_message).await?;
+            if batch.is_full(&limits) {
+                self.enqueue(batch.take())?;
+            }
+        }
+        if !batch.is_empty() {
+            self.enqueue(batch.take())?;
+        }
         Ok(())
     }
 
-    pub async fn format(
-        &self,
-        mut rx: mpsc::Receiver<MetricsMessage>,
-        tx: mpsc::Sender<String>,
-    ) -> Result<()> {
-        let mut batch = Vec::with_capacity(30);
-        while let Some(metric_message) = rx.recv().await {
-            batch.push(metric_message);
+    fn enqueue(&self, formatted: FormattedBatch) -> io::Result<()> {
+        self.queue.push(&formatted)?;
+        self.formatted_bytes
+            .fetch_add(formatted.body.len() as u64, Ordering::Relaxed);
+        self.formatted_samples
+            .fetch_add(formatted.samples as u64, Ordering::Relaxed);
+        Ok(())
+    }
 
-            if batch.len() >= 32 {
-                let formatted_text = self.formatter.format_batch(&batch);
-                tx.send(formatted_text).await?;
-                batch.clear();
+    /// Deliver queued batches until the queue is closed and drained.
+    pub async fn write(&self) -> Result<()> {
+        let mut batch = Batch::default();
+        let mut position = None;
+        loop {
+            if let Some((formatted, next)) = self.queue.read_next()? {
+                if !batch.is_empty() && !self.encoder.joins_bodies() {
+                    self.flush(&mut batch, position.take()).await?;
+                }
+                let limits = self.limits();
+                batch.push(&formatted.body, formatted.samples, &limits);
+                position = Some(next);
+                if batch.is_full(&limits) {
+                    self.flush(&mut batch, position.take()).await?;
+                }
+                continue;
+            }
+            if self.queue.is_closed() {
+                break;
+            }
+            tokio::select! {
+                _ = self.queue.wait() => {}
+                _ = batch.expired() => self.flush(&mut batch, position.take()).await?,
             }
         }
         if !batch.is_empty() {
-            tx.send(self.formatter.format_batch(&batch));
+            self.flush(&mut batch, position.take()).await?;
         }
         Ok(())
     }
 
-    pub async fn write(&self, mut rx: mpsc::Receiver<String>) -> Result<()> {
-        let mut batch = Vec::with_capacity(30);
-        while let Some(formatted_text) = rx.recv().await {
-            batch.push(formatted_text);
-            if batch.len() >= 128 {
-                self.writer.send(batch.concat()).await?;
-                batch.clear();
+    /// Send the batch and acknowledge it in the queue. Batches the
+    /// destination rejects, or that run out of retries, are dropped.
+    async fn flush(&self, batch: &mut Batch, position: Option<Position>) -> Result<()> {
+        let formatted = batch.take();
+        let writer = self.writer.lock().unwrap().clone();
+        match writer.send(formatted.body).await {
+            Ok(()) => self.delivered.store(true, Ordering::Relaxed),
+            Err(err) => {
+                self.dropped_samples
+                    .fetch_add(formatted.samples as u64, Ordering::Relaxed);
+                error!(
+                    samples = formatted.samples,
+                    total_dropped = self.dropped_samples(),
+                    "dropping batch: {err}"
+                );
             }
         }
-        if !batch.is_empty() {
-            self.writer.send(batch.concat()).await?;
-            batch.clear();
+        if let Some(position) = position {
+            self.queue.ack(position)?;
         }
         Ok(())
     }
+
+    fn limits(&self) -> FlushLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    /// Apply reloaded settings of the destination. Queued batches are kept
+    /// and sent with the new settings; a batch being sent finishes with the
+    /// old ones.
+    pub fn reconfigure(&self, config: &RemoteWriteConfig) {
+        let mut writer = self.writer.lock().unwrap();
+        *writer = writer.reconfigured(config.url.clone(), RetryPolicy::from(&config.queue_config));
+        *self.limits.lock().unwrap() = FlushLimits::from(&config.queue_config);
+        self.queue
+            .set_max_bytes(config.queue_config.max_queue_bytes);
+    }
+
+    /// Samples dropped because the destination wouldn't accept them.
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples.load(Ordering::Relaxed)
+    }
+
+    /// Whether the destination has accepted a batch since the agent started.
+    pub fn has_delivered(&self) -> bool {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            formatted_bytes: self.formatted_bytes.load(Ordering::Relaxed),
+            formatted_samples: self.formatted_samples.load(Ordering::Relaxed),
+            queue_bytes: self.queue.pending_bytes(),
+            rejected_samples: self.dropped_samples(),
+            overflowed_samples: self.queue.dropped_samples(),
+            writes: self.writer.lock().unwrap().stats(),
+        }
+    }
+}
+
+/// Where [`dispatch`] sends scrapes. Replaced when the config is reloaded.
+#[derive(Clone, Default)]
+pub struct Routes {
+    pub destinations: Vec<mpsc::Sender<Arc<MetricsMessage>>>,
+    pub external_labels: Labels,
+}
+
+/// Fan every scrape out to each destination's pipeline, adding
+/// `external_labels` to every series on the way. Each scrape goes to the
+/// routes current when it is received.
+pub async fn dispatch(mut rx: mpsc::Receiver<MetricsMessage>, routes: watch::Receiver<Routes>) {
+    while let Some(mut metric_message) = rx.recv().await {
+        let routes = routes.borrow().clone();
+        add_external_labels(&mut metric_message.metrics, &routes.external_labels);
+        let metric_message = Arc::new(metric_message);
+        for tx in &routes.destinations {
+            if tx.send(Arc::clone(&metric_message)).await.is_err() {
+                warn!("destination pipeline closed, dropping scrape");
+            }
+        }
+    }
+}
+
+/// External labels identify the agent to the destinations. Labels a series
+/// already has take precedence over them, as in Prometheus.
+pub fn add_external_labels(metrics: &mut [MetricGroup], external_labels: &Labels) {
+    if external_labels.is_empty() {
+        return;
+    }
+    rewrite_labels(metrics, |labels| {
+        for (name, value) in external_labels {
+            labels.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::remote_write::RetryPolicy;
+    use prometheus_parser::parse_text;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn agent(dir: &Path, url: &str, limits: FlushLimits) -> Arc<MetricsAgent> {
+        let retry = RetryPolicy {
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_retries: Some(0),
+        };
+        let writer = RemoteWriter::new(
+            url.to_string(),
+            reqwest::Client::new(),
+            Protocol::Text,
+            retry,
+        );
+        let queue = DiskQueue::open(dir, 1 << 20).unwrap();
+        Arc::new(MetricsAgent::new(
+            writer,
+            Encoder::new(Protocol::Text),
+            limits,
+            queue,
+        ))
+    }
+
+    fn message(text: &str) -> Arc<MetricsMessage> {
+        Arc::new(MetricsMessage {
+            target_url: "http://127.0.0.1:9100/metrics".to_string(),
+            metrics: parse_text(text).unwrap(),
+            stale: Vec::new(),
+            scraped_at: UNIX_EPOCH + Duration::from_secs(1),
+        })
+    }
+
+    async fn next_queued(agent: &MetricsAgent) -> FormattedBatch {
+        loop {
+            if let Some((batch, _)) = agent.queue.read_next().unwrap() {
+                return batch;
+            }
+            agent.queue.wait().await;
+        }
+    }
+
+    async fn received_bodies(server: &MockServer) -> Vec<String> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|request| String::from_utf8(request.body).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_count() {
+        let metrics = parse_text(
+            r#"
+            # TYPE a counter
+            a{x="1"} 1
+            a{x="2"} 2
+            # TYPE h histogram
+            h_bucket{le="1"} 1
+            h_bucket{le="+Inf"} 2
+            h_sum 3
+            h_count 2
+            # TYPE s summary
+            s{quantile="0.5"} 1
+            s_sum 1
+            s_count 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(sample_count(&metrics), 2 + 4 + 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn format_flushes_on_sample_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            "http://127.0.0.1:1/",
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: usize::MAX,
+                max_samples: 3,
+            },
+        );
+        let (in_tx, in_rx) = mpsc::channel(8);
+        let handle = tokio::spawn({
+            let agent = agent.clone();
+            async move { agent.format(in_rx).await }
+        });
+
+        in_tx.send(message("a 1\nb 2\n")).await.unwrap();
+        in_tx.send(message("c 3\n")).await.unwrap();
+        in_tx.send(message("d 4\n")).await.unwrap();
+        let flushed = next_queued(&agent).await;
+        assert_eq!(flushed.samples, 3);
+        assert_eq!(
+            flushed.body,
+            b"# TYPE a untyped\na 1 1000\n# TYPE b untyped\nb 2 1000\n# TYPE c untyped\nc 3 1000\n"
+        );
+
+        drop(in_tx);
+        handle.await.unwrap().unwrap();
+        let rest = next_queued(&agent).await;
+        assert_eq!(rest.samples, 1);
+        assert_eq!(rest.body, b"# TYPE d untyped\nd 4 1000\n");
+        assert!(agent.queue.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn format_flushes_on_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            "http://127.0.0.1:1/",
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: 8,
+                max_samples: usize::MAX,
+            },
+        );
+        let (in_tx, in_rx) = mpsc::channel(8);
+        tokio::spawn({
+            let agent = agent.clone();
+            async move { agent.format(in_rx).await }
+        });
+
+        in_tx.send(message("long_metric_name 1\n")).await.unwrap();
+        let flushed = next_queued(&agent).await;
+        assert_eq!(
+            flushed.body,
+            b"# TYPE long_metric_name untyped\nlong_metric_name 1 1000\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn format_flushes_on_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let max_age = Duration::from_secs(10);
+        let agent = agent(
+            dir.path(),
+            "http://127.0.0.1:1/",
+            FlushLimits {
+                max_age,
+                max_bytes: usize::MAX,
+                max_samples: usize::MAX,
+            },
+        );
+        let (in_tx, in_rx) = mpsc::channel(8);
+        tokio::spawn({
+            let agent = agent.clone();
+            async move { agent.format(in_rx).await }
+        });
+
+        let start = time::Instant::now();
+        in_tx.send(message("a 1\n")).await.unwrap();
+        let flushed = next_queued(&agent).await;
+        assert_eq!(flushed.samples, 1);
+        assert!(start.elapsed() >= max_age);
+        // The channel stays open, so nothing else is flushed.
+        assert!(agent.queue.read_next().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_adds_external_labels() {
+        let (scrape_tx, scrape_rx) = mpsc::channel(1);
+        let (destination_tx, mut destination_rx) = mpsc::channel(1);
+        let external_labels = Labels::from([
+            ("cluster".to_string(), "eu-1".to_string()),
+            ("env".to_string(), "prod".to_string()),
+        ]);
+        let routes = Routes {
+            destinations: vec![destination_tx],
+            external_labels,
+        };
+        let dispatcher = tokio::spawn(dispatch(scrape_rx, watch::channel(routes).1));
+
+        let message = Arc::into_inner(message("a{env=\"dev\"} 1\nb 2\n")).unwrap();
+        scrape_tx.send(message).await.unwrap();
+        drop(scrape_tx);
+        let dispatched = destination_rx.recv().await.unwrap();
+        dispatcher.await.unwrap();
+
+        let mut document = TextDocument::default();
+        MetricsFormatter.format_into(&mut document, &dispatched);
+        assert_eq!(
+            document.finish(),
+            "# TYPE a untyped\na{cluster=\"eu-1\",env=\"dev\"} 1 1000\n\
+             # TYPE b untyped\nb{cluster=\"eu-1\",env=\"prod\"} 2 1000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatch_follows_route_changes() {
+        let (scrape_tx, scrape_rx) = mpsc::channel(1);
+        let (old_tx, mut old_rx) = mpsc::channel(1);
+        let (new_tx, mut new_rx) = mpsc::channel(1);
+        let (routes_tx, routes_rx) = watch::channel(Routes {
+            destinations: vec![old_tx],
+            external_labels: Labels::new(),
+        });
+        tokio::spawn(dispatch(scrape_rx, routes_rx));
+
+        let scrape = || Arc::into_inner(message("a 1\n")).unwrap();
+        scrape_tx.send(scrape()).await.unwrap();
+        assert!(old_rx.recv().await.is_some());
+
+        routes_tx.send_replace(Routes {
+            destinations: vec![new_tx],
+            external_labels: Labels::new(),
+        });
+        scrape_tx.send(scrape()).await.unwrap();
+        assert!(new_rx.recv().await.is_some());
+        // The removed destination's channel closes, ending its pipeline.
+        assert!(old_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconfigure_sends_queued_batches_with_new_settings() {
+        let old = MockServer::start().await;
+        let new = MockServer::start().await;
+        for server in [&old, &new] {
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .mount(server)
+                .await;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            &old.uri(),
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: usize::MAX,
+                max_samples: usize::MAX,
+            },
+        );
+        let body = "# TYPE a untyped\na 1\n";
+        agent
+            .queue
+            .push(&FormattedBatch {
+                body: body.as_bytes().to_vec(),
+                samples: 1,
+            })
+            .unwrap();
+
+        let config = RemoteWriteConfig {
+            url: new.uri(),
+            name: None,
+            protocol: Protocol::Text,
+            queue_config: QueueConfig::default(),
+        };
+        agent.reconfigure(&config);
+        agent.queue.close();
+        agent.write().await.unwrap();
+
+        assert!(received_bodies(&old).await.is_empty());
+        assert_eq!(received_bodies(&new).await, [body]);
+        assert_eq!(
+            agent.stats().writes.requests,
+            BTreeMap::from([("200".to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn write_drains_queue_on_close() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            &server.uri(),
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: usize::MAX,
+                max_samples: 2,
+            },
+        );
+
+        let (in_tx, in_rx) = mpsc::channel(8);
+        let formatter = tokio::spawn({
+            let agent = agent.clone();
+            async move { agent.format(in_rx).await }
+        });
+        for i in 0..3 {
+            in_tx
+                .send(message(&format!("m{{target=\"{i}\"}} 1\n")))
+                .await
+                .unwrap();
+        }
+        drop(in_tx);
+        formatter.await.unwrap().unwrap();
+        agent.write().await.unwrap();
+
+        assert_eq!(
+            received_bodies(&server).await,
+            [
+                "# TYPE m untyped\nm{target=\"0\"} 1 1000\nm{target=\"1\"} 1 1000\n",
+                "# TYPE m untyped\nm{target=\"2\"} 1 1000\n"
+            ]
+        );
+        let stats = agent.stats();
+        assert_eq!(stats.formatted_samples, 3);
+        assert_eq!(stats.queue_bytes, 0);
+        assert_eq!(
+            stats.writes.requests,
+            BTreeMap::from([("200".to_string(), 2)])
+        );
+        assert!(agent.has_delivered());
+    }
+
+    #[tokio::test]
+    async fn write_sends_queued_text_batches_apart() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            &server.uri(),
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: usize::MAX,
+                max_samples: usize::MAX,
+            },
+        );
+
+        for i in 0..2 {
+            let body = format!("# TYPE m untyped\nm{{target=\"{i}\"}} 1\n");
+            agent
+                .queue
+                .push(&FormattedBatch {
+                    body: body.into_bytes(),
+                    samples: 1,
+                })
+                .unwrap();
+        }
+        agent.queue.close();
+        agent.write().await.unwrap();
+
+        // Joined, the two documents would declare `m` twice.
+        assert_eq!(received_bodies(&server).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn drops_and_counts_rejected_batches() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let agent = agent(
+            dir.path(),
+            &server.uri(),
+            FlushLimits {
+                max_age: Duration::from_secs(3600),
+                max_bytes: usize::MAX,
+                max_samples: 1,
+            },
+        );
+        agent
+            .queue
+            .push(&FormattedBatch {
+                body: b"a 1\nb 1\n".to_vec(),
+                samples: 2,
+            })
+            .unwrap();
+        agent.queue.close();
+        agent.write().await.unwrap();
+
+        assert_eq!(agent.dropped_samples(), 2);
+        assert_eq!(agent.stats().dropped_samples(), 2);
+        assert!(!agent.has_delivered());
+        // The rejected batch is acknowledged, so it isn't replayed.
+        drop(agent);
+        let queue = DiskQueue::open(dir.path(), 1 << 20).unwrap();
+        assert!(queue.read_next().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn redelivers_in_flight_batch_after_kill() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = FlushLimits {
+            max_age: Duration::from_secs(3600),
+            max_bytes: usize::MAX,
+            max_samples: 1,
+        };
+
+        // The destination accepts two batches, then hangs on the third.
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3600)))
+            .with_priority(2)
+            .mount(&server)
+            .await;
+        {
+            let agent = agent(dir.path(), &server.uri(), limits);
+            for i in 0..4 {
+                agent
+                    .queue
+                    .push(&FormattedBatch {
+                        body: format!("m{i} 1\n").into_bytes(),
+                        samples: 1,
+                    })
+                    .unwrap();
+            }
+            let writer = tokio::spawn({
+                let agent = agent.clone();
+                async move { agent.write().await }
+            });
+            while received_bodies(&server).await.len() < 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            // Kill the agent while the third batch is in flight.
+            writer.abort();
+            assert!(writer.await.unwrap_err().is_cancelled());
+        }
+        let first_run = received_bodies(&server).await;
+        assert_eq!(first_run, ["m0 1\n", "m1 1\n", "m2 1\n"]);
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let agent = agent(dir.path(), &server.uri(), limits);
+        agent.queue.close();
+        agent.write().await.unwrap();
+
+        // Acknowledged batches are delivered exactly once, the in-flight one
+        // at least once, and nothing is lost.
+        assert_eq!(received_bodies(&server).await, ["m2 1\n", "m3 1\n"]);
+    }
 }
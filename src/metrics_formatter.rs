@@ -1,54 +1,199 @@
+use crate::config::Protocol;
 use crate::metrics_agent::MetricsMessage;
+use crate::series::SeriesKey;
 use indexmap::IndexMap;
 use prometheus_parser::{
     GroupKey, GroupKind, HistogramMetric, MetricGroup, SimpleMetric, SummaryMetric,
 };
 use std::collections::BTreeMap;
-use std::time::{Duration, Instant};
 
 pub struct MetricsFormatter;
 
 impl MetricsFormatter {
-    pub fn format_batch(&self, metrics_message: &[MetricsMessage]) -> String {
-        metrics_message
-            .iter()
-            .flat_map(|msg| &msg.metrics)
-            .map(format_simple_group)
-            .collect::<String>()
+    /// Add the scrape to a document holding other scrapes.
+    pub fn format_into(&self, document: &mut TextDocument, metrics_message: &MetricsMessage) {
+        document.push(metrics_message);
     }
-    pub fn format_single(&self, metrics_groups: &Vec<MetricGroup>) -> String {
-        metrics_groups
-            .iter()
-            .map(format_simple_group)
-            .collect::<String>()
+}
+
+/// A text exposition document for one or more scrapes. Families are
+/// written in the order they are first seen, each under a single header
+/// and with the samples of every scrape exposing it kept together, as the
+/// format requires. A family some scrape exposes with another type keeps
+/// the type it was first seen with.
+#[derive(Default)]
+pub struct TextDocument {
+    families: IndexMap<String, Family>,
+    /// Lines of stale series, by series name. They are added to their
+    /// family once the document is finished: a later scrape may still bring
+    /// the family of a series, and its header must come before the series.
+    stale: Vec<(String, String)>,
+    len: usize,
+}
+
+#[derive(Default)]
+struct Family {
+    header: String,
+    samples: String,
+}
+
+impl TextDocument {
+    fn push(&mut self, metrics_message: &MetricsMessage) {
+        let timestamp = metrics_message.timestamp();
+        self.push_groups(&metrics_message.metrics, Some(timestamp));
+        let stale = metrics_message.stale.iter();
+        for series in stale.filter(|series| series.written_with(Protocol::Text)) {
+            let line = format_stale_series(series, timestamp);
+            self.len += line.len();
+            self.stale.push((series.name.clone(), line));
+        }
+    }
+
+    /// Add groups. Series without a timestamp of their own get
+    /// `default_timestamp`, or none at all.
+    pub fn push_groups(&mut self, groups: &[MetricGroup], default_timestamp: Option<i64>) {
+        for group in groups {
+            let samples = format_samples(group, default_timestamp);
+            self.len += samples.len();
+            let family = match self.families.get_mut(&group.name) {
+                Some(family) => family,
+                None => {
+                    let header = format_header(group);
+                    self.len += header.len();
+                    self.families.entry(group.name.clone()).or_insert(Family {
+                        header,
+                        samples: String::new(),
+                    })
+                }
+            };
+            family.samples.push_str(&samples);
+        }
+    }
+
+    /// Length in bytes of the finished document.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn finish(mut self) -> String {
+        for (name, line) in std::mem::take(&mut self.stale) {
+            let name = family_name(&self.families, &name).to_string();
+            self.families
+                .entry(name)
+                .or_default()
+                .samples
+                .push_str(&line);
+        }
+        let mut result = String::with_capacity(self.len);
+        for family in self.families.into_values() {
+            result.push_str(&family.header);
+            result.push_str(&family.samples);
+        }
+        result
+    }
+}
+
+/// The family a sample belongs to: a family already in the document whose
+/// series include it, or else a family of its own.
+fn family_name<'a>(families: &IndexMap<String, Family>, name: &'a str) -> &'a str {
+    ["_bucket", "_sum", "_count", "_gsum", "_gcount"]
+        .iter()
+        .filter_map(|suffix| name.strip_suffix(suffix))
+        .find(|family| families.contains_key(*family))
+        .unwrap_or(name)
+}
+
+/// The text format has no spelling for the staleness marker itself, so
+/// stale series are written as `NaN`, which ends them for receivers that
+/// treat NaN as a missing value.
+pub fn format_stale_series(series: &SeriesKey, timestamp: i64) -> String {
+    format!(
+        "{} NaN {}\n",
+        format_series(&series.name, &series.labels),
+        timestamp
+    )
+}
+
+/// `# HELP`, `# TYPE` and `# UNIT` lines of the group.
+fn format_header(group: &MetricGroup) -> String {
+    let mut result = String::new();
+    if let Some(help) = &group.help {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        result.push_str(&format!("# HELP {} {}\n", group.name, help));
     }
+    if let Some(type_name) = type_name(&group.metrics) {
+        result.push_str(&format!("# TYPE {} {}\n", group.name, type_name));
+    }
+    if let Some(unit) = &group.unit {
+        result.push_str(&format!("# UNIT {} {}\n", group.name, unit));
+    }
+    result
 }
 
-pub fn format_simple_group(group: &MetricGroup) -> String {
+/// The group's type among those the text format knows. Info and state set
+/// metrics are gauges, as Prometheus ingests them. Gauge histograms have no
+/// equivalent; their series are left untyped.
+fn type_name(metrics: &GroupKind) -> Option<&'static str> {
+    match metrics {
+        GroupKind::Counter(_) => Some("counter"),
+        GroupKind::Gauge(_) | GroupKind::Info(_) | GroupKind::StateSet(_) => Some("gauge"),
+        GroupKind::Histogram(_) | GroupKind::NativeHistogram(_) => Some("histogram"),
+        GroupKind::Summary(_) => Some("summary"),
+        GroupKind::Untyped(_) => Some("untyped"),
+        GroupKind::GaugeHistogram(_) => None,
+    }
+}
+
+/// Sample lines of the group. Series without a timestamp of their own get
+/// `default_timestamp`, if any.
+fn format_samples(group: &MetricGroup, default_timestamp: Option<i64>) -> String {
     match &group.metrics {
-        GroupKind::Gauge(metrics) => format_simple_metric(&group.name, metrics),
-        GroupKind::Counter(metrics) => format_simple_metric(&group.name, metrics),
-        GroupKind::Untyped(metrics) => format_simple_metric(&group.name, metrics),
-        GroupKind::Summary(metrics) => format_summary_metric(&group.name, metrics),
-        GroupKind::Histogram(metrics) => format_histogram_metric(&group.name, metrics),
+        GroupKind::Gauge(metrics) => format_simple_metric(&group.name, metrics, default_timestamp),
+        GroupKind::Counter(metrics) => {
+            format_simple_metric(&group.name, metrics, default_timestamp)
+        }
+        GroupKind::Untyped(metrics) => {
+            format_simple_metric(&group.name, metrics, default_timestamp)
+        }
+        GroupKind::Info(metrics) => format_simple_metric(&group.name, metrics, default_timestamp),
+        GroupKind::StateSet(metrics) => {
+            format_simple_metric(&group.name, metrics, default_timestamp)
+        }
+        GroupKind::Summary(metrics) => {
+            format_summary_metric(&group.name, metrics, default_timestamp)
+        }
+        GroupKind::Histogram(metrics) | GroupKind::GaugeHistogram(metrics) => {
+            format_histogram_metric(
+                &group.name,
+                metrics,
+                group.metrics.sum_and_count_suffixes(),
+                default_timestamp,
+            )
+        }
+        // The text format has no native histograms; write them as their
+        // classic histogram.
+        GroupKind::NativeHistogram(metrics) => {
+            let classic = metrics
+                .iter()
+                .map(|(key, metric)| (key.clone(), metric.to_classic()))
+                .collect();
+            format_histogram_metric(&group.name, &classic, ("_sum", "_count"), default_timestamp)
+        }
     }
 }
 
 pub fn format_simple_metric(
     group_name: &str,
     metrics: &IndexMap<GroupKey, SimpleMetric>,
+    default_timestamp: Option<i64>,
 ) -> String {
     let mut result = String::new();
     for (key, metric) in metrics {
-        let timestamp = match key.timestamp {
-            Some(ts) => format!(" {}", ts),
-            None => String::new(),
-        };
+        let timestamp = format_timestamp(key.timestamp.or(default_timestamp));
         result.push_str(&format!(
-            "{}{{{}}} {}{}\n",
-            group_name,
-            format_labels(&key.labels),
-            metric.value,
+            "{} {}{}\n",
+            format_series(group_name, &key.labels),
+            label_value(metric.value),
             timestamp
         ))
     }
@@ -58,40 +203,33 @@ pub fn format_simple_metric(
 pub fn format_summary_metric(
     group_name: &str,
     metrics: &IndexMap<GroupKey, SummaryMetric>,
+    default_timestamp: Option<i64>,
 ) -> String {
     let mut result = String::new();
     for (key, metric) in metrics {
-        let timestamp = match key.timestamp {
-            Some(ts) => format!(" {}", ts),
-            None => String::new(),
-        };
+        let timestamp = format_timestamp(key.timestamp.or(default_timestamp));
 
         for quantile in &metric.quantiles {
             let mut labels = key.labels.clone();
-            labels.insert("quantile".to_string(), quantile.quantile.to_string());
+            labels.insert("quantile".to_string(), label_value(quantile.quantile));
             result.push_str(&format!(
-                "{}{{{}}} {}{}\n",
-                group_name,
-                format_labels(&labels),
-                quantile.value,
+                "{} {}{}\n",
+                format_series(group_name, &labels),
+                label_value(quantile.value),
                 timestamp
             ));
         }
 
-        let sum_labels = key.labels.clone();
         result.push_str(&format!(
-            "{}_sum{{{}}} {}{}\n",
-            group_name,
-            format_labels(&sum_labels),
-            metric.sum,
+            "{} {}{}\n",
+            format_series(&format!("{group_name}_sum"), &key.labels),
+            label_value(metric.sum),
             timestamp
         ));
 
-        let count_labels = key.labels.clone();
         result.push_str(&format!(
-            "{}_count{{{}}} {}{}\n",
-            group_name,
-            format_labels(&count_labels),
+            "{} {}{}\n",
+            format_series(&format!("{group_name}_count"), &key.labels),
             metric.count,
             timestamp
         ));
@@ -102,40 +240,34 @@ pub fn format_summary_metric(
 pub fn format_histogram_metric(
     group_name: &str,
     metrics: &IndexMap<GroupKey, HistogramMetric>,
+    (sum_suffix, count_suffix): (&str, &str),
+    default_timestamp: Option<i64>,
 ) -> String {
     let mut result = String::new();
     for (key, metric) in metrics {
-        let timestamp = match key.timestamp {
-            Some(ts) => format!(" {}", ts),
-            None => String::new(),
-        };
+        let timestamp = format_timestamp(key.timestamp.or(default_timestamp));
 
         for bucket in &metric.buckets {
             let mut labels = key.labels.clone();
-            labels.insert("le".to_string(), bucket.bucket.to_string());
+            labels.insert("le".to_string(), label_value(bucket.bucket));
             result.push_str(&format!(
-                "{}_bucket{{{}}} {}{}\n",
-                group_name,
-                format_labels(&labels),
+                "{} {}{}\n",
+                format_series(&format!("{group_name}_bucket"), &labels),
                 bucket.count,
                 timestamp
             ));
         }
 
-        let sum_labels = key.labels.clone();
         result.push_str(&format!(
-            "{}_sum{{{}}} {}{}\n",
-            group_name,
-            format_labels(&sum_labels),
-            metric.sum,
+            "{} {}{}\n",
+            format_series(&format!("{group_name}{sum_suffix}"), &key.labels),
+            label_value(metric.sum),
             timestamp
         ));
 
-        let count_labels = key.labels.clone();
         result.push_str(&format!(
-            "{}_count{{{}}} {}{}\n",
-            group_name,
-            format_labels(&count_labels),
+            "{} {}{}\n",
+            format_series(&format!("{group_name}{count_suffix}"), &key.labels),
             metric.count,
             timestamp
         ));
@@ -143,14 +275,426 @@ pub fn format_histogram_metric(
     result
 }
 
+/// ` <timestamp>` to end a sample line with, if there is one.
+fn format_timestamp(timestamp: Option<i64>) -> String {
+    timestamp.map_or_else(String::new, |timestamp| format!(" {timestamp}"))
+}
+
+/// The series name followed by its labels in braces, if it has any.
+fn format_series(name: &str, labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, format_labels(labels))
+    }
+}
+
 pub fn format_labels(labels: &BTreeMap<String, String>) -> String {
     if labels.is_empty() {
         String::new()
     } else {
         labels
             .iter()
-            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
             .collect::<Vec<_>>()
             .join(",")
     }
 }
+
+/// Sample values and `le` and `quantile` labels, spelled the way Prometheus
+/// does: `+Inf`, `-Inf`, `NaN`, or the shortest digits that parse back to
+/// the same value, with an exponent below 1e-4 and from 1e6 on, as Go's
+/// `strconv.FormatFloat(value, 'g', -1, 64)`. Buckets get the same `le`
+/// whether Go clients or the agent write them.
+pub fn label_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        return "+Inf".to_string();
+    } else if value == f64::NEG_INFINITY {
+        return "-Inf".to_string();
+    } else if value.is_nan() {
+        return "NaN".to_string();
+    }
+    let scientific = format!("{value:e}");
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("exponent notation has an exponent");
+    let exponent: i32 = exponent.parse().expect("exponent is an integer");
+    if (-4..6).contains(&exponent) {
+        value.to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{mantissa}e{sign}{:02}", exponent.abs())
+    }
+}
+
+/// Backslashes, double quotes and line feeds are the only characters the
+/// text format escapes in label values.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::relabel::{Labels, rewrite_series};
+    use crate::series::series_keys;
+    use prometheus_parser::{
+        BucketSpan, HistogramBucket, NativeHistogramMetric, SummaryQuantile, parse_text,
+    };
+    use proptest::prelude::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Time of the scrapes, in milliseconds.
+    const TIMESTAMP: i64 = 1000;
+
+    fn format_simple_group(group: &MetricGroup) -> String {
+        let mut result = format_header(group);
+        result.push_str(&format_samples(group, Some(TIMESTAMP)));
+        result
+    }
+
+    fn message(text: &str, stale: Vec<SeriesKey>) -> MetricsMessage {
+        MetricsMessage {
+            target_url: "http://127.0.0.1:9100/metrics".to_string(),
+            metrics: parse_text(text).unwrap(),
+            stale,
+            scraped_at: UNIX_EPOCH + Duration::from_millis(TIMESTAMP as u64),
+        }
+    }
+
+    fn format(messages: &[MetricsMessage]) -> String {
+        let mut document = TextDocument::default();
+        for message in messages {
+            MetricsFormatter.format_into(&mut document, message);
+        }
+        let len = document.len();
+        let text = document.finish();
+        assert_eq!(text.len(), len);
+        text
+    }
+
+    fn value() -> impl Strategy<Value = f64> {
+        prop_oneof![
+            any::<f64>(),
+            Just(f64::INFINITY),
+            Just(f64::NEG_INFINITY),
+            Just(f64::NAN),
+            Just(-0.0),
+        ]
+    }
+
+    /// Counts go through an `f64` when parsed.
+    fn count() -> impl Strategy<Value = u64> {
+        0..(1u64 << f64::MANTISSA_DIGITS)
+    }
+
+    fn key() -> impl Strategy<Value = GroupKey> {
+        let label_name = "[a-zA-Z_][a-zA-Z0-9_]{0,8}"
+            .prop_filter("reserved label", |name| name != "le" && name != "quantile");
+        (
+            proptest::option::of(any::<i64>()),
+            proptest::collection::btree_map(label_name, any::<String>(), 0..4),
+        )
+            .prop_map(|(timestamp, labels)| GroupKey { timestamp, labels })
+    }
+
+    fn simple_metrics() -> impl Strategy<Value = IndexMap<GroupKey, SimpleMetric>> {
+        proptest::collection::vec(
+            (key(), value().prop_map(|value| SimpleMetric { value })),
+            1..4,
+        )
+        .prop_map(|metrics| metrics.into_iter().collect())
+    }
+
+    fn group_kind() -> impl Strategy<Value = GroupKind> {
+        let summary = (
+            proptest::collection::vec(
+                (value(), value())
+                    .prop_map(|(quantile, value)| SummaryQuantile { quantile, value }),
+                1..4,
+            ),
+            value(),
+            count(),
+        )
+            .prop_map(|(quantiles, sum, count)| SummaryMetric {
+                quantiles,
+                sum,
+                count,
+            });
+        let histogram = (
+            proptest::collection::vec(
+                (value(), count()).prop_map(|(bucket, count)| HistogramBucket { bucket, count }),
+                1..4,
+            ),
+            value(),
+            count(),
+        )
+            .prop_map(|(buckets, sum, count)| HistogramMetric {
+                buckets,
+                sum,
+                count,
+            });
+        prop_oneof![
+            simple_metrics().prop_map(GroupKind::Counter),
+            simple_metrics().prop_map(GroupKind::Gauge),
+            simple_metrics().prop_map(GroupKind::Untyped),
+            proptest::collection::vec((key(), summary), 1..3)
+                .prop_map(|metrics| GroupKind::Summary(metrics.into_iter().collect())),
+            proptest::collection::vec((key(), histogram), 1..3)
+                .prop_map(|metrics| GroupKind::Histogram(metrics.into_iter().collect())),
+        ]
+    }
+
+    /// Groups with distinct names, as the parser would merge the rest.
+    fn groups() -> impl Strategy<Value = Vec<MetricGroup>> {
+        let group = (
+            "[a-zA-Z_][a-zA-Z0-9_:]{0,8}",
+            // Lines are trimmed and the format has no escape for `\r`.
+            proptest::option::of("[!-~]([^\r]{0,20}[!-~])?"),
+            group_kind(),
+        );
+        proptest::collection::vec(group, 0..4).prop_map(|groups| {
+            groups
+                .into_iter()
+                .enumerate()
+                .map(|(i, (name, help, metrics))| MetricGroup {
+                    name: format!("{name}_{i}"),
+                    metrics,
+                    help,
+                    unit: None,
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_groups_parse_back(mut groups in groups()) {
+            let text = groups.iter().map(format_simple_group).collect::<String>();
+            let parsed = parse_text(&text).unwrap();
+            rewrite_series(&mut groups, |key| {
+                key.timestamp.get_or_insert(TIMESTAMP);
+            });
+            // The debug output spells every NaN the same, as they are all
+            // parsed back as the same one.
+            prop_assert_eq!(format!("{parsed:#?}"), format!("{groups:#?}"));
+        }
+    }
+
+    #[test]
+    fn label_values_are_spelled_as_go_clients_do() {
+        for (value, text) in [
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (0.25, "0.25"),
+            (0.0001, "0.0001"),
+            (1e-5, "1e-05"),
+            (1e-7, "1e-07"),
+            (-2.5e-7, "-2.5e-07"),
+            (123456.0, "123456"),
+            (1e6, "1e+06"),
+            (1234567.0, "1.234567e+06"),
+            (1e300, "1e+300"),
+            (f64::MAX, "1.7976931348623157e+308"),
+            (f64::MIN_POSITIVE, "2.2250738585072014e-308"),
+        ] {
+            assert_eq!(label_value(value), text);
+            assert_eq!(text.parse::<f64>().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn escapes_label_values_and_spells_special_floats() {
+        let key = GroupKey {
+            timestamp: None,
+            labels: [("path".to_string(), "C:\\\"a\"\nb".to_string())].into(),
+        };
+        let metrics = [(
+            key,
+            SimpleMetric {
+                value: f64::NEG_INFINITY,
+            },
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            format_simple_metric("a", &metrics, Some(TIMESTAMP)),
+            "a{path=\"C:\\\\\\\"a\\\"\\nb\"} -Inf 1000\n"
+        );
+    }
+
+    fn native_histogram_group() -> MetricGroup {
+        let key = GroupKey {
+            timestamp: None,
+            labels: BTreeMap::new(),
+        };
+        let metric = NativeHistogramMetric {
+            schema: 0,
+            count: 3,
+            sum: 2.5,
+            positive_spans: vec![BucketSpan {
+                offset: 0,
+                length: 2,
+            }],
+            positive_deltas: vec![1, 1],
+            ..Default::default()
+        };
+        MetricGroup {
+            name: "a".to_string(),
+            metrics: GroupKind::NativeHistogram([(key, metric)].into_iter().collect()),
+            help: None,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn spells_large_and_small_values_with_an_exponent() {
+        let groups = parse_text(
+            "# TYPE h histogram\n\
+             h_bucket{le=\"0.00001\"} 1\n\
+             h_bucket{le=\"0.5\"} 2\n\
+             h_bucket{le=\"1000000\"} 3\n\
+             h_bucket{le=\"+Inf\"} 3\n\
+             h_sum 1e300\n\
+             h_count 3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            format_simple_group(&groups[0]),
+            "# TYPE h histogram\n\
+             h_bucket{le=\"1e-05\"} 1 1000\n\
+             h_bucket{le=\"0.5\"} 2 1000\n\
+             h_bucket{le=\"1e+06\"} 3 1000\n\
+             h_bucket{le=\"+Inf\"} 3 1000\n\
+             h_sum 1e+300 1000\n\
+             h_count 3 1000\n"
+        );
+    }
+
+    #[test]
+    fn formats_native_histograms_as_classic() {
+        assert_eq!(
+            format_simple_group(&native_histogram_group()),
+            "# TYPE a histogram\n\
+             a_bucket{le=\"0\"} 0 1000\n\
+             a_bucket{le=\"1\"} 1 1000\n\
+             a_bucket{le=\"2\"} 3 1000\n\
+             a_bucket{le=\"+Inf\"} 3 1000\n\
+             a_sum 2.5 1000\n\
+             a_count 3 1000\n"
+        );
+    }
+
+    #[test]
+    fn marks_the_classic_series_of_native_histograms_stale() {
+        let mut stale = series_keys(&[native_histogram_group()])
+            .into_iter()
+            .collect::<Vec<_>>();
+        stale.sort_by_key(|series| format_series(&series.name, &series.labels));
+        assert_eq!(
+            format(&[message("", stale)]),
+            "a_bucket{le=\"+Inf\"} NaN 1000\n\
+             a_bucket{le=\"0\"} NaN 1000\n\
+             a_bucket{le=\"1\"} NaN 1000\n\
+             a_bucket{le=\"2\"} NaN 1000\n\
+             a_count NaN 1000\n\
+             a_sum NaN 1000\n"
+        );
+    }
+
+    #[test]
+    fn formats_help_and_unit() {
+        let mut groups = parse_text("a_seconds 1\n").unwrap();
+        groups[0].help = Some("Multi-line\nhelp with a \\.".to_string());
+        groups[0].unit = Some("seconds".to_string());
+        assert_eq!(
+            format_simple_group(&groups[0]),
+            "# HELP a_seconds Multi-line\\nhelp with a \\\\.\n\
+             # TYPE a_seconds untyped\n\
+             # UNIT a_seconds seconds\n\
+             a_seconds 1 1000\n"
+        );
+    }
+
+    #[test]
+    fn writes_stale_series_after_the_header_of_a_later_scrape() {
+        let stale = message(
+            "",
+            vec![SeriesKey::new(
+                "h_bucket".to_string(),
+                Labels::from([
+                    ("instance".to_string(), "a".to_string()),
+                    ("le".to_string(), "+Inf".to_string()),
+                ]),
+            )],
+        );
+        let exposing = message(
+            "# TYPE h histogram\n\
+             h_bucket{instance=\"b\",le=\"+Inf\"} 1\n\
+             h_sum{instance=\"b\"} 1\n\
+             h_count{instance=\"b\"} 1\n",
+            Vec::new(),
+        );
+        assert_eq!(
+            format(&[stale, exposing]),
+            "# TYPE h histogram\n\
+             h_bucket{instance=\"b\",le=\"+Inf\"} 1 1000\n\
+             h_sum{instance=\"b\"} 1 1000\n\
+             h_count{instance=\"b\"} 1 1000\n\
+             h_bucket{instance=\"a\",le=\"+Inf\"} NaN 1000\n"
+        );
+    }
+
+    #[test]
+    fn keeps_families_contiguous_across_scrapes() {
+        let first = message(
+            "# HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total{instance=\"a\"} 1\n\
+             # TYPE up gauge\n\
+             up{instance=\"a\"} 1\n",
+            Vec::new(),
+        );
+        let second = message(
+            "# TYPE requests_total counter\n\
+             requests_total{instance=\"b\"} 2 500\n\
+             # TYPE h histogram\n\
+             h_bucket{instance=\"b\",le=\"+Inf\"} 1\n\
+             h_sum{instance=\"b\"} 1\n\
+             h_count{instance=\"b\"} 1\n",
+            Vec::new(),
+        );
+        let third = message(
+            "# TYPE up gauge\nup{instance=\"c\"} 0\n",
+            vec![
+                SeriesKey::new(
+                    "h_bucket".to_string(),
+                    Labels::from([
+                        ("instance".to_string(), "c".to_string()),
+                        ("le".to_string(), "+Inf".to_string()),
+                    ]),
+                ),
+                SeriesKey::new("gone".to_string(), Labels::new()),
+            ],
+        );
+        assert_eq!(
+            format(&[first, second, third]),
+            "# HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total{instance=\"a\"} 1 1000\n\
+             requests_total{instance=\"b\"} 2 500\n\
+             # TYPE up gauge\n\
+             up{instance=\"a\"} 1 1000\n\
+             up{instance=\"c\"} 0 1000\n\
+             # TYPE h histogram\n\
+             h_bucket{instance=\"b\",le=\"+Inf\"} 1 1000\n\
+             h_sum{instance=\"b\"} 1 1000\n\
+             h_count{instance=\"b\"} 1 1000\n\
+             h_bucket{instance=\"c\",le=\"+Inf\"} NaN 1000\n\
+             gone NaN 1000\n"
+        );
+    }
+}
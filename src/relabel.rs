@@ -0,0 +1,1005 @@
+//! Prometheus-compatible relabeling.
+//!
+//! A list of [`RelabelConfig`] steps rewrites a label set in order. Any
+//! step may drop the whole set, in which case the remaining steps are not
+//! applied. Semantics follow Prometheus' `relabel_configs`, including the
+//! `hashmod` hash, so targets shard the same way they would there.
+//!
+//! Steps run over target labels before scraping (`relabel_configs`) and
+//! over every scraped series afterwards (`metric_relabel_configs`).
+
+use crate::metrics_formatter::label_value;
+use indexmap::IndexMap;
+use prometheus_parser::{
+    GroupKey, GroupKind, HistogramBucket, HistogramMetric, METRIC_NAME_LABEL, MetricGroup,
+    SimpleMetric, SummaryMetric, SummaryQuantile,
+};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Set `target_label` to `replacement`, expanded with the regex
+    /// captures of the joined source label values.
+    #[default]
+    Replace,
+    /// Drop the label set unless the regex matches the source values.
+    Keep,
+    /// Drop the label set if the regex matches the source values.
+    Drop,
+    /// Drop the label set unless the source values equal `target_label`.
+    KeepEqual,
+    /// Drop the label set if the source values equal `target_label`.
+    DropEqual,
+    /// Set `target_label` to a hash of the source values modulo `modulus`.
+    HashMod,
+    /// Copy every label whose name matches the regex to the name given by
+    /// `replacement`.
+    LabelMap,
+    /// Remove every label whose name matches the regex.
+    LabelDrop,
+    /// Remove every label whose name doesn't match the regex.
+    LabelKeep,
+    /// Set `target_label` to the lower-cased source values.
+    Lowercase,
+    /// Set `target_label` to the upper-cased source values.
+    Uppercase,
+}
+
+/// A regex that has to match a whole value, as in Prometheus.
+#[derive(Debug, Clone)]
+pub struct RelabelRegex(Regex);
+
+impl RelabelRegex {
+    pub fn new(source: &str) -> Result<Self, regex::Error> {
+        Regex::new(&format!("^(?:{source})$")).map(RelabelRegex)
+    }
+}
+
+/// Regexes are equal when compiled from the same source.
+impl PartialEq for RelabelRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Default for RelabelRegex {
+    fn default() -> Self {
+        RelabelRegex::new("(.*)").expect("default regex is valid")
+    }
+}
+
+impl<'de> Deserialize<'de> for RelabelRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        RelabelRegex::new(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+/// One relabeling step.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelabelConfig {
+    #[serde(default)]
+    pub source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    #[serde(default)]
+    pub target_label: Option<String>,
+    #[serde(default)]
+    pub regex: RelabelRegex,
+    #[serde(default)]
+    pub modulus: Option<u64>,
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+    #[serde(default)]
+    pub action: Action,
+}
+
+fn default_separator() -> String {
+    ";".to_string()
+}
+
+fn default_replacement() -> String {
+    "$1".to_string()
+}
+
+impl RelabelConfig {
+    /// Check that the step has the fields its action needs.
+    pub fn validate(&self) -> Result<(), String> {
+        let target_label = self.target_label.as_deref();
+        match self.action {
+            Action::Replace => match target_label {
+                None => return Err("`target_label` is required for action `replace`".into()),
+                // The name may be a template expanded with the captures.
+                Some(name) if !name.contains('$') && !is_valid_label_name(name) => {
+                    return Err(format!("`{name}` is not a valid label name"));
+                }
+                Some(_) => {}
+            },
+            Action::KeepEqual
+            | Action::DropEqual
+            | Action::HashMod
+            | Action::Lowercase
+            | Action::Uppercase => match target_label {
+                None => {
+                    return Err(format!(
+                        "`target_label` is required for action `{}`",
+                        self.action_name()
+                    ));
+                }
+                Some(name) if !is_valid_label_name(name) => {
+                    return Err(format!("`{name}` is not a valid label name"));
+                }
+                Some(_) => {}
+            },
+            Action::LabelDrop | Action::LabelKeep => {
+                if !self.source_labels.is_empty() || target_label.is_some() {
+                    return Err(format!(
+                        "action `{}` only takes a `regex`",
+                        self.action_name()
+                    ));
+                }
+            }
+            Action::Keep | Action::Drop | Action::LabelMap => {}
+        }
+        if self.action == Action::HashMod && self.modulus.unwrap_or_default() == 0 {
+            return Err("`modulus` must be greater than zero for action `hashmod`".into());
+        }
+        Ok(())
+    }
+
+    fn action_name(&self) -> &'static str {
+        match self.action {
+            Action::Replace => "replace",
+            Action::Keep => "keep",
+            Action::Drop => "drop",
+            Action::KeepEqual => "keepequal",
+            Action::DropEqual => "dropequal",
+            Action::HashMod => "hashmod",
+            Action::LabelMap => "labelmap",
+            Action::LabelDrop => "labeldrop",
+            Action::LabelKeep => "labelkeep",
+            Action::Lowercase => "lowercase",
+            Action::Uppercase => "uppercase",
+        }
+    }
+
+    /// Apply the step to `labels`. Returns false if the label set is to be
+    /// dropped.
+    pub fn apply(&self, labels: &mut Labels) -> bool {
+        let value = self
+            .source_labels
+            .iter()
+            .map(|name| labels.get(name).map(String::as_str).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(&self.separator);
+        let target_label = self.target_label.as_deref().unwrap_or_default();
+        let regex = &self.regex.0;
+
+        match self.action {
+            Action::Replace => {
+                let Some(captures) = regex.captures(&value) else {
+                    return true;
+                };
+                let mut name = String::new();
+                captures.expand(target_label, &mut name);
+                if !is_valid_label_name(&name) {
+                    return true;
+                }
+                let mut replaced = String::new();
+                captures.expand(&self.replacement, &mut replaced);
+                if replaced.is_empty() {
+                    labels.remove(&name);
+                } else {
+                    labels.insert(name, replaced);
+                }
+            }
+            Action::Keep => return regex.is_match(&value),
+            Action::Drop => return !regex.is_match(&value),
+            Action::KeepEqual => {
+                return labels
+                    .get(target_label)
+                    .map(String::as_str)
+                    .unwrap_or_default()
+                    == value;
+            }
+            Action::DropEqual => {
+                return labels
+                    .get(target_label)
+                    .map(String::as_str)
+                    .unwrap_or_default()
+                    != value;
+            }
+            Action::HashMod => {
+                // Same hash as Prometheus: the low 8 bytes of the MD5 sum,
+                // read big-endian.
+                let digest = md5::compute(value.as_bytes());
+                let mut low = [0; 8];
+                low.copy_from_slice(&digest.0[8..]);
+                let modulus = self.modulus.unwrap_or(1).max(1);
+                let hash = u64::from_be_bytes(low) % modulus;
+                labels.insert(target_label.to_string(), hash.to_string());
+            }
+            Action::LabelMap => {
+                let mapped = labels
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        let captures = regex.captures(name)?;
+                        let mut mapped = String::new();
+                        captures.expand(&self.replacement, &mut mapped);
+                        Some((mapped, value.clone()))
+                    })
+                    .collect::<Vec<_>>();
+                labels.extend(mapped);
+            }
+            Action::LabelDrop => labels.retain(|name, _| !regex.is_match(name)),
+            Action::LabelKeep => labels.retain(|name, _| regex.is_match(name)),
+            Action::Lowercase => {
+                labels.insert(target_label.to_string(), value.to_lowercase());
+            }
+            Action::Uppercase => {
+                labels.insert(target_label.to_string(), value.to_uppercase());
+            }
+        }
+        true
+    }
+}
+
+/// Run `labels` through every step in order. `None` means a step dropped
+/// the label set.
+pub fn relabel(mut labels: Labels, configs: &[RelabelConfig]) -> Option<Labels> {
+    for config in configs {
+        if !config.apply(&mut labels) {
+            return None;
+        }
+    }
+    Some(labels)
+}
+
+/// Run every series of the scraped groups through `configs`.
+///
+/// A series is seen as its labels plus `__name__`, as Prometheus sees it:
+/// the buckets, quantiles, `_sum` and `_count` of a histogram or summary
+/// are series of their own, with their `le` or `quantile` label. Rules
+/// can't remove or change that label on a kept bucket or quantile though.
+/// Series whose `__name__` is changed move to a group of that name and the
+/// same type; groups left without series are removed. What is left of a
+/// histogram or summary without its `_sum` or `_count` is written as
+/// untyped series.
+pub fn relabel_groups(groups: Vec<MetricGroup>, configs: &[RelabelConfig]) -> Vec<MetricGroup> {
+    if configs.is_empty() {
+        return groups;
+    }
+    let mut relabeled = Vec::with_capacity(groups.len());
+    for group in groups {
+        let MetricGroup {
+            name,
+            metrics,
+            help,
+            unit,
+        } = group;
+        let start = relabeled.len();
+        match metrics {
+            GroupKind::Gauge(metrics) => {
+                relabel_family(&mut relabeled, name, metrics, configs, GroupKind::Gauge)
+            }
+            GroupKind::Counter(metrics) => {
+                relabel_family(&mut relabeled, name, metrics, configs, GroupKind::Counter)
+            }
+            GroupKind::Untyped(metrics) => {
+                relabel_family(&mut relabeled, name, metrics, configs, GroupKind::Untyped)
+            }
+            GroupKind::Info(metrics) => {
+                relabel_family(&mut relabeled, name, metrics, configs, GroupKind::Info)
+            }
+            GroupKind::StateSet(metrics) => {
+                relabel_family(&mut relabeled, name, metrics, configs, GroupKind::StateSet)
+            }
+            GroupKind::Summary(metrics) => relabel_compound(
+                &mut relabeled,
+                name,
+                metrics,
+                configs,
+                ("_sum", "_count"),
+                GroupKind::Summary,
+            ),
+            GroupKind::Histogram(metrics) => relabel_compound(
+                &mut relabeled,
+                name,
+                metrics,
+                configs,
+                ("_sum", "_count"),
+                GroupKind::Histogram,
+            ),
+            GroupKind::GaugeHistogram(metrics) => relabel_compound(
+                &mut relabeled,
+                name,
+                metrics,
+                configs,
+                ("_gsum", "_gcount"),
+                GroupKind::GaugeHistogram,
+            ),
+            // A native histogram is a single series.
+            GroupKind::NativeHistogram(metrics) => relabel_family(
+                &mut relabeled,
+                name,
+                metrics,
+                configs,
+                GroupKind::NativeHistogram,
+            ),
+        }
+        // Renamed series keep the metadata of the family they came from.
+        for group in &mut relabeled[start..] {
+            group.help.clone_from(&help);
+            group.unit.clone_from(&unit);
+        }
+    }
+    relabeled
+}
+
+fn relabel_family<T>(
+    relabeled: &mut Vec<MetricGroup>,
+    name: String,
+    metrics: IndexMap<GroupKey, T>,
+    configs: &[RelabelConfig],
+    kind: fn(IndexMap<GroupKey, T>) -> GroupKind,
+) {
+    let mut families: IndexMap<String, IndexMap<GroupKey, T>> = IndexMap::new();
+    for (key, metric) in metrics {
+        let Some((name, labels)) = relabel_series(&name, key.labels, configs) else {
+            continue;
+        };
+        let key = GroupKey {
+            timestamp: key.timestamp,
+            labels,
+        };
+        families.entry(name).or_default().insert(key, metric);
+    }
+    extend_groups(relabeled, families, kind);
+}
+
+/// The name and labels of a series once relabeled, unless it was dropped.
+fn relabel_series(
+    name: &str,
+    mut labels: Labels,
+    configs: &[RelabelConfig],
+) -> Option<(String, Labels)> {
+    labels.insert(METRIC_NAME_LABEL.to_string(), name.to_string());
+    let mut labels = relabel(labels, configs)?;
+    let name = labels.remove(METRIC_NAME_LABEL)?;
+    Some((name, labels))
+}
+
+fn extend_groups<T>(
+    relabeled: &mut Vec<MetricGroup>,
+    families: IndexMap<String, IndexMap<GroupKey, T>>,
+    kind: fn(IndexMap<GroupKey, T>) -> GroupKind,
+) {
+    relabeled.extend(families.into_iter().map(|(name, metrics)| MetricGroup {
+        name,
+        metrics: kind(metrics),
+        help: None,
+        unit: None,
+    }));
+}
+
+/// A histogram or summary, made of a series per bucket or quantile, a
+/// `_sum` and a `_count` series.
+trait Compound: Sized {
+    type Point;
+    /// Label telling the buckets or quantiles apart.
+    const LABEL: &'static str;
+    /// Name suffix of the bucket or quantile series.
+    const SUFFIX: &'static str;
+
+    fn into_parts(self) -> (Vec<Self::Point>, f64, u64);
+    fn from_parts(points: Vec<Self::Point>, sum: f64, count: u64) -> Self;
+    /// The point's `le` or `quantile`.
+    fn bound(point: &Self::Point) -> f64;
+    fn value(point: &Self::Point) -> f64;
+}
+
+impl Compound for HistogramMetric {
+    type Point = HistogramBucket;
+    const LABEL: &'static str = "le";
+    const SUFFIX: &'static str = "_bucket";
+
+    fn into_parts(self) -> (Vec<HistogramBucket>, f64, u64) {
+        (self.buckets, self.sum, self.count)
+    }
+
+    fn from_parts(buckets: Vec<HistogramBucket>, sum: f64, count: u64) -> Self {
+        HistogramMetric {
+            buckets,
+            sum,
+            count,
+        }
+    }
+
+    fn bound(bucket: &HistogramBucket) -> f64 {
+        bucket.bucket
+    }
+
+    fn value(bucket: &HistogramBucket) -> f64 {
+        bucket.count as f64
+    }
+}
+
+impl Compound for SummaryMetric {
+    type Point = SummaryQuantile;
+    const LABEL: &'static str = "quantile";
+    const SUFFIX: &'static str = "";
+
+    fn into_parts(self) -> (Vec<SummaryQuantile>, f64, u64) {
+        (self.quantiles, self.sum, self.count)
+    }
+
+    fn from_parts(quantiles: Vec<SummaryQuantile>, sum: f64, count: u64) -> Self {
+        SummaryMetric {
+            quantiles,
+            sum,
+            count,
+        }
+    }
+
+    fn bound(quantile: &SummaryQuantile) -> f64 {
+        quantile.quantile
+    }
+
+    fn value(quantile: &SummaryQuantile) -> f64 {
+        quantile.value
+    }
+}
+
+/// The series of a histogram or summary that were kept.
+struct Parts<P> {
+    points: Vec<P>,
+    sum: Option<f64>,
+    count: Option<u64>,
+}
+
+impl<P> Default for Parts<P> {
+    fn default() -> Self {
+        Parts {
+            points: Vec::new(),
+            sum: None,
+            count: None,
+        }
+    }
+}
+
+enum Total {
+    Sum(f64),
+    Count(u64),
+}
+
+impl Total {
+    fn value(&self) -> f64 {
+        match *self {
+            Total::Sum(sum) => sum,
+            Total::Count(count) => count as f64,
+        }
+    }
+}
+
+/// Relabel each series of the histograms or summaries on its own, then put
+/// back together those still sharing a family and labels.
+fn relabel_compound<T: Compound>(
+    relabeled: &mut Vec<MetricGroup>,
+    name: String,
+    metrics: IndexMap<GroupKey, T>,
+    configs: &[RelabelConfig],
+    (sum_suffix, count_suffix): (&str, &str),
+    kind: fn(IndexMap<GroupKey, T>) -> GroupKind,
+) {
+    let mut parts: IndexMap<(String, GroupKey), Parts<T::Point>> = IndexMap::new();
+    // Series that no longer belong to a histogram or summary.
+    let mut loose: IndexMap<String, IndexMap<GroupKey, SimpleMetric>> = IndexMap::new();
+    let mut add_loose = |name: String, timestamp, labels, value| {
+        let key = GroupKey { timestamp, labels };
+        loose
+            .entry(name)
+            .or_default()
+            .insert(key, SimpleMetric { value });
+    };
+    let with_bound = |labels: &Labels, point: &T::Point| {
+        let mut labels = labels.clone();
+        labels.insert(T::LABEL.to_string(), label_value(T::bound(point)));
+        labels
+    };
+
+    for (key, metric) in metrics {
+        let timestamp = key.timestamp;
+        let (points, sum, count) = metric.into_parts();
+        for point in points {
+            let series = relabel_series(
+                &format!("{name}{}", T::SUFFIX),
+                with_bound(&key.labels, &point),
+                configs,
+            );
+            let Some((series_name, mut labels)) = series else {
+                continue;
+            };
+            labels.remove(T::LABEL);
+            match series_name.strip_suffix(T::SUFFIX) {
+                Some(family) => {
+                    let key = GroupKey { timestamp, labels };
+                    let parts = parts.entry((family.to_string(), key)).or_default();
+                    parts.points.push(point);
+                }
+                None => {
+                    let labels = with_bound(&labels, &point);
+                    add_loose(series_name, timestamp, labels, T::value(&point));
+                }
+            }
+        }
+        for (suffix, total) in [
+            (sum_suffix, Total::Sum(sum)),
+            (count_suffix, Total::Count(count)),
+        ] {
+            let series = relabel_series(&format!("{name}{suffix}"), key.labels.clone(), configs);
+            let Some((series_name, mut labels)) = series else {
+                continue;
+            };
+            labels.remove(T::LABEL);
+            match series_name.strip_suffix(suffix) {
+                Some(family) => {
+                    let key = GroupKey { timestamp, labels };
+                    let parts = parts.entry((family.to_string(), key)).or_default();
+                    match total {
+                        Total::Sum(sum) => parts.sum = Some(sum),
+                        Total::Count(count) => parts.count = Some(count),
+                    }
+                }
+                None => add_loose(series_name, timestamp, labels, total.value()),
+            }
+        }
+    }
+
+    let mut families: IndexMap<String, IndexMap<GroupKey, T>> = IndexMap::new();
+    for ((family, key), parts) in parts {
+        if let (Some(sum), Some(count)) = (parts.sum, parts.count) {
+            let metric = T::from_parts(parts.points, sum, count);
+            families.entry(family).or_default().insert(key, metric);
+            continue;
+        }
+        for point in &parts.points {
+            let labels = with_bound(&key.labels, point);
+            add_loose(
+                format!("{family}{}", T::SUFFIX),
+                key.timestamp,
+                labels,
+                T::value(point),
+            );
+        }
+        if let Some(sum) = parts.sum {
+            add_loose(
+                format!("{family}{sum_suffix}"),
+                key.timestamp,
+                key.labels.clone(),
+                sum,
+            );
+        }
+        if let Some(count) = parts.count {
+            add_loose(
+                format!("{family}{count_suffix}"),
+                key.timestamp,
+                key.labels,
+                count as f64,
+            );
+        }
+    }
+    extend_groups(relabeled, families, kind);
+    extend_groups(relabeled, loose, GroupKind::Untyped);
+}
+
+/// Rewrite the labels of every series of the groups in place.
+pub fn rewrite_labels(groups: &mut [MetricGroup], mut rewrite: impl FnMut(&mut Labels)) {
+    rewrite_series(groups, |key| rewrite(&mut key.labels));
+}
+
+/// Rewrite the labels and timestamp of every series of the groups in place.
+pub fn rewrite_series(groups: &mut [MetricGroup], mut rewrite: impl FnMut(&mut GroupKey)) {
+    for group in groups {
+        match &mut group.metrics {
+            GroupKind::Gauge(metrics)
+            | GroupKind::Counter(metrics)
+            | GroupKind::Untyped(metrics)
+            | GroupKind::Info(metrics)
+            | GroupKind::StateSet(metrics) => rewrite_keys(metrics, &mut rewrite),
+            GroupKind::Summary(metrics) => rewrite_keys(metrics, &mut rewrite),
+            GroupKind::Histogram(metrics) | GroupKind::GaugeHistogram(metrics) => {
+                rewrite_keys(metrics, &mut rewrite)
+            }
+            GroupKind::NativeHistogram(metrics) => rewrite_keys(metrics, &mut rewrite),
+        }
+    }
+}
+
+fn rewrite_keys<T>(metrics: &mut IndexMap<GroupKey, T>, rewrite: &mut impl FnMut(&mut GroupKey)) {
+    *metrics = std::mem::take(metrics)
+        .into_iter()
+        .map(|(mut key, metric)| {
+            rewrite(&mut key);
+            (key, metric)
+        })
+        .collect();
+}
+
+/// `[a-zA-Z_][a-zA-Z0-9_]*`
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn configs(yaml: &str) -> Vec<RelabelConfig> {
+        let configs: Vec<RelabelConfig> = serde_yaml::from_str(yaml).unwrap();
+        for config in &configs {
+            config.validate().unwrap();
+        }
+        configs
+    }
+
+    #[test]
+    fn replace() {
+        let input = labels(&[("a", "foo"), ("b", "bar")]);
+        let relabeled = relabel(
+            input.clone(),
+            &configs(
+                r#"
+- source_labels: [a, b]
+  regex: "f(.*);(.*)"
+  target_label: c
+  replacement: "${1}-$2"
+"#,
+            ),
+        );
+        assert_eq!(
+            relabeled,
+            Some(labels(&[("a", "foo"), ("b", "bar"), ("c", "oo-bar")]))
+        );
+
+        // The regex is anchored, so a partial match leaves the labels alone.
+        let relabeled = relabel(
+            input.clone(),
+            &configs("- {source_labels: [a], regex: o+, target_label: c}"),
+        );
+        assert_eq!(relabeled, Some(input.clone()));
+
+        // An empty replacement removes the target label.
+        let relabeled = relabel(
+            input.clone(),
+            &configs("- {source_labels: [missing], target_label: a}"),
+        );
+        assert_eq!(relabeled, Some(labels(&[("b", "bar")])));
+
+        // The target label name can be built from the captures.
+        let relabeled = relabel(
+            input,
+            &configs(
+                "- {source_labels: [a], regex: '(.*)', target_label: 'x_$1', replacement: yes}",
+            ),
+        );
+        assert_eq!(
+            relabeled,
+            Some(labels(&[("a", "foo"), ("b", "bar"), ("x_foo", "yes")]))
+        );
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        let input = labels(&[("env", "prod"), ("team", "infra")]);
+        let keep_prod = configs("- {source_labels: [env], regex: prod, action: keep}");
+        let drop_prod = configs("- {source_labels: [env], regex: prod, action: drop}");
+        assert_eq!(relabel(input.clone(), &keep_prod), Some(input.clone()));
+        assert_eq!(relabel(input.clone(), &drop_prod), None);
+
+        let staging = labels(&[("env", "prod-staging")]);
+        assert_eq!(relabel(staging.clone(), &keep_prod), None);
+        assert_eq!(relabel(staging.clone(), &drop_prod), Some(staging));
+    }
+
+    #[test]
+    fn keepequal_and_dropequal() {
+        let equal = labels(&[("port", "9100"), ("expected", "9100")]);
+        let different = labels(&[("port", "9100"), ("expected", "8080")]);
+        let keep = configs("- {source_labels: [port], target_label: expected, action: keepequal}");
+        let drop = configs("- {source_labels: [port], target_label: expected, action: dropequal}");
+        assert_eq!(relabel(equal.clone(), &keep), Some(equal.clone()));
+        assert_eq!(relabel(different.clone(), &keep), None);
+        assert_eq!(relabel(equal, &drop), None);
+        assert_eq!(relabel(different.clone(), &drop), Some(different));
+    }
+
+    #[test]
+    fn hashmod_matches_prometheus() {
+        // Same case as Prometheus' relabel tests.
+        let config = configs(
+            "- {source_labels: [__address__], target_label: shard, modulus: 1000, action: hashmod}",
+        );
+        let relabeled = relabel(labels(&[("__address__", "baz")]), &config).unwrap();
+        assert_eq!(relabeled["shard"], "976");
+        let relabeled = relabel(labels(&[("__address__", "foo")]), &config).unwrap();
+        assert_eq!(relabeled["shard"], "696");
+    }
+
+    #[test]
+    fn labelmap() {
+        let input = labels(&[("__meta_env", "prod"), ("__meta_team", "infra"), ("a", "b")]);
+        let relabeled = relabel(input, &configs("- {regex: __meta_(.+), action: labelmap}"));
+        assert_eq!(
+            relabeled,
+            Some(labels(&[
+                ("__meta_env", "prod"),
+                ("__meta_team", "infra"),
+                ("a", "b"),
+                ("env", "prod"),
+                ("team", "infra"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn labeldrop_and_labelkeep() {
+        let input = labels(&[("a", "1"), ("ab", "2"), ("b", "3")]);
+        assert_eq!(
+            relabel(input.clone(), &configs("- {regex: a.*, action: labeldrop}")),
+            Some(labels(&[("b", "3")]))
+        );
+        assert_eq!(
+            relabel(input, &configs("- {regex: a.*, action: labelkeep}")),
+            Some(labels(&[("a", "1"), ("ab", "2")]))
+        );
+    }
+
+    #[test]
+    fn lowercase_and_uppercase() {
+        let input = labels(&[("a", "Foo"), ("b", "Bar")]);
+        let relabeled = relabel(
+            input,
+            &configs(
+                r#"
+- {source_labels: [a, b], target_label: lower, action: lowercase}
+- {source_labels: [a], target_label: upper, action: uppercase}
+"#,
+            ),
+        );
+        assert_eq!(
+            relabeled,
+            Some(labels(&[
+                ("a", "Foo"),
+                ("b", "Bar"),
+                ("lower", "foo;bar"),
+                ("upper", "FOO"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn steps_stop_once_dropped() {
+        let relabeled = relabel(
+            labels(&[("a", "1")]),
+            &configs(
+                r#"
+- {source_labels: [a], regex: "1", action: drop}
+- {source_labels: [a], target_label: b}
+"#,
+            ),
+        );
+        assert_eq!(relabeled, None);
+    }
+
+    fn group_labels(group: &MetricGroup) -> Vec<Labels> {
+        match &group.metrics {
+            GroupKind::Gauge(metrics)
+            | GroupKind::Counter(metrics)
+            | GroupKind::Untyped(metrics)
+            | GroupKind::Info(metrics)
+            | GroupKind::StateSet(metrics) => {
+                metrics.keys().map(|key| key.labels.clone()).collect()
+            }
+            GroupKind::Summary(metrics) => metrics.keys().map(|key| key.labels.clone()).collect(),
+            GroupKind::Histogram(metrics) | GroupKind::GaugeHistogram(metrics) => {
+                metrics.keys().map(|key| key.labels.clone()).collect()
+            }
+            GroupKind::NativeHistogram(metrics) => {
+                metrics.keys().map(|key| key.labels.clone()).collect()
+            }
+        }
+    }
+
+    const SCRAPE: &str = r#"
+        # TYPE go_gc_duration_seconds summary
+        go_gc_duration_seconds{quantile="0.5"} 0.01
+        go_gc_duration_seconds{quantile="1"} 0.02
+        go_gc_duration_seconds_sum 1.5
+        go_gc_duration_seconds_count 100
+        # TYPE http_request_duration_seconds histogram
+        http_request_duration_seconds_bucket{handler="/",le="0.1"} 5
+        http_request_duration_seconds_bucket{handler="/",le="+Inf"} 7
+        http_request_duration_seconds_sum{handler="/"} 1.2
+        http_request_duration_seconds_count{handler="/"} 7
+        http_request_duration_seconds_bucket{handler="/debug",le="0.1"} 1
+        http_request_duration_seconds_bucket{handler="/debug",le="+Inf"} 1
+        http_request_duration_seconds_sum{handler="/debug"} 0.01
+        http_request_duration_seconds_count{handler="/debug"} 1
+        # TYPE node_cpu_seconds_total counter
+        node_cpu_seconds_total{cpu="0",mode="idle"} 100
+        node_cpu_seconds_total{cpu="0",mode="user"} 20
+        "#;
+
+    #[test]
+    fn relabel_groups_drops_series_and_empty_groups() {
+        let groups = prometheus_parser::parse_text(SCRAPE).unwrap();
+        let relabeled = relabel_groups(
+            groups,
+            &configs(
+                r#"
+- {source_labels: [__name__], regex: "go_.*", action: drop}
+- {source_labels: [handler], regex: /debug, action: drop}
+- {source_labels: [mode], regex: user, action: drop}
+"#,
+            ),
+        );
+        let names = relabeled
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["http_request_duration_seconds", "node_cpu_seconds_total"]
+        );
+
+        // The remaining histogram keeps all of its buckets.
+        let GroupKind::Histogram(histogram) = &relabeled[0].metrics else {
+            panic!("expected a histogram: {:?}", relabeled[0]);
+        };
+        assert_eq!(histogram.len(), 1);
+        let (key, metric) = histogram.first().unwrap();
+        assert_eq!(key.labels, labels(&[("handler", "/")]));
+        assert_eq!(metric.buckets.len(), 2);
+        assert_eq!(metric.count, 7);
+        assert_eq!(
+            group_labels(&relabeled[1]),
+            [labels(&[("cpu", "0"), ("mode", "idle")])]
+        );
+    }
+
+    #[test]
+    fn relabel_groups_renames_and_rewrites_labels() {
+        let groups = prometheus_parser::parse_text(SCRAPE).unwrap();
+        let relabeled = relabel_groups(
+            groups,
+            &configs(
+                r#"
+- source_labels: [__name__, mode]
+  regex: "node_cpu_seconds_total;user"
+  target_label: __name__
+  replacement: node_cpu_user_seconds_total
+- {regex: "le|quantile", action: labeldrop}
+- {source_labels: [handler], regex: "/.*", target_label: le, replacement: "0.5"}
+- {regex: mode, action: labeldrop}
+"#,
+            ),
+        );
+        let names = relabeled
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "go_gc_duration_seconds",
+                "http_request_duration_seconds",
+                "node_cpu_seconds_total",
+                "node_cpu_user_seconds_total",
+            ]
+        );
+        assert!(matches!(relabeled[3].metrics, GroupKind::Counter(_)));
+        assert_eq!(group_labels(&relabeled[3]), [labels(&[("cpu", "0")])]);
+
+        // `le` and `quantile` belong to the buckets and survive `labeldrop`;
+        // a rule setting `le` on the whole series is ignored.
+        let GroupKind::Summary(summary) = &relabeled[0].metrics else {
+            panic!("expected a summary: {:?}", relabeled[0]);
+        };
+        assert_eq!(summary[0].quantiles.len(), 2);
+        let GroupKind::Histogram(histogram) = &relabeled[1].metrics else {
+            panic!("expected a histogram: {:?}", relabeled[1]);
+        };
+        assert_eq!(histogram.len(), 2);
+        assert!(
+            histogram
+                .iter()
+                .all(|(key, metric)| !key.labels.contains_key("le") && metric.buckets.len() == 2)
+        );
+    }
+
+    #[test]
+    fn relabel_groups_sees_the_series_of_histograms_and_summaries() {
+        let groups = prometheus_parser::parse_text(SCRAPE).unwrap();
+        let relabeled = relabel_groups(
+            groups,
+            &configs(
+                r#"
+- {source_labels: [__name__, le], regex: "http_.*_bucket;0.1", action: drop}
+- {source_labels: [__name__], regex: "go_gc_duration_seconds_count", action: drop}
+- {source_labels: [quantile], regex: "1", target_label: max, replacement: "true"}
+"#,
+            ),
+        );
+        let names = relabeled
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "go_gc_duration_seconds",
+                "go_gc_duration_seconds_sum",
+                "http_request_duration_seconds",
+                "node_cpu_seconds_total",
+            ]
+        );
+
+        // Without its `_count`, the summary is left as untyped series, and
+        // the quantile a rule labeled is still told apart by `quantile`.
+        let GroupKind::Untyped(quantiles) = &relabeled[0].metrics else {
+            panic!("expected untyped series: {:?}", relabeled[0]);
+        };
+        assert_eq!(
+            quantiles.keys().map(|key| &key.labels).collect::<Vec<_>>(),
+            [
+                &labels(&[("quantile", "0.5")]),
+                &labels(&[("max", "true"), ("quantile", "1")]),
+            ]
+        );
+        assert!(matches!(relabeled[1].metrics, GroupKind::Untyped(_)));
+
+        // Only the dropped bucket is gone from the histograms.
+        let GroupKind::Histogram(histogram) = &relabeled[2].metrics else {
+            panic!("expected a histogram: {:?}", relabeled[2]);
+        };
+        assert_eq!(histogram.len(), 2);
+        for metric in histogram.values() {
+            let bounds = metric
+                .buckets
+                .iter()
+                .map(|bucket| bucket.bucket)
+                .collect::<Vec<_>>();
+            assert_eq!(bounds, [f64::INFINITY]);
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let invalid = |yaml: &str| {
+            let config: RelabelConfig = serde_yaml::from_str(yaml).unwrap();
+            config.validate().unwrap_err()
+        };
+        assert!(invalid("{source_labels: [a]}").contains("target_label"));
+        assert!(invalid("{target_label: 1a}").contains("valid label name"));
+        assert!(invalid("{target_label: a, action: hashmod}").contains("modulus"));
+        assert!(invalid("{source_labels: [a], action: labeldrop}").contains("regex"));
+        assert!(serde_yaml::from_str::<RelabelConfig>("{regex: '(', action: keep}").is_err());
+    }
+}
@@ -1,24 +1,441 @@
-use reqwest::Client;
+use crate::config::{Protocol, QueueConfig};
+use reqwest::{Client, StatusCode, header};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_retry::strategy::jitter;
+use tracing::warn;
+
+const REMOTE_WRITE_VERSION: &str = "0.1.0";
+/// Outcome of a request that got no response.
+const NETWORK_ERROR: &str = "error";
+
+/// How failed requests are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many retries; `None` retries until delivered.
+    pub max_retries: Option<usize>,
+}
+
+impl From<&QueueConfig> for RetryPolicy {
+    fn from(queue_config: &QueueConfig) -> Self {
+        RetryPolicy {
+            min_backoff: queue_config.min_backoff,
+            max_backoff: queue_config.max_backoff,
+            max_retries: queue_config.max_retries,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WriteError {
+    #[error("destination rejected the payload with status {status}: {body}")]
+    Rejected { status: StatusCode, body: String },
+    #[error("giving up after {attempts} attempts, last error: {reason}")]
+    RetriesExhausted { attempts: usize, reason: String },
+    #[error("failed to compress payload: {0}")]
+    Compress(#[from] snap::Error),
+}
+
+/// Outcome of a single failed attempt that is worth retrying.
+struct Recoverable {
+    reason: String,
+    code: String,
+    retry_after: Option<Duration>,
+}
+
+/// What a writer has sent so far. Outcomes are keyed by status code, or
+/// `error` for requests that got no response.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteStats {
+    pub requests: BTreeMap<String, u64>,
+    pub retries: u64,
+    /// Bodies given up on, by the outcome of their last attempt.
+    pub failures: BTreeMap<String, u64>,
+}
 
 #[derive(Clone)]
 pub struct RemoteWriter {
     vm_url: String,
     client: Client,
+    protocol: Protocol,
+    retry: RetryPolicy,
+    stats: Arc<Mutex<WriteStats>>,
 }
 
 impl RemoteWriter {
-    pub fn new(vm_url: String, client: Client) -> Self {
-        RemoteWriter { vm_url, client }
-    }
-    pub async fn send(&self, text: String) -> Result<()> {
-        let res = self
-            .client
-            .post(self.vm_url.clone())
-            .body(text)
-            .header("Content-Type", "text/plain")
-            .send()
-            .await?;
-        res.error_for_status()?;
-        Ok(())
+    pub fn new(vm_url: String, client: Client, protocol: Protocol, retry: RetryPolicy) -> Self {
+        RemoteWriter {
+            vm_url,
+            client,
+            protocol,
+            retry,
+            stats: Arc::default(),
+        }
+    }
+
+    /// A writer for the same destination with new settings, sharing this
+    /// one's client and stats.
+    pub fn reconfigured(&self, vm_url: String, retry: RetryPolicy) -> Self {
+        RemoteWriter {
+            vm_url,
+            retry,
+            ..self.clone()
+        }
+    }
+
+    pub fn stats(&self) -> WriteStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Send a body produced by the destination's encoder. remote_write
+    /// bodies are serialized `WriteRequest`s and get snappy-compressed here.
+    ///
+    /// Network errors, 429 and 5xx responses are retried with jittered
+    /// exponential backoff, honoring `Retry-After` up to `max_backoff`. Any
+    /// other error status means the payload will never be accepted and is
+    /// returned right away.
+    pub async fn send(&self, body: Vec<u8>) -> Result<(), WriteError> {
+        let body = match self.protocol {
+            Protocol::Text => body,
+            Protocol::RemoteWrite => snap::raw::Encoder::new().compress_vec(&body)?,
+        };
+
+        let mut backoff = self.retry.min_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let recoverable = match self.attempt(body.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(Ok(recoverable)) => recoverable,
+                Err(Err(err)) => {
+                    if let WriteError::Rejected { status, .. } = &err {
+                        self.count_failure(status.as_str());
+                    }
+                    return Err(err);
+                }
+            };
+            if self
+                .retry
+                .max_retries
+                .is_some_and(|max_retries| attempts > max_retries)
+            {
+                self.count_failure(&recoverable.code);
+                return Err(WriteError::RetriesExhausted {
+                    attempts,
+                    reason: recoverable.reason,
+                });
+            }
+
+            // A destination asking for more than max_backoff doesn't get
+            // to stall the queue behind it any longer than that.
+            let delay = match recoverable.retry_after {
+                Some(retry_after) => retry_after.min(self.retry.max_backoff),
+                None => backoff / 2 + jitter(backoff / 2),
+            };
+            warn!(
+                url = %self.vm_url,
+                attempts,
+                ?delay,
+                "remote write failed, retrying: {}",
+                recoverable.reason
+            );
+            self.stats.lock().unwrap().retries += 1;
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+    }
+
+    async fn attempt(&self, body: Vec<u8>) -> Result<(), Result<Recoverable, WriteError>> {
+        let request = self.client.post(self.vm_url.clone()).body(body);
+        let request = match self.protocol {
+            Protocol::Text => request.header(header::CONTENT_TYPE, "text/plain"),
+            Protocol::RemoteWrite => request
+                .header(header::CONTENT_TYPE, "application/x-protobuf")
+                .header(header::CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", REMOTE_WRITE_VERSION),
+        };
+        let res = match request.send().await {
+            Ok(res) => res,
+            Err(err) => {
+                self.count_request(NETWORK_ERROR);
+                return Err(Ok(Recoverable {
+                    reason: err.to_string(),
+                    code: NETWORK_ERROR.to_string(),
+                    retry_after: None,
+                }));
+            }
+        };
+
+        let status = res.status();
+        self.count_request(status.as_str());
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = res
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = res.text().await.unwrap_or_default();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            Err(Ok(Recoverable {
+                reason: format!("status {status}: {body}"),
+                code: status.as_str().to_string(),
+                retry_after,
+            }))
+        } else {
+            Err(Err(WriteError::Rejected { status, body }))
+        }
+    }
+
+    fn count_request(&self, code: &str) {
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .requests
+            .entry(code.to_string())
+            .or_default() += 1;
+    }
+
+    fn count_failure(&self, code: &str) {
+        *self
+            .stats
+            .lock()
+            .unwrap()
+            .failures
+            .entry(code.to_string())
+            .or_default() += 1;
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus_parser::proto;
+    use prost::Message;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn writer(server: &MockServer, protocol: Protocol, max_retries: Option<usize>) -> RemoteWriter {
+        RemoteWriter::new(
+            server.uri(),
+            Client::new(),
+            protocol,
+            RetryPolicy {
+                min_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(4),
+                max_retries,
+            },
+        )
+    }
+
+    async fn requests(server: &MockServer) -> usize {
+        server.received_requests().await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_until_delivered() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let writer = writer(&server, Protocol::Text, None);
+        writer.send(b"up 1\n".to_vec()).await.unwrap();
+        assert_eq!(requests(&server).await, 3);
+        assert_eq!(
+            writer.stats(),
+            WriteStats {
+                requests: BTreeMap::from([("204".to_string(), 1), ("503".to_string(), 2)]),
+                retries: 2,
+                failures: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_too_many_requests_honoring_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        writer(&server, Protocol::Text, Some(1))
+            .send(b"up 1\n".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(requests(&server).await, 2);
+    }
+
+    #[tokio::test]
+    async fn caps_retry_after_at_max_backoff() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "86400"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let writer = writer(&server, Protocol::Text, Some(1));
+        tokio::time::timeout(Duration::from_secs(5), writer.send(b"up 1\n".to_vec()))
+            .await
+            .expect("Retry-After should be capped at max_backoff")
+            .unwrap();
+        assert_eq!(requests(&server).await, 2);
+    }
+
+    #[tokio::test]
+    async fn drops_client_errors_without_retrying() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("bad sample"))
+            .mount(&server)
+            .await;
+
+        let writer = writer(&server, Protocol::Text, None);
+        let err = writer.send(b"up 1\n".to_vec()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            WriteError::Rejected { status: StatusCode::BAD_REQUEST, ref body } if body == "bad sample"
+        ));
+        assert_eq!(requests(&server).await, 1);
+        assert_eq!(
+            writer.stats().failures,
+            BTreeMap::from([("400".to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let err = writer(&server, Protocol::Text, Some(2))
+            .send(b"up 1\n".to_vec())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            WriteError::RetriesExhausted { attempts: 3, .. }
+        ));
+        assert_eq!(requests(&server).await, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_network_errors() {
+        // Nothing listens on the port once the listener is dropped.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let writer = RemoteWriter::new(
+            url,
+            Client::new(),
+            Protocol::Text,
+            RetryPolicy {
+                min_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                max_retries: Some(1),
+            },
+        );
+
+        let err = writer.send(b"up 1\n".to_vec()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            WriteError::RetriesExhausted { attempts: 2, .. }
+        ));
+        let stats = writer.stats();
+        assert_eq!(stats.requests, BTreeMap::from([("error".to_string(), 2)]));
+        assert_eq!(stats.failures, BTreeMap::from([("error".to_string(), 1)]));
+    }
+
+    #[tokio::test]
+    async fn sends_snappy_compressed_remote_write() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("Content-Type", "application/x-protobuf"))
+            .and(header("Content-Encoding", "snappy"))
+            .and(header("X-Prometheus-Remote-Write-Version", "0.1.0"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = proto::WriteRequest {
+            timeseries: vec![proto::TimeSeries {
+                labels: vec![proto::Label {
+                    name: "__name__".into(),
+                    value: "up".into(),
+                }],
+                samples: vec![proto::Sample {
+                    value: 1.0,
+                    timestamp: 1700000000000,
+                }],
+                histograms: vec![],
+            }],
+            metadata: vec![],
+        };
+        writer(&server, Protocol::RemoteWrite, None)
+            .send(request.encode_to_vec())
+            .await
+            .unwrap();
+
+        let received = server.received_requests().await.unwrap();
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&received[0].body)
+            .unwrap();
+        assert_eq!(
+            proto::WriteRequest::decode(body.as_slice()).unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 3 "), Some(Duration::from_secs(3)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let delay = parse_retry_after(&later).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+        assert_eq!(parse_retry_after("soon"), None);
     }
 }
@@ -0,0 +1,504 @@
+use crate::config::Protocol;
+use crate::metrics_agent::MetricsMessage;
+use crate::metrics_formatter::label_value;
+use crate::series::{SeriesKey, stale_nan};
+use indexmap::IndexMap;
+use prometheus_parser::proto::{self, MetricType};
+use prometheus_parser::{
+    BucketSpan, GroupKey, GroupKind, HistogramMetric, METRIC_NAME_LABEL, MetricGroup,
+    NativeHistogramMetric, SimpleMetric, SummaryMetric,
+};
+use prost::Message;
+
+/// Encodes scraped metrics as Prometheus remote_write `WriteRequest`s.
+pub struct RemoteWriteEncoder;
+
+impl RemoteWriteEncoder {
+    /// Serialized (uncompressed) `WriteRequest` for one scrape.
+    ///
+    /// Serialized requests can be concatenated: protobuf merges repeated
+    /// fields, so the concatenation decodes as a single request holding
+    /// every series.
+    pub fn encode_message(&self, metrics_message: &MetricsMessage) -> Vec<u8> {
+        let timestamp = metrics_message.timestamp();
+        let mut request = encode_groups(&metrics_message.metrics, timestamp);
+        encode_stale_series(&mut request, &metrics_message.stale, timestamp);
+        request.encode_to_vec()
+    }
+}
+
+/// Build a `WriteRequest` for the groups. Series without a timestamp of
+/// their own get `default_timestamp`.
+pub fn encode_groups(groups: &[MetricGroup], default_timestamp: i64) -> proto::WriteRequest {
+    let mut request = proto::WriteRequest::default();
+    for group in groups {
+        let metric_type = match &group.metrics {
+            GroupKind::Gauge(metrics) => {
+                encode_simple_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Gauge
+            }
+            GroupKind::Counter(metrics) => {
+                encode_simple_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Counter
+            }
+            GroupKind::Untyped(metrics) => {
+                encode_simple_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Unknown
+            }
+            GroupKind::Summary(metrics) => {
+                encode_summary_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Summary
+            }
+            GroupKind::Histogram(metrics) => {
+                encode_histogram_metric(&mut request, group, metrics, default_timestamp);
+                MetricType::Histogram
+            }
+            GroupKind::GaugeHistogram(metrics) => {
+                encode_histogram_metric(&mut request, group, metrics, default_timestamp);
+                MetricType::Gaugehistogram
+            }
+            GroupKind::Info(metrics) => {
+                encode_simple_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Info
+            }
+            GroupKind::StateSet(metrics) => {
+                encode_simple_metric(&mut request, &group.name, metrics, default_timestamp);
+                MetricType::Stateset
+            }
+            GroupKind::NativeHistogram(metrics) => {
+                encode_native_histogram_metric(
+                    &mut request,
+                    &group.name,
+                    metrics,
+                    default_timestamp,
+                );
+                MetricType::Histogram
+            }
+        };
+        request.metadata.push(proto::MetricMetadata {
+            r#type: metric_type as i32,
+            metric_family_name: group.name.clone(),
+            help: group.help.clone().unwrap_or_default(),
+            unit: group.unit.clone().unwrap_or_default(),
+        });
+    }
+    request
+}
+
+/// Add a staleness marker sample for each of the series.
+pub fn encode_stale_series(
+    request: &mut proto::WriteRequest,
+    series: &[SeriesKey],
+    timestamp: i64,
+) {
+    let series = series.iter();
+    for series in series.filter(|series| series.written_with(Protocol::RemoteWrite)) {
+        let key = GroupKey {
+            timestamp: None,
+            labels: series.labels.clone(),
+        };
+        request.timeseries.push(time_series(
+            &series.name,
+            &key,
+            None,
+            stale_nan(),
+            timestamp,
+        ));
+    }
+}
+
+fn encode_simple_metric(
+    request: &mut proto::WriteRequest,
+    group_name: &str,
+    metrics: &IndexMap<GroupKey, SimpleMetric>,
+    default_timestamp: i64,
+) {
+    for (key, metric) in metrics {
+        let timestamp = key.timestamp.unwrap_or(default_timestamp);
+        request
+            .timeseries
+            .push(time_series(group_name, key, None, metric.value, timestamp));
+    }
+}
+
+fn encode_summary_metric(
+    request: &mut proto::WriteRequest,
+    group_name: &str,
+    metrics: &IndexMap<GroupKey, SummaryMetric>,
+    default_timestamp: i64,
+) {
+    for (key, metric) in metrics {
+        let timestamp = key.timestamp.unwrap_or(default_timestamp);
+        for quantile in &metric.quantiles {
+            request.timeseries.push(time_series(
+                group_name,
+                key,
+                Some(("quantile", label_value(quantile.quantile))),
+                quantile.value,
+                timestamp,
+            ));
+        }
+        request.timeseries.push(time_series(
+            &format!("{group_name}_sum"),
+            key,
+            None,
+            metric.sum,
+            timestamp,
+        ));
+        request.timeseries.push(time_series(
+            &format!("{group_name}_count"),
+            key,
+            None,
+            metric.count as f64,
+            timestamp,
+        ));
+    }
+}
+
+fn encode_histogram_metric(
+    request: &mut proto::WriteRequest,
+    group: &MetricGroup,
+    metrics: &IndexMap<GroupKey, HistogramMetric>,
+    default_timestamp: i64,
+) {
+    let group_name = &group.name;
+    let (sum_suffix, count_suffix) = group.metrics.sum_and_count_suffixes();
+    for (key, metric) in metrics {
+        let timestamp = key.timestamp.unwrap_or(default_timestamp);
+        let bucket_name = format!("{group_name}_bucket");
+        for bucket in &metric.buckets {
+            request.timeseries.push(time_series(
+                &bucket_name,
+                key,
+                Some(("le", label_value(bucket.bucket))),
+                bucket.count as f64,
+                timestamp,
+            ));
+        }
+        request.timeseries.push(time_series(
+            &format!("{group_name}{sum_suffix}"),
+            key,
+            None,
+            metric.sum,
+            timestamp,
+        ));
+        request.timeseries.push(time_series(
+            &format!("{group_name}{count_suffix}"),
+            key,
+            None,
+            metric.count as f64,
+            timestamp,
+        ));
+    }
+}
+
+fn encode_native_histogram_metric(
+    request: &mut proto::WriteRequest,
+    group_name: &str,
+    metrics: &IndexMap<GroupKey, NativeHistogramMetric>,
+    default_timestamp: i64,
+) {
+    for (key, metric) in metrics {
+        let spans = |spans: &[BucketSpan]| {
+            spans
+                .iter()
+                .map(|span| proto::BucketSpan {
+                    offset: span.offset,
+                    length: span.length,
+                })
+                .collect()
+        };
+        let histogram = proto::Histogram {
+            count: Some(proto::histogram::Count::CountInt(metric.count)),
+            sum: metric.sum,
+            schema: metric.schema,
+            zero_threshold: metric.zero_threshold,
+            zero_count: Some(proto::histogram::ZeroCount::ZeroCountInt(metric.zero_count)),
+            negative_spans: spans(&metric.negative_spans),
+            negative_deltas: metric.negative_deltas.clone(),
+            negative_counts: Vec::new(),
+            positive_spans: spans(&metric.positive_spans),
+            positive_deltas: metric.positive_deltas.clone(),
+            positive_counts: Vec::new(),
+            reset_hint: proto::histogram::ResetHint::Unknown as i32,
+            timestamp: key.timestamp.unwrap_or(default_timestamp),
+        };
+        request.timeseries.push(proto::TimeSeries {
+            labels: series_labels(group_name, key, None),
+            samples: Vec::new(),
+            histograms: vec![histogram],
+        });
+    }
+}
+
+/// A single-sample series.
+fn time_series(
+    name: &str,
+    key: &GroupKey,
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    timestamp: i64,
+) -> proto::TimeSeries {
+    proto::TimeSeries {
+        labels: series_labels(name, key, extra_label),
+        samples: vec![proto::Sample { value, timestamp }],
+        histograms: Vec::new(),
+    }
+}
+
+/// Labels of a series, sorted by name as receivers expect.
+fn series_labels(
+    name: &str,
+    key: &GroupKey,
+    extra_label: Option<(&str, String)>,
+) -> Vec<proto::Label> {
+    let mut labels = Vec::with_capacity(key.labels.len() + 2);
+    labels.push(proto::Label {
+        name: METRIC_NAME_LABEL.to_string(),
+        value: name.to_string(),
+    });
+    labels.extend(key.labels.iter().map(|(name, value)| proto::Label {
+        name: name.clone(),
+        value: value.clone(),
+    }));
+    if let Some((name, value)) = extra_label {
+        labels.push(proto::Label {
+            name: name.to_string(),
+            value,
+        });
+    }
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus_parser::{MetadataConflictStrategy, parse_request, parse_text};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const INPUT: &str = r#"
+        # HELP http_requests_total The total number of HTTP requests.
+        # TYPE http_requests_total counter
+        http_requests_total{method="post",code="200"} 1027 1395066363000
+        http_requests_total{method="post",code="400"} 3 1395066363000
+        # TYPE temperature gauge
+        temperature{Zone="a"} 21.5
+        # TYPE http_request_duration_seconds histogram
+        http_request_duration_seconds_bucket{le="0.05"} 24054
+        http_request_duration_seconds_bucket{le="+Inf"} 144320
+        http_request_duration_seconds_sum 53423
+        http_request_duration_seconds_count 144320
+        # TYPE rpc_duration_seconds summary
+        rpc_duration_seconds{quantile="0.5"} 4773
+        rpc_duration_seconds{quantile="0.99"} 76656
+        rpc_duration_seconds_sum 1.7560473e+07
+        rpc_duration_seconds_count 2693
+        "#;
+
+    fn native_histogram() -> MetricGroup {
+        let key = GroupKey {
+            timestamp: None,
+            labels: [("job".to_string(), "api".to_string())].into(),
+        };
+        let metric = NativeHistogramMetric {
+            schema: 3,
+            zero_threshold: 0.001,
+            zero_count: 1,
+            count: 4,
+            sum: 2.5,
+            positive_spans: vec![BucketSpan {
+                offset: -2,
+                length: 2,
+            }],
+            positive_deltas: vec![1, 1],
+            ..Default::default()
+        };
+        MetricGroup {
+            name: "request_duration_seconds".to_string(),
+            metrics: GroupKind::NativeHistogram([(key, metric)].into_iter().collect()),
+            help: None,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn encode_native_histograms() {
+        let request = encode_groups(&[native_histogram()], 1700000000000);
+        assert_eq!(request.metadata[0].r#type, MetricType::Histogram as i32);
+        assert_eq!(request.timeseries.len(), 1);
+        let series = &request.timeseries[0];
+        assert_eq!(series.labels[0].value, "request_duration_seconds");
+        assert!(series.samples.is_empty());
+        assert_eq!(
+            series.histograms,
+            [proto::Histogram {
+                count: Some(proto::histogram::Count::CountInt(4)),
+                sum: 2.5,
+                schema: 3,
+                zero_threshold: 0.001,
+                zero_count: Some(proto::histogram::ZeroCount::ZeroCountInt(1)),
+                positive_spans: vec![proto::BucketSpan {
+                    offset: -2,
+                    length: 2
+                }],
+                positive_deltas: vec![1, 1],
+                timestamp: 1700000000000,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn encode_series_and_metadata() {
+        let groups = parse_text(INPUT).unwrap();
+        let request = encode_groups(&groups, 1700000000000);
+
+        let metadata = request
+            .metadata
+            .iter()
+            .map(|metadata| {
+                (
+                    metadata.metric_family_name.as_str(),
+                    MetricType::try_from(metadata.r#type).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            metadata,
+            [
+                ("http_requests_total", MetricType::Counter),
+                ("temperature", MetricType::Gauge),
+                ("http_request_duration_seconds", MetricType::Histogram),
+                ("rpc_duration_seconds", MetricType::Summary),
+            ]
+        );
+        assert_eq!(
+            request.metadata[0].help,
+            "The total number of HTTP requests."
+        );
+        assert!(request.metadata[1].help.is_empty());
+        assert_eq!(request.timeseries.len(), 2 + 1 + 4 + 4);
+
+        let counter = &request.timeseries[0];
+        let labels = counter
+            .labels
+            .iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                ("__name__", "http_requests_total"),
+                ("code", "200"),
+                ("method", "post"),
+            ]
+        );
+        assert_eq!(
+            counter.samples,
+            [proto::Sample {
+                value: 1027.0,
+                timestamp: 1395066363000
+            }]
+        );
+
+        // Label names are sorted byte-wise, so upper case sorts first.
+        let gauge = &request.timeseries[2];
+        assert_eq!(gauge.labels[0].name, "Zone");
+        assert_eq!(gauge.labels[1].name, "__name__");
+        assert_eq!(gauge.samples[0].timestamp, 1700000000000);
+
+        let inf_bucket = &request.timeseries[4];
+        assert!(
+            inf_bucket
+                .labels
+                .iter()
+                .any(|label| label.name == "le" && label.value == "+Inf")
+        );
+    }
+
+    #[test]
+    fn encode_round_trips_through_parse_request() {
+        let groups = parse_text(INPUT).unwrap();
+        let request = encode_groups(&groups, 1700000000000);
+        let parsed = parse_request(request, MetadataConflictStrategy::Reject).unwrap();
+        assert_eq!(parsed.len(), groups.len());
+        for (parsed, original) in parsed.iter().zip(&groups) {
+            assert_eq!(parsed.name, original.name);
+            assert_eq!(parsed.help, original.help);
+            match (&parsed.metrics, &original.metrics) {
+                (GroupKind::Counter(a), GroupKind::Counter(b))
+                | (GroupKind::Gauge(a), GroupKind::Gauge(b)) => {
+                    assert_eq!(
+                        a.values().collect::<Vec<_>>(),
+                        b.values().collect::<Vec<_>>()
+                    );
+                }
+                (GroupKind::Histogram(a), GroupKind::Histogram(b)) => {
+                    assert_eq!(
+                        a.values().collect::<Vec<_>>(),
+                        b.values().collect::<Vec<_>>()
+                    );
+                }
+                (GroupKind::Summary(a), GroupKind::Summary(b)) => {
+                    assert_eq!(
+                        a.values().collect::<Vec<_>>(),
+                        b.values().collect::<Vec<_>>()
+                    );
+                }
+                (parsed, original) => panic!("kind mismatch: {parsed:?} != {original:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn encode_stale_markers_keep_their_bits() {
+        let mut request = proto::WriteRequest::default();
+        let series = SeriesKey::new(
+            "up".to_string(),
+            [("job".to_string(), "node".to_string())].into(),
+        );
+        encode_stale_series(&mut request, &[series], 1700000000000);
+
+        let decoded = proto::WriteRequest::decode(request.encode_to_vec().as_slice()).unwrap();
+        let sample = &decoded.timeseries[0].samples[0];
+        assert_eq!(sample.value.to_bits(), crate::series::STALE_NAN_BITS);
+        assert_eq!(sample.timestamp, 1700000000000);
+        assert_eq!(decoded.timeseries[0].labels[0].value, "up");
+        assert_eq!(decoded.timeseries[0].labels[1].value, "node");
+    }
+
+    #[test]
+    fn encode_message_stamps_samples_with_the_scrape_time() {
+        let message = MetricsMessage {
+            target_url: "http://127.0.0.1:9100/metrics".to_string(),
+            metrics: parse_text("a 1\nb 2 500\n").unwrap(),
+            stale: vec![SeriesKey::new("c".to_string(), Default::default())],
+            scraped_at: UNIX_EPOCH + Duration::from_millis(1700000000000),
+        };
+        let body = RemoteWriteEncoder.encode_message(&message);
+        let decoded = proto::WriteRequest::decode(body.as_slice()).unwrap();
+        let timestamps = decoded
+            .timeseries
+            .iter()
+            .map(|series| series.samples[0].timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [1700000000000, 500, 1700000000000]);
+    }
+
+    #[test]
+    fn concatenated_requests_decode_as_one() {
+        let groups = parse_text(INPUT).unwrap();
+        let first = encode_groups(&groups[..1], 0);
+        let second = encode_groups(&groups[1..], 0);
+        let mut body = first.encode_to_vec();
+        body.extend(second.encode_to_vec());
+
+        let decoded = proto::WriteRequest::decode(body.as_slice()).unwrap();
+        assert_eq!(
+            decoded.timeseries.len(),
+            first.timeseries.len() + second.timeseries.len()
+        );
+        assert_eq!(decoded.metadata.len(), groups.len());
+    }
+}
@@ -0,0 +1,58 @@
+//! Synthetic series describing every scrape attempt, named and labeled the
+//! way Prometheus writes them, so dashboards and alerts on `up` and
+//! `scrape_*` keep working.
+
+use crate::relabel::Labels;
+use indexmap::IndexMap;
+use prometheus_parser::{GroupKey, GroupKind, MetricGroup, SimpleMetric};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrapeHealth {
+    pub up: bool,
+    pub duration: Duration,
+    /// Samples exposed by the target.
+    pub samples_scraped: usize,
+    /// Samples left after `metric_relabel_configs`.
+    pub samples_post_metric_relabeling: usize,
+    /// Series the previous scrape of the target didn't have.
+    pub series_added: usize,
+}
+
+impl ScrapeHealth {
+    /// A failed scrape: `up` is 0 and nothing was scraped.
+    pub fn failed(duration: Duration) -> Self {
+        ScrapeHealth {
+            duration,
+            ..ScrapeHealth::default()
+        }
+    }
+
+    /// One gauge group per series, labeled with the target labels.
+    pub fn groups(&self, target_labels: &Labels) -> Vec<MetricGroup> {
+        [
+            ("up", if self.up { 1.0 } else { 0.0 }),
+            ("scrape_duration_seconds", self.duration.as_secs_f64()),
+            ("scrape_samples_scraped", self.samples_scraped as f64),
+            (
+                "scrape_samples_post_metric_relabeling",
+                self.samples_post_metric_relabeling as f64,
+            ),
+            ("scrape_series_added", self.series_added as f64),
+        ]
+        .into_iter()
+        .map(|(name, value)| {
+            let key = GroupKey {
+                timestamp: None,
+                labels: target_labels.clone(),
+            };
+            MetricGroup {
+                name: name.to_string(),
+                metrics: GroupKind::Gauge(IndexMap::from([(key, SimpleMetric { value })])),
+                help: None,
+                unit: None,
+            }
+        })
+        .collect()
+    }
+}
@@ -0,0 +1,638 @@
+//! Runs one independent scrape loop per target.
+//!
+//! Every loop ticks on its own job's interval and feeds the shared
+//! formatter/writer pipeline through a single channel. Loops remember the
+//! series of their target's last scrape so that series which disappear,
+//! and every series of a target that fails or is removed, are marked stale.
+
+use crate::metrics_agent::{MetricsMessage, sample_count};
+use crate::relabel::Labels;
+use crate::scrape_health::ScrapeHealth;
+use crate::scraper::TargetScraper;
+use crate::series::{SeriesCache, series_keys};
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+/// Identifies a target across [`ScrapeManager::sync`] calls: its scrape
+/// URL and target labels.
+pub type TargetKey = (String, Labels);
+
+fn target_key(scraper: &TargetScraper) -> TargetKey {
+    (scraper.url.clone(), scraper.labels.clone())
+}
+
+/// What is known about a target, kept for as long as it is scraped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TargetStatus {
+    pub job_name: String,
+    pub discovered_labels: Labels,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub attempts: u64,
+    pub failures: u64,
+    /// Time spent on all attempts together.
+    pub total_duration: Duration,
+    /// Start of the last scrape, `None` before the first one ended.
+    pub last_scrape: Option<SystemTime>,
+    pub last_duration: Duration,
+    /// Why the last scrape failed.
+    pub last_error: Option<String>,
+}
+
+impl TargetStatus {
+    /// Take on the settings of the loop scraping the target. The counts
+    /// carry over when a loop is restarted with new settings.
+    fn configure(&mut self, scraper: &TargetScraper, interval: Duration) {
+        self.job_name = scraper.job_name.clone();
+        self.discovered_labels = scraper.discovered_labels.clone();
+        self.interval = interval;
+        self.timeout = scraper.timeout;
+    }
+
+    /// `up` or `down` after the last scrape, `unknown` before the first.
+    pub fn health(&self) -> &'static str {
+        match (&self.last_scrape, &self.last_error) {
+            (None, _) => "unknown",
+            (Some(_), None) => "up",
+            (Some(_), Some(_)) => "down",
+        }
+    }
+}
+
+/// Status of every running scrape loop, shared with the agent's HTTP server.
+#[derive(Clone, Default)]
+pub struct TargetStatuses(Arc<Mutex<HashMap<TargetKey, TargetStatus>>>);
+
+impl TargetStatuses {
+    /// Every target's status, ordered by target.
+    pub fn snapshot(&self) -> Vec<(TargetKey, TargetStatus)> {
+        let mut statuses = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, status)| (key.clone(), status.clone()))
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+
+    /// Update the status of a target, unless its loop has been removed.
+    fn update(&self, key: &TargetKey, update: impl FnOnce(&mut TargetStatus)) {
+        if let Some(status) = self.0.lock().unwrap().get_mut(key) {
+            update(status);
+        }
+    }
+}
+
+/// A running scrape loop and what it was started with.
+struct ScrapeLoop {
+    /// Dropping the sender stops the loop and marks its series stale;
+    /// sending on it stops the loop quietly.
+    stop: oneshot::Sender<()>,
+    scraper: Arc<TargetScraper>,
+    interval: Duration,
+}
+
+pub struct ScrapeManager {
+    tx: mpsc::Sender<MetricsMessage>,
+    loops: JoinSet<()>,
+    running: HashMap<TargetKey, ScrapeLoop>,
+    statuses: TargetStatuses,
+}
+
+impl ScrapeManager {
+    pub fn new(tx: mpsc::Sender<MetricsMessage>) -> Self {
+        ScrapeManager {
+            tx,
+            loops: JoinSet::new(),
+            running: HashMap::new(),
+            statuses: TargetStatuses::default(),
+        }
+    }
+
+    pub fn statuses(&self) -> TargetStatuses {
+        self.statuses.clone()
+    }
+
+    /// Scrape exactly the given targets, each every `interval`: start loops
+    /// for new targets, restart the loops of targets whose settings changed
+    /// and stop the loops of targets that are gone.
+    pub fn sync(&mut self, scrapers: Vec<(TargetScraper, Duration)>) {
+        let mut wanted = HashSet::new();
+        for (scraper, interval) in scrapers {
+            let key = target_key(&scraper);
+            // A target listed twice is scraped once.
+            if wanted.contains(&key) {
+                continue;
+            }
+            let unchanged = self.running.get(&key).is_some_and(|running| {
+                running.interval == interval && running.scraper.same_settings(&scraper)
+            });
+            if !unchanged {
+                // The target is still there, so its series aren't marked
+                // stale.
+                if let Some(running) = self.running.remove(&key) {
+                    info!(target_url = %key.0, "restarting scrape loop with new settings");
+                    let _ = running.stop.send(());
+                }
+                self.start(key.clone(), scraper, interval);
+            }
+            wanted.insert(key);
+        }
+        let mut statuses = self.statuses.0.lock().unwrap();
+        self.running.retain(|key, _| {
+            let keep = wanted.contains(key);
+            if !keep {
+                info!(target_url = %key.0, "stopping scrape loop of removed target");
+                statuses.remove(key);
+            }
+            keep
+        });
+    }
+
+    fn start(&mut self, key: TargetKey, scraper: TargetScraper, interval: Duration) {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let scraper = Arc::new(scraper);
+        self.statuses
+            .0
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .configure(&scraper, interval);
+        self.loops.spawn(scrape_loop(
+            Arc::clone(&scraper),
+            interval,
+            self.tx.clone(),
+            self.statuses.clone(),
+            stop_rx,
+        ));
+        let running = ScrapeLoop {
+            stop: stop_tx,
+            scraper,
+            interval,
+        };
+        self.running.insert(key, running);
+    }
+
+    /// Stop every scrape loop and wait for them to finish. Series aren't
+    /// marked stale: the targets are still there, and will be scraped again
+    /// once the agent is back.
+    pub async fn shutdown(mut self) {
+        for (_, running) in self.running.drain() {
+            let _ = running.stop.send(());
+        }
+        self.join().await;
+    }
+
+    /// Wait for every scrape loop to finish. Loops only stop once the
+    /// pipeline behind them has shut down.
+    pub async fn join(self) {
+        let ScrapeManager {
+            tx,
+            mut loops,
+            running,
+            ..
+        } = self;
+        drop(tx);
+        while let Some(result) = loops.join_next().await {
+            if let Err(err) = result {
+                warn!("scrape loop panicked: {err}");
+            }
+        }
+        drop(running);
+    }
+}
+
+async fn scrape_loop(
+    scraper: Arc<TargetScraper>,
+    interval: Duration,
+    tx: mpsc::Sender<MetricsMessage>,
+    statuses: TargetStatuses,
+    mut stop: oneshot::Receiver<()>,
+) {
+    // Ticks are scheduled against a fixed start so a slow scrape doesn't push
+    // every following one back; a tick missed entirely is skipped.
+    let offset = scrape_offset(&scraper.url, interval);
+    let start = time::Instant::now() + offset;
+    let mut ticker = time::interval_at(start, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    debug!(target_url = %scraper.url, ?interval, ?offset, "starting scrape loop");
+
+    let key = target_key(&scraper);
+    let mut series = SeriesCache::default();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            stopped = &mut stop => return stop_loop(stopped, &scraper, &mut series, &tx).await,
+        }
+        let scraped_at = SystemTime::now();
+        let started = Instant::now();
+        // A stop doesn't wait for a scrape that may still be retrying.
+        let result = tokio::select! {
+            result = scraper.scrape() => result,
+            stopped = &mut stop => return stop_loop(stopped, &scraper, &mut series, &tx).await,
+        };
+        let duration = started.elapsed();
+        statuses.update(&key, |status| {
+            status.attempts += 1;
+            status.failures += u64::from(result.is_err());
+            status.total_duration += duration;
+            status.last_scrape = Some(scraped_at);
+            status.last_duration = duration;
+            status.last_error = result.as_ref().err().map(|err| format!("{err:#}"));
+        });
+        // Every attempt reports its health, a failed one with `up 0`.
+        let (mut metrics, stale, health) = match result {
+            Ok(scrape) => {
+                let changes = series.update(&scrape.metrics);
+                let health = ScrapeHealth {
+                    up: true,
+                    duration,
+                    samples_scraped: scrape.samples_scraped,
+                    samples_post_metric_relabeling: sample_count(&scrape.metrics),
+                    series_added: changes.added,
+                };
+                (scrape.metrics, changes.vanished, health)
+            }
+            Err(err) => {
+                warn!(target_url = %scraper.url, "scrape failed: {err:#}");
+                (Vec::new(), series.take(), ScrapeHealth::failed(duration))
+            }
+        };
+        metrics.extend(health.groups(&scraper.labels));
+        let message = MetricsMessage {
+            target_url: scraper.url.clone(),
+            metrics,
+            stale,
+            scraped_at,
+        };
+        if tx.send(message).await.is_err() {
+            debug!(target_url = %scraper.url, "pipeline closed, stopping scrape loop");
+            return;
+        }
+    }
+}
+
+/// End a scrape loop. A stop sent on purpose ends it quietly; a dropped
+/// sender means the target is gone, so all of its series, the health series
+/// included, are marked stale.
+async fn stop_loop(
+    stopped: Result<(), oneshot::error::RecvError>,
+    scraper: &TargetScraper,
+    series: &mut SeriesCache,
+    tx: &mpsc::Sender<MetricsMessage>,
+) {
+    if stopped.is_ok() {
+        debug!(target_url = %scraper.url, "stopping scrape loop");
+        return;
+    }
+    let mut stale = series.take();
+    stale.extend(series_keys(
+        &ScrapeHealth::default().groups(&scraper.labels),
+    ));
+    let message = MetricsMessage {
+        target_url: scraper.url.clone(),
+        metrics: Vec::new(),
+        stale,
+        scraped_at: SystemTime::now(),
+    };
+    if tx.send(message).await.is_err() {
+        debug!(target_url = %scraper.url, "pipeline closed, dropping staleness markers");
+    }
+}
+
+/// Spread targets over the interval so they aren't all scraped at once,
+/// while keeping each target's phase the same from tick to tick.
+fn scrape_offset(url: &str, interval: Duration) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    let interval_nanos = interval.as_nanos().max(1);
+    Duration::from_nanos((hasher.finish() as u128 % interval_nanos) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics_formatter::{MetricsFormatter, TextDocument};
+    use crate::relabel::Labels;
+    use reqwest::Client;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn scraper(url: String) -> TargetScraper {
+        TargetScraper {
+            job_name: "node".to_string(),
+            url,
+            labels: Labels::from([("job".to_string(), "node".to_string())]),
+            discovered_labels: Labels::new(),
+            client: Client::new(),
+            timeout: Duration::from_secs(1),
+            max_retries: 0,
+            honor_labels: false,
+            honor_timestamps: true,
+            metric_relabel_configs: Vec::new(),
+        }
+    }
+
+    /// Sample lines of the formatted message, without the scrape time
+    /// every one of them ends with.
+    fn lines(message: &MetricsMessage) -> Vec<String> {
+        let mut document = TextDocument::default();
+        MetricsFormatter.format_into(&mut document, message);
+        let timestamp = format!(" {}", message.timestamp());
+        document
+            .finish()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                line.strip_suffix(&timestamp)
+                    .expect("stamped with the scrape time")
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Lines of the first message of a loop scraping `url`.
+    async fn first_scrape(url: String) -> Vec<String> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        manager.sync(vec![(scraper(url), Duration::from_millis(10))]);
+        let message = rx.recv().await.unwrap();
+        drop(rx);
+        manager.join().await;
+        lines(&message)
+    }
+
+    fn stale_names(message: &MetricsMessage) -> Vec<&str> {
+        let mut names = message
+            .stale
+            .iter()
+            .map(|series| series.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn reports_health_of_successful_scrapes() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\nb{x=\"1\"} 2\n"))
+            .mount(&server)
+            .await;
+
+        let lines = first_scrape(format!("{}/metrics", server.uri())).await;
+        assert_eq!(
+            lines[..3],
+            [
+                "a{job=\"node\"} 1",
+                "b{job=\"node\",x=\"1\"} 2",
+                "up{job=\"node\"} 1"
+            ]
+        );
+        assert!(lines[3].starts_with("scrape_duration_seconds{job=\"node\"} "));
+        assert_eq!(
+            lines[4..],
+            [
+                "scrape_samples_scraped{job=\"node\"} 2",
+                "scrape_samples_post_metric_relabeling{job=\"node\"} 2",
+                "scrape_series_added{job=\"node\"} 2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn marks_vanished_series_and_removed_targets_stale() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\nb 1\n"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 2\n"))
+            .mount(&server)
+            .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        let target = || scraper(format!("{}/metrics", server.uri()));
+        manager.sync(vec![(target(), Duration::from_millis(10))]);
+
+        let first = rx.recv().await.unwrap();
+        assert!(first.stale.is_empty());
+        let second = rx.recv().await.unwrap();
+        assert_eq!(stale_names(&second), ["b"]);
+        assert_eq!(second.stale[0].labels["job"], "node");
+
+        // Syncing the same target keeps its loop running.
+        manager.sync(vec![(target(), Duration::from_millis(10))]);
+        assert_eq!(manager.running.len(), 1);
+
+        manager.sync(Vec::new());
+        let removed = loop {
+            let message = rx.recv().await.unwrap();
+            if message.metrics.is_empty() {
+                break message;
+            }
+        };
+        assert_eq!(
+            stale_names(&removed),
+            [
+                "a",
+                "scrape_duration_seconds",
+                "scrape_samples_post_metric_relabeling",
+                "scrape_samples_scraped",
+                "scrape_series_added",
+                "up",
+            ]
+        );
+        manager.join().await;
+    }
+
+    #[tokio::test]
+    async fn restarts_loops_whose_settings_changed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\n"))
+            .mount(&server)
+            .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        let statuses = manager.statuses();
+        let target = || scraper(format!("{}/metrics", server.uri()));
+        let key = target_key(&target());
+        let started = |manager: &ScrapeManager| Arc::as_ptr(&manager.running[&key].scraper);
+        manager.sync(vec![(target(), Duration::from_millis(10))]);
+        rx.recv().await.unwrap();
+        let first = started(&manager);
+
+        manager.sync(vec![(target(), Duration::from_millis(10))]);
+        assert_eq!(started(&manager), first);
+
+        let mut slower = target();
+        slower.timeout = Duration::from_secs(2);
+        manager.sync(vec![(slower, Duration::from_millis(10))]);
+        assert_ne!(started(&manager), first);
+        let status = &statuses.snapshot()[0].1;
+        assert_eq!(status.timeout, Duration::from_secs(2));
+        assert!(status.attempts >= 1);
+
+        // The restarted target keeps its series.
+        for _ in 0..3 {
+            let message = rx.recv().await.unwrap();
+            assert!(message.stale.is_empty());
+            assert!(!message.metrics.is_empty());
+        }
+        drop(rx);
+        manager.join().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_loops_without_staleness_markers() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\n"))
+            .mount(&server)
+            .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        manager.sync(vec![(
+            scraper(format!("{}/metrics", server.uri())),
+            Duration::from_millis(10),
+        )]);
+        rx.recv().await.unwrap();
+        let shutdown = tokio::spawn(manager.shutdown());
+
+        // The channel closes once the loop is gone.
+        while let Some(message) = rx.recv().await {
+            assert!(message.stale.is_empty());
+            assert!(!message.metrics.is_empty());
+        }
+        shutdown.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_does_not_wait_for_a_slow_scrape() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+            .mount(&server)
+            .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        let mut slow = scraper(format!("{}/metrics", server.uri()));
+        slow.timeout = Duration::from_secs(30);
+        manager.sync(vec![(slow, Duration::from_millis(10))]);
+        // Let the loop start its scrape.
+        time::sleep(Duration::from_millis(100)).await;
+
+        time::timeout(Duration::from_secs(5), manager.shutdown())
+            .await
+            .unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_failed_scrapes_as_down() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("not a metric line\n"))
+            .mount(&server)
+            .await;
+
+        let health = first_scrape(format!("{}/metrics", server.uri())).await;
+        assert_eq!(health.len(), 5);
+        assert_eq!(health[0], "up{job=\"node\"} 0");
+        assert_eq!(health[2], "scrape_samples_scraped{job=\"node\"} 0");
+
+        // A target that never answers is down as soon as its scrape times
+        // out, however many retries the job allows.
+        let hanging = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(60)))
+            .mount(&hanging)
+            .await;
+        let interval = Duration::from_secs(1);
+        let scraper = TargetScraper {
+            timeout: Duration::from_millis(200),
+            max_retries: 10,
+            ..scraper(format!("{}/metrics", hanging.uri()))
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        manager.sync(vec![(scraper, interval)]);
+        let message = rx.recv().await.unwrap();
+        assert!(message.scraped_at.elapsed().unwrap() < interval);
+        let health = lines(&message);
+        assert_eq!(health[0], "up{job=\"node\"} 0");
+        let duration = health[1]
+            .strip_prefix("scrape_duration_seconds{job=\"node\"} ")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap();
+        assert!(duration < 0.5, "{duration}");
+        drop(rx);
+        manager.join().await;
+    }
+
+    #[tokio::test]
+    async fn tracks_the_status_of_running_targets() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        let statuses = manager.statuses();
+        let target = scraper(format!("{}/metrics", server.uri()));
+        let key = target_key(&target);
+        manager.sync(vec![(target, Duration::from_millis(10))]);
+        rx.recv().await.unwrap();
+
+        let snapshot = statuses.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].0, key);
+        let status = &snapshot[0].1;
+        assert_eq!(status.job_name, "node");
+        assert!(status.attempts >= 1);
+        assert!(status.failures >= 1);
+        assert!(status.last_scrape.is_some());
+        assert!(status.last_error.as_ref().unwrap().contains("503"));
+        assert_eq!(status.health(), "down");
+
+        manager.sync(Vec::new());
+        assert!(statuses.snapshot().is_empty());
+        drop(rx);
+        manager.join().await;
+    }
+
+    #[test]
+    fn offset_is_stable_and_within_interval() {
+        let interval = Duration::from_secs(15);
+        let offset = scrape_offset("http://127.0.0.1:9100/metrics", interval);
+        assert!(offset < interval);
+        assert_eq!(
+            offset,
+            scrape_offset("http://127.0.0.1:9100/metrics", interval)
+        );
+        assert_eq!(
+            scrape_offset("http://127.0.0.1:9100/metrics", Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+}
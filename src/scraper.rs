@@ -1,42 +1,289 @@
-use anyhow::Result;
+use crate::config::{GlobalConfig, ScrapeConfig};
+use crate::metrics_agent::sample_count;
+use crate::relabel::{Labels, RelabelConfig, relabel_groups, rewrite_labels, rewrite_series};
+use crate::target::{Target, merge_target_labels};
+use anyhow::{Result, anyhow};
 use std::time::Duration;
 use tokio_retry::{Retry, strategy::ExponentialBackoff};
 
-use prometheus_parser::{MetricGroup, parse_text};
-use reqwest::Client;
+use prometheus_parser::{MetricGroup, parse_openmetrics, parse_protobuf, parse_text};
+use reqwest::{Client, header};
+
+/// Exposition formats we can parse, most preferred first. Compression is
+/// negotiated by the client, which asks for gzip and decompresses
+/// responses transparently.
+const ACCEPT: &str = "application/openmetrics-text;version=1.0.0;q=0.6,\
+                      application/openmetrics-text;version=0.0.1;q=0.5,\
+                      application/vnd.google.protobuf;\
+                      proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.4,\
+                      text/plain;version=0.0.4;q=0.3,\
+                      */*;q=0.1";
+
+/// Exposition format of a scrape response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrapeFormat {
+    OpenMetrics,
+    Protobuf,
+    Text,
+}
+
+impl ScrapeFormat {
+    /// Format announced by a `Content-Type`. Anything we don't recognize,
+    /// including a missing header, is read as the text format.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let media_type = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase());
+        match media_type.as_deref() {
+            Some("application/openmetrics-text") => ScrapeFormat::OpenMetrics,
+            Some("application/vnd.google.protobuf") => ScrapeFormat::Protobuf,
+            _ => ScrapeFormat::Text,
+        }
+    }
+
+    pub fn parse(self, body: &[u8]) -> Result<Vec<MetricGroup>> {
+        Ok(match self {
+            ScrapeFormat::OpenMetrics => parse_openmetrics(std::str::from_utf8(body)?)?,
+            ScrapeFormat::Protobuf => parse_protobuf(body)?,
+            ScrapeFormat::Text => parse_text(std::str::from_utf8(body)?)?,
+        })
+    }
+}
+
+/// A successful scrape.
+pub struct Scrape {
+    pub metrics: Vec<MetricGroup>,
+    /// Samples exposed by the target, before `metric_relabel_configs`.
+    pub samples_scraped: usize,
+}
 
 pub struct TargetScraper {
+    pub job_name: String,
     pub url: String,
+    /// Target labels added to every scraped series.
+    pub labels: Labels,
+    pub discovered_labels: Labels,
     pub client: Client,
     pub timeout: Duration,
     pub max_retries: usize,
+    pub honor_labels: bool,
+    pub honor_timestamps: bool,
+    pub metric_relabel_configs: Vec<RelabelConfig>,
 }
 
 impl TargetScraper {
-    pub fn new(url: String, client: Client, timeout: Duration, max_retries: usize) -> Self {
+    /// Scraper for `target` with the settings of its job.
+    pub fn new(target: Target, client: Client, job: &ScrapeConfig, global: &GlobalConfig) -> Self {
         TargetScraper {
-            url,
+            job_name: job.job_name.clone(),
+            url: target.url,
+            labels: target.labels,
+            discovered_labels: target.discovered_labels,
             client,
-            timeout,
-            max_retries,
+            timeout: job.scrape_timeout(global),
+            max_retries: job.scrape_retries(global),
+            honor_labels: job.honor_labels,
+            honor_timestamps: job.honor_timestamps,
+            metric_relabel_configs: job.metric_relabel_configs.clone(),
         }
     }
-    pub async fn scrape(&self) -> Result<Vec<MetricGroup>> {
+
+    /// Whether `other` scrapes the same target in the same way. The client
+    /// isn't compared, as every scraper shares the agent's.
+    pub fn same_settings(&self, other: &TargetScraper) -> bool {
+        self.job_name == other.job_name
+            && self.url == other.url
+            && self.labels == other.labels
+            && self.discovered_labels == other.discovered_labels
+            && self.timeout == other.timeout
+            && self.max_retries == other.max_retries
+            && self.honor_labels == other.honor_labels
+            && self.honor_timestamps == other.honor_timestamps
+            && self.metric_relabel_configs == other.metric_relabel_configs
+    }
+
+    /// Scrape the target, add the target labels to every series and run
+    /// the job's `metric_relabel_configs` over the result. Exposed
+    /// timestamps are dropped unless `honor_timestamps` is set, leaving the
+    /// series to the time of the scrape.
+    ///
+    /// Failed requests are retried, but the whole scrape, retries included,
+    /// gives up after the job's `scrape_timeout`; the next tick is the next
+    /// chance.
+    pub async fn scrape(&self) -> Result<Scrape> {
         let strategy = ExponentialBackoff::from_millis(100)
             .max_delay(Duration::from_secs(10))
             .take(self.max_retries);
 
-        Retry::spawn(strategy, || async {
-            tokio::time::timeout(self.timeout, fetch_metrics(self.client.clone(), &self.url))
-                .await?
+        let fetch = Retry::spawn(strategy, || fetch_metrics(self.client.clone(), &self.url));
+        let mut metrics = tokio::time::timeout(self.timeout, fetch)
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "scrape timed out after {}",
+                    humantime::format_duration(self.timeout)
+                )
+            })??;
+        rewrite_labels(&mut metrics, |labels| {
+            merge_target_labels(labels, &self.labels, self.honor_labels)
+        });
+        if !self.honor_timestamps {
+            rewrite_series(&mut metrics, |key| key.timestamp = None);
+        }
+        Ok(Scrape {
+            samples_scraped: sample_count(&metrics),
+            metrics: relabel_groups(metrics, &self.metric_relabel_configs),
         })
-        .await
     }
 }
 
 pub async fn fetch_metrics(client: Client, url: &str) -> Result<Vec<MetricGroup>> {
-    let response = client.get(url).send().await?;
-    let body = response.text().await?;
-    let parsed = parse_text(&body)?;
-    Ok(parsed)
+    let response = client
+        .get(url)
+        .header(header::ACCEPT, ACCEPT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let format = ScrapeFormat::from_content_type(
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let body = response.bytes().await?;
+    format.parse(&body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use prometheus_parser::GroupKind;
+    use std::io::Write;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_from_content_type() {
+        assert_eq!(
+            ScrapeFormat::from_content_type(Some(
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            )),
+            ScrapeFormat::OpenMetrics
+        );
+        assert_eq!(
+            ScrapeFormat::from_content_type(Some("text/plain; version=0.0.4")),
+            ScrapeFormat::Text
+        );
+        assert_eq!(
+            ScrapeFormat::from_content_type(Some(
+                "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited"
+            )),
+            ScrapeFormat::Protobuf
+        );
+        assert_eq!(ScrapeFormat::from_content_type(None), ScrapeFormat::Text);
+    }
+
+    #[tokio::test]
+    async fn negotiates_openmetrics() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                "# TYPE requests counter\nrequests_total 3\n# EOF\n",
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let metrics = fetch_metrics(Client::new(), &server.uri()).await.unwrap();
+        let received = server.received_requests().await.unwrap();
+        assert_eq!(received[0].headers["Accept"], ACCEPT);
+        assert_eq!(metrics[0].name, "requests_total");
+        assert!(matches!(metrics[0].metrics, GroupKind::Counter(_)));
+    }
+
+    #[tokio::test]
+    async fn decompresses_gzip_responses() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"up 1\n").unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("Accept-Encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(encoder.finish().unwrap(), "text/plain; version=0.0.4")
+                    .insert_header("Content-Encoding", "gzip"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let metrics = fetch_metrics(Client::new(), &server.uri()).await.unwrap();
+        assert_eq!(metrics[0].name, "up");
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hanging_target_after_the_timeout() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(60)))
+            .mount(&server)
+            .await;
+
+        let scraper = TargetScraper {
+            job_name: "node".to_string(),
+            url: server.uri(),
+            labels: Labels::new(),
+            discovered_labels: Labels::new(),
+            client: Client::new(),
+            timeout: Duration::from_millis(200),
+            max_retries: 10,
+            honor_labels: false,
+            honor_timestamps: true,
+            metric_relabel_configs: Vec::new(),
+        };
+        let started = std::time::Instant::now();
+        let err = scraper.scrape().await.err().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1), "{err:#}");
+        assert_eq!(err.to_string(), "scrape timed out after 200ms");
+    }
+
+    #[tokio::test]
+    async fn keeps_exposed_timestamps_only_when_honored() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1 500\nb 2\n"))
+            .mount(&server)
+            .await;
+
+        let mut scraper = TargetScraper {
+            job_name: "node".to_string(),
+            url: server.uri(),
+            labels: Labels::new(),
+            discovered_labels: Labels::new(),
+            client: Client::new(),
+            timeout: Duration::from_secs(1),
+            max_retries: 0,
+            honor_labels: false,
+            honor_timestamps: true,
+            metric_relabel_configs: Vec::new(),
+        };
+        let timestamps = |scrape: Scrape| {
+            scrape
+                .metrics
+                .iter()
+                .map(|group| match &group.metrics {
+                    GroupKind::Untyped(metrics) => metrics.keys().next().unwrap().timestamp,
+                    kind => panic!("unexpected group kind: {kind:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            timestamps(scraper.scrape().await.unwrap()),
+            [Some(500), None]
+        );
+        scraper.honor_timestamps = false;
+        assert_eq!(timestamps(scraper.scrape().await.unwrap()), [None, None]);
+    }
 }
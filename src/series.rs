@@ -0,0 +1,287 @@
+//! Identity of the individual series a scrape expands to.
+//!
+//! A histogram or summary group holds several series per label set: one
+//! per bucket or quantile plus `_sum` and `_count`. Tracking them one by one
+//! tells which series a target started or stopped exposing, and series it
+//! stopped exposing get a staleness marker.
+
+use crate::config::Protocol;
+use crate::metrics_formatter::label_value;
+use crate::relabel::Labels;
+use prometheus_parser::{GroupKind, HistogramMetric, MetricGroup};
+use std::collections::HashSet;
+
+/// Bit pattern of the NaN Prometheus uses as a staleness marker. It tells
+/// the storage that a series ended, so queries stop returning its last
+/// value right away instead of for the whole lookback window.
+pub const STALE_NAN_BITS: u64 = 0x7ff0000000000002;
+
+pub fn stale_nan() -> f64 {
+    f64::from_bits(STALE_NAN_BITS)
+}
+
+/// A single series: its full name and labels, as written out.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SeriesKey {
+    pub name: String,
+    pub labels: Labels,
+    /// The only protocol the series is written with, `None` for every
+    /// protocol. Native histograms are one series with remote_write, and
+    /// their classic bucket, `_sum` and `_count` series in the text format.
+    pub protocol: Option<Protocol>,
+}
+
+impl SeriesKey {
+    pub fn new(name: String, labels: Labels) -> Self {
+        SeriesKey {
+            name,
+            labels,
+            protocol: None,
+        }
+    }
+
+    fn with_label(name: String, labels: &Labels, label: &str, value: f64) -> Self {
+        let mut labels = labels.clone();
+        labels.insert(label.to_string(), label_value(value));
+        SeriesKey::new(name, labels)
+    }
+
+    /// Whether the series is written with `protocol`.
+    pub fn written_with(&self, protocol: Protocol) -> bool {
+        self.protocol.is_none_or(|only| only == protocol)
+    }
+}
+
+/// Every series of the groups.
+pub fn series_keys(groups: &[MetricGroup]) -> HashSet<SeriesKey> {
+    let mut keys = HashSet::new();
+    for group in groups {
+        let name = &group.name;
+        match &group.metrics {
+            GroupKind::Gauge(metrics)
+            | GroupKind::Counter(metrics)
+            | GroupKind::Untyped(metrics)
+            | GroupKind::Info(metrics)
+            | GroupKind::StateSet(metrics) => {
+                keys.extend(
+                    metrics
+                        .keys()
+                        .map(|key| SeriesKey::new(name.clone(), key.labels.clone())),
+                );
+            }
+            GroupKind::NativeHistogram(metrics) => {
+                for (key, metric) in metrics {
+                    keys.insert(SeriesKey {
+                        protocol: Some(Protocol::RemoteWrite),
+                        ..SeriesKey::new(name.clone(), key.labels.clone())
+                    });
+                    keys.extend(
+                        histogram_keys(name, &key.labels, &metric.to_classic(), ("_sum", "_count"))
+                            .map(|key| SeriesKey {
+                                protocol: Some(Protocol::Text),
+                                ..key
+                            }),
+                    );
+                }
+            }
+            GroupKind::Summary(metrics) => {
+                for (key, metric) in metrics {
+                    keys.extend(metric.quantiles.iter().map(|quantile| {
+                        SeriesKey::with_label(
+                            name.clone(),
+                            &key.labels,
+                            "quantile",
+                            quantile.quantile,
+                        )
+                    }));
+                    keys.extend(sum_and_count(name, &key.labels, ("_sum", "_count")));
+                }
+            }
+            GroupKind::Histogram(metrics) | GroupKind::GaugeHistogram(metrics) => {
+                for (key, metric) in metrics {
+                    keys.extend(histogram_keys(
+                        name,
+                        &key.labels,
+                        metric,
+                        group.metrics.sum_and_count_suffixes(),
+                    ));
+                }
+            }
+        }
+    }
+    keys
+}
+
+fn histogram_keys<'a>(
+    name: &'a str,
+    labels: &'a Labels,
+    metric: &'a HistogramMetric,
+    suffixes: (&str, &str),
+) -> impl Iterator<Item = SeriesKey> + 'a {
+    let buckets = metric.buckets.iter().map(move |bucket| {
+        SeriesKey::with_label(format!("{name}_bucket"), labels, "le", bucket.bucket)
+    });
+    buckets.chain(sum_and_count(name, labels, suffixes))
+}
+
+fn sum_and_count(name: &str, labels: &Labels, suffixes: (&str, &str)) -> [SeriesKey; 2] {
+    [suffixes.0, suffixes.1].map(|suffix| SeriesKey::new(format!("{name}{suffix}"), labels.clone()))
+}
+
+/// How the series of a scrape differ from the previous one.
+#[derive(Debug, Default, PartialEq)]
+pub struct SeriesChanges {
+    /// Number of series the previous scrape didn't have.
+    pub added: usize,
+    /// Series of the previous scrape that are gone.
+    pub vanished: Vec<SeriesKey>,
+}
+
+/// The series of a target's previous scrape.
+#[derive(Debug, Default)]
+pub struct SeriesCache {
+    series: HashSet<SeriesKey>,
+}
+
+impl SeriesCache {
+    /// Remember the series of a successful scrape.
+    pub fn update(&mut self, groups: &[MetricGroup]) -> SeriesChanges {
+        let series = series_keys(groups);
+        // A native histogram is stored as a single series.
+        let added = series
+            .difference(&self.series)
+            .filter(|key| key.protocol != Some(Protocol::Text))
+            .count();
+        let vanished = self.series.difference(&series).cloned().collect();
+        self.series = series;
+        SeriesChanges { added, vanished }
+    }
+
+    /// Forget every series, returning them, once the target failed or went
+    /// away.
+    pub fn take(&mut self) -> Vec<SeriesKey> {
+        self.series.drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus_parser::{GroupKey, NativeHistogramMetric, parse_text};
+
+    #[test]
+    fn expands_histograms_and_summaries() {
+        let groups = parse_text(
+            r#"
+            # TYPE h histogram
+            h_bucket{le="1"} 1
+            h_bucket{le="+Inf"} 2
+            h_sum 3
+            h_count 2
+            # TYPE s summary
+            s{quantile="0.5",x="a"} 1
+            s_sum{x="a"} 1
+            s_count{x="a"} 1
+            up 1
+            "#,
+        )
+        .unwrap();
+        let mut names = series_keys(&groups)
+            .into_iter()
+            .map(|key| {
+                let labels = key
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("{}{{{labels}}}", key.name)
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "h_bucket{le=+Inf}",
+                "h_bucket{le=1}",
+                "h_count{}",
+                "h_sum{}",
+                "s_count{x=a}",
+                "s_sum{x=a}",
+                "s{quantile=0.5,x=a}",
+                "up{}",
+            ]
+        );
+    }
+
+    #[test]
+    fn native_histograms_are_written_as_classic_series_in_text() {
+        let key = GroupKey {
+            timestamp: None,
+            labels: Labels::new(),
+        };
+        let metric = NativeHistogramMetric {
+            count: 1,
+            sum: 1.0,
+            zero_count: 1,
+            ..Default::default()
+        };
+        let groups = [MetricGroup {
+            name: "h".to_string(),
+            metrics: GroupKind::NativeHistogram([(key, metric)].into_iter().collect()),
+            help: None,
+            unit: None,
+        }];
+        let keys = series_keys(&groups);
+        let written_with = |protocol| {
+            let mut names = keys
+                .iter()
+                .filter(|key| key.written_with(protocol))
+                .map(|key| format!("{}{:?}", key.name, key.labels))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(written_with(Protocol::RemoteWrite), ["h{}"]);
+        assert_eq!(
+            written_with(Protocol::Text),
+            [
+                "h_bucket{\"le\": \"+Inf\"}",
+                "h_bucket{\"le\": \"0\"}",
+                "h_count{}",
+                "h_sum{}",
+            ]
+        );
+        assert_eq!(SeriesCache::default().update(&groups).added, 1);
+    }
+
+    fn series(name: &str) -> SeriesKey {
+        SeriesKey::new(name.to_string(), Labels::new())
+    }
+
+    #[test]
+    fn tracks_added_and_vanished_series() {
+        let mut cache = SeriesCache::default();
+        let changes = cache.update(&parse_text("a 1\nb 1\n").unwrap());
+        assert_eq!(changes.added, 2);
+        assert!(changes.vanished.is_empty());
+
+        let changes = cache.update(&parse_text("a 2\nb 2\n").unwrap());
+        assert_eq!(changes, SeriesChanges::default());
+
+        let changes = cache.update(&parse_text("a 3\nc 1\n").unwrap());
+        assert_eq!(changes.added, 1);
+        assert_eq!(changes.vanished, [series("b")]);
+
+        let mut taken = cache.take();
+        taken.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(taken, [series("a"), series("c")]);
+        assert_eq!(cache.update(&parse_text("a 4\n").unwrap()).added, 1);
+    }
+
+    #[test]
+    fn stale_nan_is_a_distinct_nan() {
+        assert!(stale_nan().is_nan());
+        assert_ne!(f64::NAN.to_bits(), STALE_NAN_BITS);
+    }
+}
@@ -0,0 +1,507 @@
+//! Runs the scrape loops and destination pipelines a config describes,
+//! keeps the loops in line with discovered targets, and brings everything
+//! in line with a new config on reload.
+//!
+//! A reload is applied as a diff. Only the scrape loops of targets that were
+//! added, removed or changed are touched. Destinations are identified by
+//! their queue directory: those that stay keep their pipeline and queued
+//! batches and get the new settings, removed ones write out what they hold.
+//! Whatever can fail is done before anything running is changed, so a
+//! config that can't be applied leaves the running one in place.
+
+use crate::config::{Config, RemoteWriteConfig};
+use crate::discovery::{self, FileDiscovery, TargetGroup};
+use crate::disk_queue::DiskQueue;
+use crate::metrics_agent::{self, Encoder, FlushLimits, MetricsAgent, MetricsMessage, Routes};
+use crate::remote_write::{RemoteWriter, RetryPolicy};
+use crate::scrape_manager::{ScrapeManager, TargetStatuses};
+use crate::scraper::TargetScraper;
+use crate::target::Target;
+use crate::telemetry::Telemetry;
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// A destination's running pipeline.
+struct Destination {
+    /// Tells the pipeline apart from those of destinations removed earlier.
+    id: u64,
+    config: RemoteWriteConfig,
+    agent: Arc<MetricsAgent>,
+    tx: mpsc::Sender<Arc<MetricsMessage>>,
+}
+
+pub struct Supervisor {
+    config: Config,
+    client: reqwest::Client,
+    scrape_manager: ScrapeManager,
+    /// Target groups of the jobs' `file_sd_configs`, by job and position
+    /// of the config.
+    discovered: BTreeMap<(String, usize), Vec<TargetGroup>>,
+    discovery: mpsc::Receiver<discovery::Update>,
+    /// Watch the target files; replaced on reload.
+    watchers: JoinSet<()>,
+    telemetry: Telemetry,
+    routes: watch::Sender<Routes>,
+    dispatcher: JoinHandle<()>,
+    /// Current destinations by queue directory.
+    destinations: HashMap<PathBuf, Destination>,
+    /// Queue directories of removed destinations still writing out their
+    /// queues, by pipeline.
+    draining: HashMap<u64, PathBuf>,
+    pipelines: JoinSet<(u64, Result<()>)>,
+    next_id: u64,
+    /// The pipeline of every destination started, removed ones included.
+    agents: Vec<Arc<MetricsAgent>>,
+}
+
+impl Supervisor {
+    /// Start scraping the targets of `config` and writing to its
+    /// destinations.
+    pub fn start(config: Config) -> Result<Self> {
+        let (scrape_tx, scrape_rx) = mpsc::channel::<MetricsMessage>(32);
+        let scrape_manager = ScrapeManager::new(scrape_tx.clone());
+        let telemetry = Telemetry::new(scrape_manager.statuses(), &scrape_tx);
+        let (routes, routes_rx) = watch::channel(Routes::default());
+        let mut supervisor = Supervisor {
+            config: config.clone(),
+            client: reqwest::Client::new(),
+            scrape_manager,
+            discovered: BTreeMap::new(),
+            discovery: mpsc::channel(1).1,
+            watchers: JoinSet::new(),
+            telemetry,
+            routes,
+            dispatcher: tokio::spawn(metrics_agent::dispatch(scrape_rx, routes_rx)),
+            destinations: HashMap::new(),
+            draining: HashMap::new(),
+            pipelines: JoinSet::new(),
+            next_id: 0,
+            agents: Vec::new(),
+        };
+        supervisor.apply(config)?;
+        Ok(supervisor)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn telemetry(&self) -> Telemetry {
+        self.telemetry.clone()
+    }
+
+    pub fn statuses(&self) -> TargetStatuses {
+        self.scrape_manager.statuses()
+    }
+
+    /// The pipeline of every destination started, removed ones included.
+    pub fn agents(&self) -> Vec<Arc<MetricsAgent>> {
+        self.agents.clone()
+    }
+
+    /// Load the config at `path` and apply it. If it can't be, the failure
+    /// is logged and returned, and the running config stays in place.
+    pub fn reload(&mut self, path: &Path) -> Result<()> {
+        info!(path = %path.display(), "reloading config");
+        let result = Config::load(path)
+            .with_context(|| format!("loading config from `{}`", path.display()))
+            .and_then(|config| self.apply(config));
+        match &result {
+            Ok(()) => info!("config reloaded"),
+            Err(err) => error!("failed to reload config, keeping the running one: {err:#}"),
+        }
+        result
+    }
+
+    fn apply(&mut self, config: Config) -> Result<()> {
+        let mut opened = HashMap::new();
+        for remote_write in &config.remote_write {
+            let queue_dir = remote_write.queue_dir(&config.storage);
+            if let Some(running) = self.destinations.get(&queue_dir) {
+                if running.config.protocol != remote_write.protocol {
+                    bail!(
+                        "the protocol of the destination queued in `{}` can't change while \
+                         the agent runs, as its queue holds batches in the old one",
+                        queue_dir.display()
+                    );
+                }
+                continue;
+            }
+            if self
+                .draining
+                .values()
+                .any(|draining| *draining == queue_dir)
+            {
+                bail!(
+                    "the destination queued in `{}` was removed and is still writing out its queue",
+                    queue_dir.display()
+                );
+            }
+            let queue = DiskQueue::open(&queue_dir, remote_write.queue_config.max_queue_bytes)
+                .with_context(|| format!("opening disk queue in `{}`", queue_dir.display()))?;
+            opened.insert(queue_dir, queue);
+        }
+        if config.web.listen_address != self.config.web.listen_address {
+            warn!(
+                listen_address = %self.config.web.listen_address,
+                "web.listen_address only changes on restart"
+            );
+        }
+
+        let mut routes = Vec::new();
+        for remote_write in &config.remote_write {
+            let queue_dir = remote_write.queue_dir(&config.storage);
+            match self.destinations.get_mut(&queue_dir) {
+                Some(destination) if destination.config != *remote_write => {
+                    info!(url = %remote_write.url, "updating remote write destination");
+                    destination.agent.reconfigure(remote_write);
+                    self.telemetry.add_destination(
+                        remote_write,
+                        Arc::clone(&destination.agent),
+                        &destination.tx,
+                    );
+                    destination.config = remote_write.clone();
+                }
+                Some(_) => {}
+                None => {
+                    let queue = opened.remove(&queue_dir).expect("opened above");
+                    self.add_destination(queue_dir.clone(), remote_write, queue);
+                }
+            }
+            routes.push(self.destinations[&queue_dir].tx.clone());
+        }
+        // Removed destinations end once their channel is no longer routed to.
+        self.destinations.retain(|queue_dir, destination| {
+            let keep = routes.iter().any(|tx| tx.same_channel(&destination.tx));
+            if !keep {
+                info!(
+                    url = %destination.config.url,
+                    "removing remote write destination, writing out its queue"
+                );
+                self.telemetry.remove_destination(&destination.agent);
+                self.draining.insert(destination.id, queue_dir.clone());
+            }
+            keep
+        });
+        self.routes.send_replace(Routes {
+            destinations: routes,
+            external_labels: config.global.external_labels.clone(),
+        });
+
+        self.discover(&config);
+        self.scrape_manager.sync(self.scrapers(&config));
+        self.config = config;
+        Ok(())
+    }
+
+    /// Start discovery over for the jobs of `config`. The files are read
+    /// right away, so that the config is applied with all its targets.
+    fn discover(&mut self, config: &Config) {
+        // Updates from the watchers replaced are dropped with their channel.
+        let (tx, rx) = mpsc::channel(8);
+        self.discovery = rx;
+        self.watchers = JoinSet::new();
+        self.discovered.clear();
+        for job in &config.scrape_configs {
+            for (index, file_sd_config) in job.file_sd_configs.iter().enumerate() {
+                let mut discovery = FileDiscovery::new(file_sd_config.clone());
+                self.discovered
+                    .insert((job.job_name.clone(), index), discovery.refresh());
+                self.watchers
+                    .spawn(discovery.run(job.job_name.clone(), index, tx.clone()));
+            }
+        }
+    }
+
+    fn add_destination(
+        &mut self,
+        queue_dir: PathBuf,
+        config: &RemoteWriteConfig,
+        queue: DiskQueue,
+    ) {
+        info!(
+            name = config.name.as_deref().unwrap_or_default(),
+            url = %config.url,
+            protocol = ?config.protocol,
+            "configured remote write destination"
+        );
+        let writer = RemoteWriter::new(
+            config.url.clone(),
+            self.client.clone(),
+            config.protocol,
+            RetryPolicy::from(&config.queue_config),
+        );
+        let agent = Arc::new(MetricsAgent::new(
+            writer,
+            Encoder::new(config.protocol),
+            FlushLimits::from(&config.queue_config),
+            queue,
+        ));
+        let (tx, rx) = mpsc::channel::<Arc<MetricsMessage>>(32);
+        self.telemetry
+            .add_destination(config, Arc::clone(&agent), &tx);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let pipeline = Arc::clone(&agent);
+        self.pipelines.spawn(async move {
+            let result = tokio::try_join!(pipeline.format(rx), pipeline.write());
+            (id, result.map(|_| ()))
+        });
+        self.agents.push(Arc::clone(&agent));
+        self.destinations.insert(
+            queue_dir,
+            Destination {
+                id,
+                config: config.clone(),
+                agent,
+                tx,
+            },
+        );
+    }
+
+    /// A scraper for every static and discovered target of the config.
+    /// Invalid targets are skipped.
+    fn scrapers(&self, config: &Config) -> Vec<(TargetScraper, Duration)> {
+        let mut scrapers = Vec::new();
+        for job in &config.scrape_configs {
+            let discovered = self
+                .discovered
+                .range((job.job_name.clone(), 0)..=(job.job_name.clone(), usize::MAX))
+                .flat_map(|(_, groups)| Target::from_groups(job, groups));
+            for (address, target) in Target::from_static_configs(job).chain(discovered) {
+                let target = match target {
+                    Ok(Some(target)) => target,
+                    Ok(None) => {
+                        debug!(job = %job.job_name, address, "target dropped by relabeling");
+                        continue;
+                    }
+                    Err(err) => {
+                        warn!(job = %job.job_name, address, "skipping target: {err}");
+                        continue;
+                    }
+                };
+                info!(
+                    job = %job.job_name,
+                    target_url = %target.url,
+                    labels = ?target.labels,
+                    "configured scrape target"
+                );
+                let scraper = TargetScraper::new(target, self.client.clone(), job, &config.global);
+                scrapers.push((scraper, job.scrape_interval(&config.global)));
+            }
+        }
+        scrapers
+    }
+
+    /// Keep the scrape loops in line with discovered targets until a
+    /// pipeline stops on its own, which pipelines only do when they fail.
+    /// Removed destinations finishing their queues are waited past.
+    pub async fn supervise(&mut self) -> anyhow::Error {
+        loop {
+            tokio::select! {
+                Some(joined) = self.pipelines.join_next() => {
+                    if let Some(err) = self.pipeline_ended(joined) {
+                        return err;
+                    }
+                }
+                Some(update) = self.discovery.recv() => {
+                    info!(job = %update.job_name, "discovered targets changed");
+                    self.discovered
+                        .insert((update.job_name, update.index), update.groups);
+                    self.scrape_manager.sync(self.scrapers(&self.config));
+                }
+                else => return std::future::pending().await,
+            }
+        }
+    }
+
+    /// The error of a pipeline that ended, unless it was removed.
+    fn pipeline_ended(
+        &mut self,
+        joined: Result<(u64, Result<()>), JoinError>,
+    ) -> Option<anyhow::Error> {
+        let (id, result) = match joined {
+            Ok(joined) => joined,
+            Err(err) => return Some(err.into()),
+        };
+        let Some(queue_dir) = self.draining.remove(&id) else {
+            return Some(
+                result
+                    .err()
+                    .unwrap_or_else(|| anyhow!("pipeline stopped unexpectedly")),
+            );
+        };
+        match result {
+            Ok(()) => info!(queue_dir = %queue_dir.display(), "removed destination is done"),
+            Err(err) => error!(
+                queue_dir = %queue_dir.display(),
+                "removed destination failed: {err:#}"
+            ),
+        }
+        None
+    }
+
+    /// Stop scraping and wait for every pipeline, removed ones included, to
+    /// write out what it holds.
+    pub async fn shutdown(self) -> Result<()> {
+        let Supervisor {
+            scrape_manager,
+            routes,
+            dispatcher,
+            destinations,
+            mut pipelines,
+            ..
+        } = self;
+        // Stopping the scrape loops closes the channels behind them, so each
+        // stage drains what it holds before the next one sees its input end.
+        scrape_manager.shutdown().await;
+        drop((routes, destinations));
+        dispatcher.await?;
+        while let Some(joined) = pipelines.join_next().await {
+            joined?.1?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// A config scraping `targets` every hour, writing to the named
+    /// destinations, with queues under `storage`.
+    fn config(storage: &Path, targets: &[&MockServer], destinations: &[(&str, String)]) -> Config {
+        let targets = targets
+            .iter()
+            .map(|server| format!("\"{}\"", server.address()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut yaml = format!(
+            "storage:\n  path: {}\nweb:\n  listen_address: 127.0.0.1:0\n\
+             scrape_configs:\n  - job_name: node\n    scrape_interval: 1h\n    \
+             static_configs:\n      - targets: [{targets}]\nremote_write:\n",
+            storage.display()
+        );
+        for (name, url) in destinations {
+            yaml.push_str(&format!("  - name: {name}\n    url: {url}\n"));
+        }
+        Config::from_yaml(&yaml).unwrap()
+    }
+
+    async fn server(method_name: &str) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method(method_name))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\n"))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn applies_changes_and_keeps_the_running_config_on_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (server("GET").await, server("GET").await);
+        let (sink, moved_sink) = (server("POST").await, server("POST").await);
+
+        let mut supervisor =
+            Supervisor::start(config(dir.path(), &[&a], &[("vm", sink.uri())])).unwrap();
+        let vm = Arc::clone(&supervisor.agents()[0]);
+        assert_eq!(supervisor.statuses().snapshot().len(), 1);
+
+        // A new target and destination, and a destination moved elsewhere.
+        let destinations = [("vm", moved_sink.uri()), ("other", sink.uri())];
+        supervisor
+            .apply(config(dir.path(), &[&a, &b], &destinations))
+            .unwrap();
+        assert_eq!(supervisor.statuses().snapshot().len(), 2);
+        assert_eq!(supervisor.agents().len(), 2);
+        assert!(Arc::ptr_eq(&supervisor.agents()[0], &vm));
+        assert_eq!(supervisor.telemetry().agents().len(), 2);
+
+        let mut invalid = config(dir.path(), &[&a], &[("vm", sink.uri())]);
+        invalid.remote_write[0].protocol = crate::config::Protocol::RemoteWrite;
+        assert!(supervisor.apply(invalid).is_err());
+        assert_eq!(supervisor.statuses().snapshot().len(), 2);
+        assert_eq!(supervisor.config().remote_write[0].url, moved_sink.uri());
+
+        // The removed destination writes out its queue and ends quietly.
+        supervisor
+            .apply(config(dir.path(), &[&a], &[("vm", moved_sink.uri())]))
+            .unwrap();
+        assert_eq!(supervisor.statuses().snapshot().len(), 1);
+        assert_eq!(supervisor.telemetry().agents().len(), 1);
+        assert!(
+            time::timeout(Duration::from_millis(500), supervisor.supervise())
+                .await
+                .is_err()
+        );
+        assert!(supervisor.draining.is_empty());
+
+        supervisor.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn follows_discovered_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (server("GET").await, server("GET").await);
+        let sink = server("POST").await;
+        let targets_file = dir.path().join("targets.json");
+        let write_targets = |servers: &[&MockServer]| {
+            let targets = servers
+                .iter()
+                .map(|server| format!("\"{}\"", server.address()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            std::fs::write(&targets_file, format!(r#"[{{"targets": [{targets}]}}]"#)).unwrap();
+        };
+        write_targets(&[&a]);
+        let config = Config::from_yaml(&format!(
+            "storage:\n  path: {}\n\
+             scrape_configs:\n  - job_name: node\n    scrape_interval: 1h\n    \
+             file_sd_configs:\n      - files: [{}]\n        refresh_interval: 50ms\n\
+             remote_write:\n  - url: {}\n",
+            dir.path().display(),
+            targets_file.display(),
+            sink.uri()
+        ))
+        .unwrap();
+
+        let mut supervisor = Supervisor::start(config).unwrap();
+        let scrape_urls = |supervisor: &Supervisor| {
+            supervisor
+                .statuses()
+                .snapshot()
+                .into_iter()
+                .map(|((url, _), _)| url)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scrape_urls(&supervisor), [format!("{}/metrics", a.uri())]);
+
+        write_targets(&[&b]);
+        // Supervising applies discovery updates as they come.
+        for _ in 0..100 {
+            if scrape_urls(&supervisor) == [format!("{}/metrics", b.uri())] {
+                break;
+            }
+            let _ = time::timeout(Duration::from_millis(50), supervisor.supervise()).await;
+        }
+        assert_eq!(scrape_urls(&supervisor), [format!("{}/metrics", b.uri())]);
+        let status = &supervisor.statuses().snapshot()[0].1;
+        assert_eq!(
+            status.discovered_labels[discovery::FILEPATH_LABEL],
+            targets_file.display().to_string()
+        );
+
+        supervisor.shutdown().await.unwrap();
+    }
+}
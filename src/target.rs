@@ -0,0 +1,377 @@
+//! Scrape targets, built from a job's config and its relabeling rules.
+//!
+//! Every target starts out as a set of discovered labels: its address, the
+//! labels of its static config or target group, and, where those don't set
+//! them, the job's scheme, metrics path and URL parameters as `__`-prefixed
+//! meta labels and the job name. The job's
+//! `relabel_configs` then rewrite that set; the meta labels left afterwards
+//! make up the scrape URL and the rest become the target's labels.
+
+use crate::config::ScrapeConfig;
+use crate::discovery::TargetGroup;
+use crate::relabel::{Labels, relabel};
+use reqwest::Url;
+
+pub const ADDRESS_LABEL: &str = "__address__";
+pub const SCHEME_LABEL: &str = "__scheme__";
+pub const METRICS_PATH_LABEL: &str = "__metrics_path__";
+pub const PARAM_LABEL_PREFIX: &str = "__param_";
+pub const JOB_LABEL: &str = "job";
+pub const INSTANCE_LABEL: &str = "instance";
+/// Prefix given to exposed labels that clash with target labels.
+pub const EXPORTED_LABEL_PREFIX: &str = "exported_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TargetError {
+    #[error("no `{ADDRESS_LABEL}` label left after relabeling")]
+    MissingAddress,
+    #[error("invalid address `{0}`")]
+    InvalidAddress(String),
+    #[error("unsupported scheme `{0}`")]
+    InvalidScheme(String),
+    #[error("invalid scrape URL `{url}`: {message}")]
+    InvalidUrl { url: String, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub url: String,
+    /// Labels identifying the target, without meta labels.
+    pub labels: Labels,
+    /// Labels the target was discovered with, before relabeling.
+    pub discovered_labels: Labels,
+}
+
+impl Target {
+    /// Build the target at `address` of `job`, with the labels of the
+    /// static config or target group it was listed in. Returns `Ok(None)`
+    /// if relabeling dropped it.
+    pub fn new(
+        job: &ScrapeConfig,
+        address: &str,
+        group_labels: &Labels,
+    ) -> Result<Option<Self>, TargetError> {
+        let mut discovered = group_labels.clone();
+        discovered.insert(ADDRESS_LABEL.to_string(), address.to_string());
+        // As with Prometheus, the job only fills in the labels a target
+        // wasn't given, so a target file can set its own metrics path.
+        let mut default = |name: String, value: &str| {
+            let label = discovered.entry(name).or_default();
+            if label.is_empty() {
+                *label = value.to_string();
+            }
+        };
+        default(SCHEME_LABEL.to_string(), job.scheme.as_str());
+        default(METRICS_PATH_LABEL.to_string(), &job.metrics_path);
+        default(JOB_LABEL.to_string(), &job.job_name);
+        for (name, values) in &job.params {
+            if let Some(value) = values.first() {
+                default(format!("{PARAM_LABEL_PREFIX}{name}"), value);
+            }
+        }
+
+        let Some(mut labels) = relabel(discovered.clone(), &job.relabel_configs) else {
+            return Ok(None);
+        };
+        let address = match labels.get(ADDRESS_LABEL) {
+            Some(address) if !address.is_empty() => address.clone(),
+            _ => return Err(TargetError::MissingAddress),
+        };
+        let scheme = labels.get(SCHEME_LABEL).cloned().unwrap_or_default();
+        let address = with_default_port(&address, &scheme)?;
+        let metrics_path = labels.get(METRICS_PATH_LABEL).cloned().unwrap_or_default();
+
+        let url = format!("{scheme}://{address}{metrics_path}");
+        let mut url = Url::parse(&url).map_err(|err| TargetError::InvalidUrl {
+            url,
+            message: err.to_string(),
+        })?;
+        {
+            // `__param_*` labels override the first value of a configured
+            // parameter; the other values are kept.
+            let mut query = url.query_pairs_mut();
+            for (name, values) in &job.params {
+                let label = format!("{PARAM_LABEL_PREFIX}{name}");
+                let mut values = values.iter();
+                if let Some(first) = values.next() {
+                    query.append_pair(name, labels.get(&label).unwrap_or(first));
+                }
+                for value in values {
+                    query.append_pair(name, value);
+                }
+            }
+            for (label, value) in &labels {
+                if let Some(name) = label.strip_prefix(PARAM_LABEL_PREFIX)
+                    && !job.params.contains_key(name)
+                {
+                    query.append_pair(name, value);
+                }
+            }
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+
+        labels
+            .entry(INSTANCE_LABEL.to_string())
+            .or_insert_with(|| address.clone());
+        labels.retain(|name, value| !name.starts_with("__") && !value.is_empty());
+        Ok(Some(Target {
+            url: url.to_string(),
+            labels,
+            discovered_labels: discovered,
+        }))
+    }
+
+    /// Every target listed in the job's static configs, with the address it
+    /// was listed as.
+    pub fn from_static_configs(
+        job: &ScrapeConfig,
+    ) -> impl Iterator<Item = (&str, Result<Option<Self>, TargetError>)> {
+        job.static_configs.iter().flat_map(move |static_config| {
+            static_config.targets.iter().map(move |address| {
+                let target = Target::new(job, address, &static_config.labels);
+                (address.as_str(), target)
+            })
+        })
+    }
+
+    /// Every target of discovered groups, with the address it was listed
+    /// as.
+    pub fn from_groups<'a>(
+        job: &'a ScrapeConfig,
+        groups: &'a [TargetGroup],
+    ) -> impl Iterator<Item = (&'a str, Result<Option<Self>, TargetError>)> {
+        groups.iter().flat_map(move |group| {
+            group.targets.iter().map(move |address| {
+                let target = Target::new(job, address, &group.labels);
+                (address.as_str(), target)
+            })
+        })
+    }
+}
+
+/// Add the target's labels to the labels of a scraped series.
+///
+/// With `honor_labels`, labels exposed by the target win over target labels
+/// of the same name. Otherwise the target label wins and the exposed one is
+/// kept as `exported_<name>`, as in Prometheus.
+pub fn merge_target_labels(labels: &mut Labels, target_labels: &Labels, honor_labels: bool) {
+    for (name, value) in target_labels {
+        match labels.get(name) {
+            None => {
+                labels.insert(name.clone(), value.clone());
+            }
+            Some(_) if honor_labels => {}
+            Some(exposed) if exposed == value => {}
+            Some(_) => {
+                let exposed = labels.insert(name.clone(), value.clone()).unwrap();
+                let mut exported = format!("{EXPORTED_LABEL_PREFIX}{name}");
+                while labels.contains_key(&exported) {
+                    exported.insert_str(0, EXPORTED_LABEL_PREFIX);
+                }
+                labels.insert(exported, exposed);
+            }
+        }
+    }
+}
+
+/// Targets may leave out the port; it then defaults to the scheme's.
+fn with_default_port(address: &str, scheme: &str) -> Result<String, TargetError> {
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(TargetError::InvalidScheme(scheme.to_string())),
+    };
+    if address.contains(['/', '?', '#', '@']) {
+        return Err(TargetError::InvalidAddress(address.to_string()));
+    }
+    match address.rsplit_once(':') {
+        // A bare IPv6 address is all colons, but ends with `]` when bracketed.
+        Some((_, port)) if !port.ends_with(']') => match port.parse::<u16>() {
+            Ok(_) => Ok(address.to_string()),
+            Err(_) => Err(TargetError::InvalidAddress(address.to_string())),
+        },
+        _ => Ok(format!("{address}:{default_port}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+
+    fn job(yaml: &str) -> ScrapeConfig {
+        let config = Config::from_yaml(&format!(
+            "scrape_configs:\n{yaml}\nremote_write:\n  - url: http://127.0.0.1:8428/\n"
+        ))
+        .unwrap();
+        config.scrape_configs[0].clone()
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn defaults_without_relabeling() {
+        let job = job("  - job_name: node");
+        let target = Target::new(&job, "127.0.0.1:9100", &labels(&[("env", "prod")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(target.url, "http://127.0.0.1:9100/metrics");
+        assert_eq!(
+            target.labels,
+            labels(&[
+                ("env", "prod"),
+                ("instance", "127.0.0.1:9100"),
+                ("job", "node"),
+            ])
+        );
+        assert_eq!(
+            target.discovered_labels,
+            labels(&[
+                ("__address__", "127.0.0.1:9100"),
+                ("__metrics_path__", "/metrics"),
+                ("__scheme__", "http"),
+                ("env", "prod"),
+                ("job", "node"),
+            ])
+        );
+    }
+
+    #[test]
+    fn meta_labels_build_the_url() {
+        let job = job(r#"
+  - job_name: blackbox
+    metrics_path: /probe
+    params:
+      module: [http_2xx]
+    relabel_configs:
+      - source_labels: [__address__]
+        target_label: __param_target
+      - source_labels: [__param_target]
+        target_label: instance
+      - target_label: __address__
+        replacement: blackbox:9115
+      - target_label: __scheme__
+        replacement: https
+      - target_label: __param_debug
+        replacement: "true"
+"#);
+        let target = Target::new(&job, "example.com:443", &Labels::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            target.url,
+            "https://blackbox:9115/probe?module=http_2xx&debug=true&target=example.com%3A443"
+        );
+        assert_eq!(
+            target.labels,
+            labels(&[("instance", "example.com:443"), ("job", "blackbox")])
+        );
+    }
+
+    #[test]
+    fn group_labels_override_the_job_defaults() {
+        let job = job(r#"
+  - job_name: blackbox
+    params:
+      module: [http_2xx]
+"#);
+        let group_labels = labels(&[
+            ("__metrics_path__", "/probe"),
+            ("__scheme__", "https"),
+            ("__param_module", "tcp_connect"),
+        ]);
+        let target = Target::new(&job, "blackbox:9115", &group_labels)
+            .unwrap()
+            .unwrap();
+        assert_eq!(target.url, "https://blackbox:9115/probe?module=tcp_connect");
+        assert_eq!(target.discovered_labels["__metrics_path__"], "/probe");
+        assert_eq!(target.labels["job"], "blackbox");
+    }
+
+    #[test]
+    fn dropped_and_invalid_targets() {
+        let job = job(r#"
+  - job_name: node
+    relabel_configs:
+      - source_labels: [env]
+        regex: dev
+        action: drop
+      - source_labels: [host]
+        target_label: __address__
+"#);
+        assert!(
+            Target::new(&job, "127.0.0.1:9100", &labels(&[("env", "dev")]))
+                .unwrap()
+                .is_none()
+        );
+        // An empty replacement removes `__address__`.
+        assert!(matches!(
+            Target::new(&job, "127.0.0.1:9100", &Labels::new()),
+            Err(TargetError::MissingAddress)
+        ));
+        let target = Target::new(&job, "127.0.0.1:9100", &labels(&[("host", "node-1")]))
+            .unwrap()
+            .unwrap();
+        // The URL leaves out the default port, the instance keeps it.
+        assert_eq!(target.url, "http://node-1/metrics");
+        assert_eq!(target.labels["instance"], "node-1:80");
+        assert_eq!(target.labels["host"], "node-1");
+    }
+
+    #[test]
+    fn test_merge_target_labels() {
+        let target_labels = labels(&[("instance", "host:9100"), ("job", "node")]);
+
+        let mut exposed = labels(&[("cpu", "0")]);
+        merge_target_labels(&mut exposed, &target_labels, false);
+        assert_eq!(
+            exposed,
+            labels(&[("cpu", "0"), ("instance", "host:9100"), ("job", "node")])
+        );
+
+        let mut exposed = labels(&[
+            ("job", "exporter"),
+            ("exported_job", "older"),
+            ("instance", "host:9100"),
+        ]);
+        merge_target_labels(&mut exposed, &target_labels, false);
+        assert_eq!(
+            exposed,
+            labels(&[
+                ("exported_exported_job", "exporter"),
+                ("exported_job", "older"),
+                ("instance", "host:9100"),
+                ("job", "node"),
+            ])
+        );
+
+        let mut exposed = labels(&[("job", "exporter")]);
+        merge_target_labels(&mut exposed, &target_labels, true);
+        assert_eq!(
+            exposed,
+            labels(&[("instance", "host:9100"), ("job", "exporter")])
+        );
+    }
+
+    #[test]
+    fn test_with_default_port() {
+        assert_eq!(with_default_port("host", "http").unwrap(), "host:80");
+        assert_eq!(with_default_port("host", "https").unwrap(), "host:443");
+        assert_eq!(with_default_port("host:9100", "http").unwrap(), "host:9100");
+        assert_eq!(with_default_port("[::1]", "http").unwrap(), "[::1]:80");
+        assert_eq!(
+            with_default_port("[::1]:9100", "http").unwrap(),
+            "[::1]:9100"
+        );
+        assert!(with_default_port("host:port", "http").is_err());
+        assert!(with_default_port("host/path", "http").is_err());
+        assert!(with_default_port("host", "ftp").is_err());
+    }
+}
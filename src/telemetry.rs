@@ -0,0 +1,389 @@
+//! The agent's own metrics, served on `/metrics`.
+//!
+//! Nothing is recorded here: the scrape loops, channels and destination
+//! pipelines keep their own counters, and they are read into metric groups
+//! whenever the endpoint is scraped. The groups are formatted like any
+//! scrape, so the agent can scrape itself through its own pipeline.
+
+use crate::config::RemoteWriteConfig;
+use crate::metrics_agent::{MetricsAgent, MetricsMessage, PipelineStats};
+use crate::metrics_formatter::TextDocument;
+use crate::relabel::Labels;
+use crate::scrape_manager::{TargetStatus, TargetStatuses};
+use indexmap::IndexMap;
+use prometheus_parser::{GroupKey, GroupKind, MetricGroup, SimpleMetric, SummaryMetric};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// A destination's pipeline and the channel feeding it.
+struct Destination {
+    labels: Labels,
+    agent: Arc<MetricsAgent>,
+    tx: mpsc::WeakSender<Arc<MetricsMessage>>,
+}
+
+/// Shared handle to the agent's metrics. Destinations come and go as the
+/// config is reloaded.
+#[derive(Clone)]
+pub struct Telemetry {
+    targets: TargetStatuses,
+    scrape_tx: mpsc::WeakSender<MetricsMessage>,
+    destinations: Arc<Mutex<Vec<Destination>>>,
+}
+
+impl Telemetry {
+    /// Only weak handles to the channels are kept, so they still close
+    /// once their senders are gone.
+    pub fn new(targets: TargetStatuses, scrape_tx: &mpsc::Sender<MetricsMessage>) -> Self {
+        Telemetry {
+            targets,
+            scrape_tx: scrape_tx.downgrade(),
+            destinations: Arc::default(),
+        }
+    }
+
+    /// Report a destination's pipeline, under the labels of its current
+    /// config if it is reported already.
+    pub fn add_destination(
+        &self,
+        config: &RemoteWriteConfig,
+        agent: Arc<MetricsAgent>,
+        tx: &mpsc::Sender<Arc<MetricsMessage>>,
+    ) {
+        let mut labels = Labels::from([("url".to_string(), config.url.clone())]);
+        if let Some(name) = &config.name {
+            labels.insert("remote_name".to_string(), name.clone());
+        }
+        let destination = Destination {
+            labels,
+            agent,
+            tx: tx.downgrade(),
+        };
+        let mut destinations = self.destinations.lock().unwrap();
+        match destinations
+            .iter_mut()
+            .find(|reported| Arc::ptr_eq(&reported.agent, &destination.agent))
+        {
+            Some(reported) => *reported = destination,
+            None => destinations.push(destination),
+        }
+    }
+
+    pub fn remove_destination(&self, agent: &Arc<MetricsAgent>) {
+        self.destinations
+            .lock()
+            .unwrap()
+            .retain(|destination| !Arc::ptr_eq(&destination.agent, agent));
+    }
+
+    /// The pipelines of the destinations being reported.
+    pub fn agents(&self) -> Vec<Arc<MetricsAgent>> {
+        self.destinations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|destination| Arc::clone(&destination.agent))
+            .collect()
+    }
+
+    /// The current value of every metric.
+    pub fn groups(&self) -> Vec<MetricGroup> {
+        let targets = self
+            .targets
+            .snapshot()
+            .into_iter()
+            .map(|((url, labels), status)| {
+                let job = labels.get("job").cloned().unwrap_or_default();
+                let labels =
+                    Labels::from([("scrape_job".to_string(), job), ("target".to_string(), url)]);
+                (labels, status)
+            })
+            .collect::<Vec<_>>();
+        let per_target = |value: fn(&TargetStatus) -> u64| {
+            targets
+                .iter()
+                .map(move |(labels, status)| (labels.clone(), value(status) as f64))
+        };
+        let mut groups = vec![
+            counter(
+                "agent_scrapes_total",
+                "Scrapes attempted, per target.",
+                per_target(|status| status.attempts),
+            ),
+            counter(
+                "agent_scrape_failures_total",
+                "Scrapes that failed, per target.",
+                per_target(|status| status.failures),
+            ),
+            summary(
+                "agent_scrape_duration_seconds",
+                "Time spent scraping, per target.",
+                targets.iter().map(|(labels, status)| {
+                    let metric = SummaryMetric {
+                        quantiles: Vec::new(),
+                        sum: status.total_duration.as_secs_f64(),
+                        count: status.attempts,
+                    };
+                    (labels.clone(), metric)
+                }),
+            ),
+        ];
+
+        let mut channels = Vec::new();
+        if let Some(tx) = self.scrape_tx.upgrade() {
+            let labels = Labels::from([("channel".to_string(), "scrape".to_string())]);
+            channels.push((labels, tx.max_capacity(), tx.capacity()));
+        }
+        let reported = self.destinations.lock().unwrap();
+        for destination in reported.iter() {
+            if let Some(tx) = destination.tx.upgrade() {
+                let mut labels = destination.labels.clone();
+                labels.insert("channel".to_string(), "format".to_string());
+                channels.push((labels, tx.max_capacity(), tx.capacity()));
+            }
+        }
+        groups.extend([
+            gauge(
+                "agent_channel_depth",
+                "Messages waiting in a channel between pipeline stages.",
+                channels
+                    .iter()
+                    .map(|(labels, max, free)| (labels.clone(), (max - free) as f64)),
+            ),
+            gauge(
+                "agent_channel_capacity",
+                "Messages a channel between pipeline stages holds at most.",
+                channels
+                    .iter()
+                    .map(|(labels, max, _)| (labels.clone(), *max as f64)),
+            ),
+        ]);
+
+        let destinations = reported
+            .iter()
+            .map(|destination| (destination.labels.clone(), destination.agent.stats()))
+            .collect::<Vec<_>>();
+        drop(reported);
+        let per_destination = |value: fn(&PipelineStats) -> u64| {
+            destinations
+                .iter()
+                .map(move |(labels, stats)| (labels.clone(), value(stats) as f64))
+        };
+        let per_code = |counts: fn(&PipelineStats) -> &BTreeMap<String, u64>| {
+            destinations.iter().flat_map(move |(labels, stats)| {
+                counts(stats).iter().map(|(code, count)| {
+                    let mut labels = labels.clone();
+                    labels.insert("code".to_string(), code.clone());
+                    (labels, *count as f64)
+                })
+            })
+        };
+        groups.extend([
+            counter(
+                "agent_formatted_bytes_total",
+                "Bytes formatted and queued for a destination.",
+                per_destination(|stats| stats.formatted_bytes),
+            ),
+            counter(
+                "agent_formatted_samples_total",
+                "Samples formatted and queued for a destination.",
+                per_destination(|stats| stats.formatted_samples),
+            ),
+            counter(
+                "agent_remote_write_requests_total",
+                "Requests sent to a destination, by status code, or `error` without a response.",
+                per_code(|stats| &stats.writes.requests),
+            ),
+            counter(
+                "agent_remote_write_retries_total",
+                "Requests to a destination that were retried.",
+                per_destination(|stats| stats.writes.retries),
+            ),
+            counter(
+                "agent_remote_write_failures_total",
+                "Batches given up on, by the status code of their last attempt.",
+                per_code(|stats| &stats.writes.failures),
+            ),
+            gauge(
+                "agent_queue_bytes",
+                "Size of the batches queued on disk and not delivered yet.",
+                per_destination(|stats| stats.queue_bytes),
+            ),
+            counter(
+                "agent_dropped_samples_total",
+                "Samples dropped, because the destination rejected them or the queue was full.",
+                destinations.iter().flat_map(|(labels, stats)| {
+                    [
+                        ("rejected", stats.rejected_samples),
+                        ("queue_full", stats.overflowed_samples),
+                    ]
+                    .map(|(reason, samples)| {
+                        let mut labels = labels.clone();
+                        labels.insert("reason".to_string(), reason.to_string());
+                        (labels, samples as f64)
+                    })
+                }),
+            ),
+        ]);
+        groups
+    }
+
+    /// The metrics in the text exposition format. They are current values,
+    /// so they carry no timestamp: the scrape reading them stamps them.
+    pub fn render(&self) -> String {
+        let mut document = TextDocument::default();
+        document.push_groups(&self.groups(), None);
+        document.finish()
+    }
+}
+
+fn group_key(labels: Labels) -> GroupKey {
+    GroupKey {
+        timestamp: None,
+        labels,
+    }
+}
+
+fn simple_metrics(
+    samples: impl IntoIterator<Item = (Labels, f64)>,
+) -> IndexMap<GroupKey, SimpleMetric> {
+    samples
+        .into_iter()
+        .map(|(labels, value)| (group_key(labels), SimpleMetric { value }))
+        .collect()
+}
+
+fn group(name: &str, help: &str, metrics: GroupKind) -> MetricGroup {
+    MetricGroup {
+        name: name.to_string(),
+        metrics,
+        help: Some(help.to_string()),
+        unit: None,
+    }
+}
+
+fn counter(
+    name: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (Labels, f64)>,
+) -> MetricGroup {
+    group(name, help, GroupKind::Counter(simple_metrics(samples)))
+}
+
+fn gauge(name: &str, help: &str, samples: impl IntoIterator<Item = (Labels, f64)>) -> MetricGroup {
+    group(name, help, GroupKind::Gauge(simple_metrics(samples)))
+}
+
+fn summary(
+    name: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (Labels, SummaryMetric)>,
+) -> MetricGroup {
+    let metrics = samples
+        .into_iter()
+        .map(|(labels, metric)| (group_key(labels), metric))
+        .collect();
+    group(name, help, GroupKind::Summary(metrics))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Protocol, QueueConfig};
+    use crate::disk_queue::DiskQueue;
+    use crate::metrics_agent::{Encoder, FlushLimits};
+    use crate::remote_write::{RemoteWriter, RetryPolicy};
+    use prometheus_parser::parse_text;
+    use reqwest::Client;
+    use std::time::SystemTime;
+
+    fn destination(dir: &std::path::Path) -> (RemoteWriteConfig, Arc<MetricsAgent>) {
+        let config = RemoteWriteConfig {
+            url: "http://127.0.0.1:8428/write".to_string(),
+            name: Some("vm".to_string()),
+            protocol: Protocol::Text,
+            queue_config: QueueConfig::default(),
+        };
+        let writer = RemoteWriter::new(
+            config.url.clone(),
+            Client::new(),
+            config.protocol,
+            RetryPolicy::from(&config.queue_config),
+        );
+        let agent = MetricsAgent::new(
+            writer,
+            Encoder::new(config.protocol),
+            FlushLimits::from(&config.queue_config),
+            DiskQueue::open(dir, 1 << 20).unwrap(),
+        );
+        (config, Arc::new(agent))
+    }
+
+    /// Sample lines of the rendered metrics.
+    fn sample_lines(text: &str) -> Vec<String> {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_channels_and_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, agent) = destination(dir.path());
+        let (scrape_tx, _scrape_rx) = mpsc::channel(4);
+        let (format_tx, _format_rx) = mpsc::channel(8);
+        let message = MetricsMessage {
+            target_url: String::new(),
+            metrics: Vec::new(),
+            stale: Vec::new(),
+            scraped_at: SystemTime::now(),
+        };
+        format_tx.send(Arc::new(message)).await.unwrap();
+
+        let telemetry = Telemetry::new(TargetStatuses::default(), &scrape_tx);
+        telemetry.add_destination(&config, Arc::clone(&agent), &format_tx);
+        let text = telemetry.render();
+        parse_text(&text).unwrap();
+        let samples = sample_lines(&text);
+        let destination = r#"remote_name="vm",url="http://127.0.0.1:8428/write""#;
+        for expected in [
+            r#"agent_channel_depth{channel="scrape"} 0"#.to_string(),
+            r#"agent_channel_capacity{channel="scrape"} 4"#.to_string(),
+            format!(r#"agent_channel_depth{{channel="format",{destination}}} 1"#),
+            format!(r#"agent_channel_capacity{{channel="format",{destination}}} 8"#),
+            format!(r#"agent_formatted_samples_total{{{destination}}} 0"#),
+            format!(r#"agent_queue_bytes{{{destination}}} 0"#),
+            format!(r#"agent_dropped_samples_total{{reason="queue_full",{destination}}} 0"#),
+        ] {
+            assert!(samples.contains(&expected), "missing {expected} in\n{text}");
+        }
+
+        // Closed channels aren't reported.
+        drop(scrape_tx);
+        let samples = sample_lines(&telemetry.render());
+        assert!(
+            !samples
+                .iter()
+                .any(|line| line.contains(r#"channel="scrape""#))
+        );
+
+        // Reloaded destinations are reported under their new labels.
+        let moved = RemoteWriteConfig {
+            url: "http://127.0.0.1:8429/write".to_string(),
+            ..config
+        };
+        telemetry.add_destination(&moved, Arc::clone(&agent), &format_tx);
+        let samples = sample_lines(&telemetry.render());
+        assert!(
+            samples.contains(
+                &r#"agent_queue_bytes{remote_name="vm",url="http://127.0.0.1:8429/write"} 0"#
+                    .to_string()
+            )
+        );
+        assert_eq!(telemetry.agents().len(), 1);
+        telemetry.remove_destination(&agent);
+        assert!(telemetry.agents().is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+//! The agent's HTTP server: its own metrics, health and readiness probes,
+//! config reloads, and a Prometheus-compatible `/api/v1/targets`.
+
+use crate::relabel::Labels;
+use crate::scrape_manager::{TargetStatus, TargetStatuses};
+use crate::telemetry::Telemetry;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// `lastScrape` of targets not scraped yet: Go's zero time, as Prometheus
+/// reports it.
+const NEVER: &str = "0001-01-01T00:00:00Z";
+
+/// Asks the agent to reload its config, and gets back the outcome.
+pub type ReloadRequest = oneshot::Sender<anyhow::Result<()>>;
+
+/// What the handlers answer from.
+pub struct WebState {
+    pub telemetry: Telemetry,
+    pub targets: TargetStatuses,
+    pub reload: mpsc::Sender<ReloadRequest>,
+}
+
+/// Serve requests until the process exits.
+pub async fn serve(listener: TcpListener, state: Arc<WebState>) -> io::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/-/healthy", get(healthy))
+        .route("/-/ready", get(ready))
+        .route("/-/reload", post(reload))
+        .route("/api/v1/targets", get(targets))
+        .with_state(state);
+    axum::serve(listener, app).await
+}
+
+async fn metrics(State(state): State<Arc<WebState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, TEXT_CONTENT_TYPE)],
+        state.telemetry.render(),
+    )
+}
+
+async fn healthy() -> &'static str {
+    "Agent is Healthy.\n"
+}
+
+/// Ready once every destination has accepted a write. The server is only
+/// started after the config is loaded.
+async fn ready(State(state): State<Arc<WebState>>) -> (StatusCode, &'static str) {
+    if state
+        .telemetry
+        .agents()
+        .iter()
+        .all(|agent| agent.has_delivered())
+    {
+        (StatusCode::OK, "Agent is Ready.\n")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Agent is not ready: waiting for a first write to every destination.\n",
+        )
+    }
+}
+
+/// Reload the config as on SIGHUP, answering once it is applied. An invalid
+/// config is reported and leaves the running one in place.
+async fn reload(State(state): State<Arc<WebState>>) -> (StatusCode, String) {
+    let shutting_down = || {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Agent is shutting down.\n".to_string(),
+        )
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state.reload.send(reply_tx).await.is_err() {
+        return shutting_down();
+    }
+    match reply_rx.await {
+        Ok(Ok(())) => (StatusCode::OK, String::new()),
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to reload config: {err:#}\n"),
+        ),
+        Err(_) => shutting_down(),
+    }
+}
+
+#[derive(Serialize)]
+struct ApiResponse<T> {
+    status: &'static str,
+    data: T,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TargetsData {
+    active_targets: Vec<ActiveTarget>,
+    /// Targets dropped by relabeling aren't kept, so this is always empty.
+    dropped_targets: Vec<()>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ActiveTarget {
+    discovered_labels: Labels,
+    labels: Labels,
+    scrape_pool: String,
+    scrape_url: String,
+    global_url: String,
+    last_error: String,
+    last_scrape: String,
+    last_scrape_duration: f64,
+    health: &'static str,
+    scrape_interval: String,
+    scrape_timeout: String,
+}
+
+impl ActiveTarget {
+    fn new(url: String, labels: Labels, status: TargetStatus) -> Self {
+        let last_scrape = match status.last_scrape {
+            Some(at) => humantime::format_rfc3339_nanos(at).to_string(),
+            None => NEVER.to_string(),
+        };
+        ActiveTarget {
+            health: status.health(),
+            discovered_labels: status.discovered_labels,
+            labels,
+            scrape_pool: status.job_name,
+            global_url: url.clone(),
+            scrape_url: url,
+            last_error: status.last_error.unwrap_or_default(),
+            last_scrape,
+            last_scrape_duration: status.last_duration.as_secs_f64(),
+            scrape_interval: humantime::format_duration(status.interval).to_string(),
+            scrape_timeout: humantime::format_duration(status.timeout).to_string(),
+        }
+    }
+}
+
+async fn targets(State(state): State<Arc<WebState>>) -> Json<ApiResponse<TargetsData>> {
+    let active_targets = state
+        .targets
+        .snapshot()
+        .into_iter()
+        .map(|((url, labels), status)| ActiveTarget::new(url, labels, status))
+        .collect();
+    Json(ApiResponse {
+        status: "success",
+        data: TargetsData {
+            active_targets,
+            dropped_targets: Vec::new(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Protocol, QueueConfig, RemoteWriteConfig};
+    use crate::disk_queue::DiskQueue;
+    use crate::metrics_agent::{Encoder, FlushLimits, MetricsAgent, MetricsMessage};
+    use crate::remote_write::{RemoteWriter, RetryPolicy};
+    use crate::scrape_health::ScrapeHealth;
+    use crate::scrape_manager::ScrapeManager;
+    use crate::scraper::TargetScraper;
+    use reqwest::Client;
+    use serde_json::{Value, json};
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+    use tokio::sync::mpsc;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Serve on a free port, without a config to reload unless `reload`
+    /// is taken care of.
+    async fn start(
+        targets: TargetStatuses,
+        reload: mpsc::Sender<ReloadRequest>,
+    ) -> (SocketAddr, Telemetry) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (scrape_tx, _) = mpsc::channel(1);
+        let telemetry = Telemetry::new(targets.clone(), &scrape_tx);
+        let state = WebState {
+            telemetry: telemetry.clone(),
+            targets,
+            reload,
+        };
+        tokio::spawn(serve(listener, Arc::new(state)));
+        (address, telemetry)
+    }
+
+    fn no_reload() -> mpsc::Sender<ReloadRequest> {
+        mpsc::channel(1).0
+    }
+
+    async fn status(address: SocketAddr, path: &str) -> StatusCode {
+        reqwest::get(format!("http://{address}{path}"))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let (address, _) = start(TargetStatuses::default(), no_reload()).await;
+
+        let response = reqwest::get(format!("http://{address}/metrics"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.headers()[header::CONTENT_TYPE], TEXT_CONTENT_TYPE);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("# TYPE agent_scrapes_total counter\n"));
+    }
+
+    #[tokio::test]
+    async fn ready_after_the_first_write() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let retry = RetryPolicy {
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_retries: Some(0),
+        };
+        let limits = FlushLimits {
+            max_age: Duration::from_secs(3600),
+            max_bytes: usize::MAX,
+            max_samples: usize::MAX,
+        };
+        let agent = Arc::new(MetricsAgent::new(
+            RemoteWriter::new(server.uri(), Client::new(), Protocol::Text, retry),
+            Encoder::new(Protocol::Text),
+            limits,
+            DiskQueue::open(dir.path(), 1 << 20).unwrap(),
+        ));
+        let (address, telemetry) = start(TargetStatuses::default(), no_reload()).await;
+        let config = RemoteWriteConfig {
+            url: server.uri(),
+            name: None,
+            protocol: Protocol::Text,
+            queue_config: QueueConfig::default(),
+        };
+        let (tx, rx) = mpsc::channel(1);
+        telemetry.add_destination(&config, Arc::clone(&agent), &tx);
+
+        assert_eq!(status(address, "/-/healthy").await, StatusCode::OK);
+        assert_eq!(
+            status(address, "/-/ready").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let message = MetricsMessage {
+            target_url: String::new(),
+            metrics: ScrapeHealth::default().groups(&Labels::new()),
+            stale: Vec::new(),
+            scraped_at: SystemTime::now(),
+        };
+        tx.send(Arc::new(message)).await.unwrap();
+        drop(tx);
+        agent.format(rx).await.unwrap();
+        agent.write().await.unwrap();
+        assert_eq!(status(address, "/-/ready").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn lists_targets_like_prometheus() {
+        let target = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a 1\n"))
+            .mount(&target)
+            .await;
+        let url = format!("{}/metrics", target.uri());
+        let address_label =
+            Labels::from([("__address__".to_string(), target.address().to_string())]);
+        let scraper = TargetScraper {
+            job_name: "node".to_string(),
+            url: url.clone(),
+            labels: Labels::from([("job".to_string(), "node".to_string())]),
+            discovered_labels: address_label.clone(),
+            client: Client::new(),
+            timeout: Duration::from_secs(1),
+            max_retries: 0,
+            honor_labels: false,
+            honor_timestamps: true,
+            metric_relabel_configs: Vec::new(),
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut manager = ScrapeManager::new(tx);
+        manager.sync(vec![(scraper, Duration::from_secs(15))]);
+        rx.recv().await.unwrap();
+        let (address, _) = start(manager.statuses(), no_reload()).await;
+
+        let body: Value = reqwest::get(format!("http://{address}/api/v1/targets"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["data"]["droppedTargets"], json!([]));
+        let active = &body["data"]["activeTargets"][0];
+        assert_eq!(active["discoveredLabels"], json!(address_label));
+        assert_eq!(active["labels"], json!({"job": "node"}));
+        assert_eq!(active["scrapePool"], "node");
+        assert_eq!(active["scrapeUrl"], url);
+        assert_eq!(active["health"], "up");
+        assert_eq!(active["lastError"], "");
+        assert_eq!(active["scrapeInterval"], "15s");
+        assert_eq!(active["scrapeTimeout"], "1s");
+        assert!(active["lastScrape"].as_str().unwrap().ends_with('Z'));
+        assert!(active["lastScrapeDuration"].as_f64().unwrap() > 0.0);
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reload_reports_the_outcome() {
+        let (reload_tx, mut reload_rx) = mpsc::channel::<ReloadRequest>(1);
+        tokio::spawn(async move {
+            let mut valid = true;
+            while let Some(reply) = reload_rx.recv().await {
+                let result = if valid {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("remote_write[0].url: must not be empty"))
+                };
+                reply.send(result).unwrap();
+                valid = false;
+            }
+        });
+        let (address, _) = start(TargetStatuses::default(), reload_tx).await;
+        let client = Client::new();
+        let reload = || client.post(format!("http://{address}/-/reload")).send();
+
+        assert_eq!(reload().await.unwrap().status(), StatusCode::OK);
+        let response = reload().await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.text().await.unwrap(),
+            "failed to reload config: remote_write[0].url: must not be empty\n"
+        );
+
+        let (address, _) = start(TargetStatuses::default(), no_reload()).await;
+        let response = client
+            .post(format!("http://{address}/-/reload"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}